@@ -0,0 +1,75 @@
+use aws_lc_rs::signature::KeyPair;
+use clap::Parser;
+use hoodini_core::authority::AuthorityCertificate;
+use hoodini_core::keys;
+use hoodini_core::types::{KeyId, ServiceName};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///Issues an authority certificate, letting an intermediate (build machine) key sign certificates
+///on behalf of the offline root key.
+fn main() -> io::Result<()> {
+    let args = CliArgs::parse();
+    let root_key = keys::load_signing_key(&args.root_key_path)
+        .expect("Couldn't load root signing key");
+    let root_id = args
+        .root_key_id
+        .map(KeyId)
+        .unwrap_or_else(|| KeyId::from_public_key(root_key.public_key().as_ref()));
+    let public_key = keys::load_public_key_material(&args.authority_key_path)
+        .expect("Couldn't load authority public key");
+    let key_id = args
+        .key_id
+        .map(KeyId)
+        .unwrap_or_else(|| KeyId::from_public_key(&public_key));
+
+    let not_before = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before UNIX epoch")
+        .as_secs();
+    let allowed_services = if args.services.is_empty() {
+        None
+    } else {
+        Some(args.services.into_iter().map(ServiceName).collect())
+    };
+    let certificate = AuthorityCertificate::new(
+        key_id,
+        &public_key,
+        root_id,
+        not_before,
+        not_before + args.validity_days * 24 * 60 * 60,
+        allowed_services,
+        &root_key,
+    );
+    println!(
+        "Issued authority {} (signed by {})",
+        certificate.key_id, certificate.issuer
+    );
+    let file = File::create(&args.output)?;
+    serde_json::to_writer_pretty(file, &certificate)?;
+    Ok(())
+}
+
+#[derive(clap::Parser)]
+pub struct CliArgs {
+    #[arg(short='k', long="root_key_path")]
+    root_key_path: PathBuf,
+    //Defaults to an identifier derived from the root public key
+    #[arg(short='r', long="root_key_id")]
+    root_key_id: Option<String>,
+    //Public key of the intermediate authority
+    #[arg(short='p', long="authority_key_path")]
+    authority_key_path: PathBuf,
+    //Defaults to an identifier derived from the authority public key
+    #[arg(short='i', long="key_id")]
+    key_id: Option<String>,
+    //Services the authority may certify. Every service when omitted
+    #[arg(short='s', long="service")]
+    services: Vec<String>,
+    #[arg(short='d', long="validity_days", default_value_t = 90)]
+    validity_days: u64,
+    #[arg(short='o', long="output")]
+    output: PathBuf,
+}
@@ -0,0 +1,116 @@
+use clap::Parser;
+use hoodini_core::canonical::SigningPayload;
+use hoodini_core::keys;
+use hoodini_core::transparency::{verify_consistency, SignedTreeHead, TransparencyLog};
+use hoodini_core::types::KeyId;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+///Checks that a transparency log was only ever appended to: every recorded tree head must match
+///the log entries, and be consistent with the latest one. Tree heads saved by auditors can be
+///passed to check that the log still extends them.
+fn main() -> io::Result<()> {
+    let args = CliArgs::parse();
+    let log = TransparencyLog::open(&args.log_dir)?;
+    let mut log_keys = HashMap::new();
+    for path in args.log_keys.iter() {
+        let key_material =
+            keys::load_public_key_material(path).expect("Couldn't load log public key");
+        log_keys.insert(KeyId::from_public_key(&key_material), key_material);
+    }
+
+    if args.list {
+        for (index, entry) in log.entries().iter().enumerate() {
+            let services: Vec<&str> = entry
+                .hosted_services()
+                .iter()
+                .map(|service| service.0.as_str())
+                .collect();
+            println!(
+                "{}\t{}\tservices {}\tbinary {}\tpolicy {}",
+                index,
+                entry.binary_name(),
+                services.join(","),
+                entry.binary_hash.0,
+                entry.policy_hash.0
+            );
+        }
+    }
+
+    let mut tree_heads = log.tree_heads().to_vec();
+    for path in args.tree_heads.iter() {
+        let tree_head: SignedTreeHead = serde_json::from_reader(File::open(path)?)?;
+        tree_heads.push(tree_head);
+    }
+    let latest = log.tree().size();
+    let latest_root = log.tree().root(latest).expect("Log size is within the tree");
+
+    let mut failures = 0;
+    for tree_head in tree_heads.iter() {
+        if let Err(reason) = check_tree_head(&log, tree_head, &log_keys, latest, &latest_root) {
+            println!("Tree head of size {}: {}", tree_head.tree_size, reason);
+            failures += 1;
+        }
+    }
+    println!(
+        "Checked {} tree heads against {} entries (root {})",
+        tree_heads.len(),
+        latest,
+        hex::encode(latest_root)
+    );
+    if failures > 0 {
+        println!("{} tree heads failed, the log was rewritten or tampered with", failures);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn check_tree_head(
+    log: &TransparencyLog,
+    tree_head: &SignedTreeHead,
+    log_keys: &HashMap<KeyId, Vec<u8>>,
+    latest: u64,
+    latest_root: &[u8; 32],
+) -> Result<(), String> {
+    if !log_keys.is_empty() {
+        let key = log_keys
+            .get(&tree_head.log_id)
+            .ok_or_else(|| format!("signed by unknown log key {}", tree_head.log_id))?;
+        let key = aws_lc_rs::signature::UnparsedPublicKey::new(&aws_lc_rs::signature::ED25519, key);
+        let signature = hex::decode(&tree_head.signature.0).map_err(|_| "malformed signature")?;
+        key.verify(&tree_head.signing_payload(), &signature)
+            .map_err(|_| "invalid signature")?;
+    }
+    let root = tree_head.root().ok_or("malformed root hash")?;
+    if tree_head.tree_size > latest {
+        return Err(format!("larger than the log ({} entries)", latest));
+    }
+    if log.tree().root(tree_head.tree_size) != Some(root) {
+        return Err("root does not match the log entries".to_string());
+    }
+    let proof = log
+        .tree()
+        .consistency_proof(tree_head.tree_size, latest)
+        .ok_or("no consistency proof")?;
+    if !verify_consistency(tree_head.tree_size, latest, &root, latest_root, &proof) {
+        return Err("inconsistent with the latest tree head".to_string());
+    }
+    Ok(())
+}
+
+#[derive(clap::Parser)]
+pub struct CliArgs {
+    #[arg(short='l', long="log_dir")]
+    log_dir: PathBuf,
+    //Public keys tree heads must be signed with. Signatures are not checked when omitted
+    #[arg(short='v', long="log_key")]
+    log_keys: Vec<PathBuf>,
+    //Tree heads saved outside of the log, e.g. by auditors
+    #[arg(short='t', long="tree_head")]
+    tree_heads: Vec<PathBuf>,
+    //Print every certified binary/policy pair
+    #[arg(long="list")]
+    list: bool,
+}
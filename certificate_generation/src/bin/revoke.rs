@@ -0,0 +1,74 @@
+use aws_lc_rs::signature::KeyPair;
+use clap::Parser;
+use hoodini_core::keys;
+use hoodini_core::revocation::{RevocationList, RevokedEntry};
+use hoodini_core::types::{BinHash, CertificateId, KeyId, TahiniCertificate};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///Builds a signed revocation list, loaded by the sidecar and clients next to certificates.
+///The signing key must be a root key: lists signed by intermediate keys are refused.
+fn main() -> io::Result<()> {
+    let args = CliArgs::parse();
+    let skey = keys::load_signing_key(&args.signing_key_path)
+        .expect("Couldn't load revocation list signing key");
+    let issuer = args
+        .key_id
+        .map(KeyId)
+        .unwrap_or_else(|| KeyId::from_public_key(skey.public_key().as_ref()));
+
+    let mut entries = Vec::new();
+    //Keep the entries of the list we are extending, dropping its signature
+    if args.extend {
+        let file = File::open(&args.output)?;
+        let previous: RevocationList = serde_json::from_reader(file)?;
+        entries.extend(previous.entries);
+    }
+    entries.extend(args.binary_hashes.into_iter().map(|hash| RevokedEntry::BinaryHash(BinHash(hash))));
+    entries.extend(args.policy_hashes.into_iter().map(|hash| RevokedEntry::PolicyHash(hash.into())));
+    entries.extend(args.certificate_ids.into_iter().map(|id| RevokedEntry::CertificateId(CertificateId(id))));
+    for certificate_path in args.certificates {
+        let file = File::open(&certificate_path)?;
+        let certificate: TahiniCertificate = serde_json::from_reader(file)?;
+        let id = certificate.id().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        println!("Revoking certificate {} ({:?})", id, certificate_path);
+        entries.push(RevokedEntry::CertificateId(id));
+    }
+    let mut seen = HashSet::new();
+    entries.retain(|entry| seen.insert(entry.clone()));
+
+    let issued_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before UNIX epoch")
+        .as_secs();
+    let list = RevocationList::new(issuer, issued_at, entries, &skey);
+    let file = File::create(&args.output)?;
+    serde_json::to_writer_pretty(file, &list)?;
+    Ok(())
+}
+
+#[derive(clap::Parser)]
+pub struct CliArgs {
+    #[arg(short='k', long="signing_key_path")]
+    signing_key_path: PathBuf,
+    //Defaults to an identifier derived from the public key
+    #[arg(short='i', long="key_id")]
+    key_id: Option<String>,
+    #[arg(short='o', long="output")]
+    output: PathBuf,
+    //Keep the entries already present in the output list
+    #[arg(short='e', long="extend")]
+    extend: bool,
+    #[arg(long="binary_hash")]
+    binary_hashes: Vec<String>,
+    #[arg(long="policy_hash")]
+    policy_hashes: Vec<String>,
+    #[arg(long="certificate_id")]
+    certificate_ids: Vec<String>,
+    //Certificate files whose id should be revoked
+    #[arg(short='c', long="certificate")]
+    certificates: Vec<PathBuf>,
+}
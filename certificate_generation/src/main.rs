@@ -1,5 +1,10 @@
 use clap::Parser;
-use serde::{Deserialize, Serialize};
+use aws_lc_rs::signature::KeyPair;
+use hoodini_core::cose;
+use hoodini_core::manifest::PolicyManifest;
+use hoodini_core::transparency::TransparencyLog;
+use hoodini_core::types::{BinHash, KeyId, PolicyHash, ServiceName};
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs;
@@ -11,26 +16,6 @@ use std::path::{Path, PathBuf};
 
 mod manifest_generation;
 
-#[derive(Deserialize, Serialize)]
-pub struct TahiniCertificate {
-    service_name: String,
-    policy_hash: PolicyHash,
-    binary_hash: BinHash,
-    signature: Signature,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[allow(unused)]
-pub struct BinHash(String);
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[allow(unused)]
-pub struct Signature(String);
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[allow(unused)]
-pub struct PolicyHash(String);
-
 fn retrieve_hash_from_file(policy_filename: &Path) -> io::Result<String> {
     let file = File::options().read(true).open(policy_filename)?;
     let first_line = std::io::BufReader::new(file).lines().next();
@@ -46,6 +31,46 @@ fn retrieve_hash_from_file(policy_filename: &Path) -> io::Result<String> {
     }
 }
 
+//The policy file holds the aggregate hash on its first line, then the per-policy manifest as JSON.
+//Files written before the manifest was dumped only hold the aggregate hash
+fn retrieve_manifest_from_file(policy_filename: &Path) -> io::Result<Option<PolicyManifest>> {
+    let contents = fs::read_to_string(policy_filename)?;
+    let manifest = contents.split_once('\n').map(|(_, json)| json.trim()).unwrap_or("");
+    if manifest.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(manifest).map(Some).map_err(io::Error::from)
+}
+
+#[derive(Deserialize)]
+struct ProjectMetadata {
+    binaries: HashMap<String, BinaryMetadata>,
+}
+
+#[derive(Deserialize)]
+struct BinaryMetadata {
+    //tarpc services the binary hosts. Only the binary's own name when omitted
+    #[serde(default)]
+    services: Vec<String>,
+}
+
+//Services hosted by each binary, as listed in the project metadata
+fn retrieve_services(metadata_path: &Path) -> io::Result<HashMap<String, Vec<ServiceName>>> {
+    if !metadata_path.exists() {
+        return Ok(HashMap::new());
+    }
+    let contents = fs::read_to_string(metadata_path)?;
+    let metadata: ProjectMetadata = toml::from_str(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(metadata
+        .binaries
+        .into_iter()
+        .map(|(bin_name, binary)| {
+            (bin_name, binary.services.into_iter().map(ServiceName).collect())
+        })
+        .collect())
+}
+
 fn match_policies_to_bin(
     policy_dir: &Path,
     target_binaries: Vec<PathBuf>,
@@ -159,6 +184,12 @@ fn main() -> io::Result<()> {
     let skey = manifest_generation::get_signing_key(
         &key_path
     );
+    let issuance = manifest_generation::issuance_info(
+        &skey,
+        args.key_id,
+        args.validity_days,
+        &project_root,
+    );
 
     let binaries = find_binaries_in_target(&target_dir)?;
 
@@ -169,20 +200,71 @@ fn main() -> io::Result<()> {
     //
     let merged = merge_maps(pols, bin_hashes);
 
-    let certificates: HashMap<_, _> = merged
-        .into_iter()
-        .map(|(bin_name, data)| {
-            (
-                bin_name.clone(),
-                manifest_generation::gen_certificate(bin_name, data, &skey),
-            )
-        })
-        .collect();
+    let metadata_path = args
+        .metadata_path
+        .unwrap_or_else(|| project_root.join("project_metadata.toml"));
+    let mut services = retrieve_services(&metadata_path)?;
+
+    let mut certificates = HashMap::new();
+    for (bin_name, data) in merged.into_iter() {
+        let manifest = retrieve_manifest_from_file(
+            &policy_dir.join(format!("{}_policy_hashes.json", bin_name)),
+        )?;
+        if manifest.is_none() {
+            println!("No policy manifest for {}", bin_name);
+        }
+        let hosted = match services.remove(&bin_name) {
+            Some(hosted) if !hosted.is_empty() => hosted,
+            _ => {
+                println!("No services listed for {}, certifying it as its only service", bin_name);
+                vec![ServiceName(bin_name.clone())]
+            }
+        };
+        let certificate = manifest_generation::gen_certificate(
+            bin_name.clone(),
+            hosted,
+            data,
+            manifest,
+            &issuance,
+            &skey,
+        );
+        certificates.insert(bin_name, certificate);
+    }
+
+    //Append every certificate to the transparency log, and attach its inclusion proof
+    let log_dir = args
+        .log_dir
+        .unwrap_or_else(|| project_root.join("transparency_log"));
+    let log_key = match &args.log_key_path {
+        Some(path) => manifest_generation::get_signing_key(path),
+        None => manifest_generation::get_signing_key(&key_path),
+    };
+    let mut log = TransparencyLog::open(&log_dir)?;
+    let mut indices = HashMap::new();
+    for (bin_name, certificate) in certificates.iter() {
+        indices.insert(bin_name.clone(), log.append(certificate)?);
+    }
+    let tree_head = log.sign_tree_head(
+        KeyId::from_public_key(log_key.public_key().as_ref()),
+        issuance.issued_at,
+        &log_key,
+    )?;
+    for (bin_name, certificate) in certificates.iter_mut() {
+        certificate.transparency = log.prove_inclusion(indices[bin_name], &tree_head);
+    }
+    println!(
+        "Transparency log {:?} now holds {} certificates",
+        log_dir, tree_head.tree_size
+    );
 
     fn format_filename(filename: &String) -> String {
         format!("{}_certificate.json", filename)
     }
 
+    fn format_cose_filename(filename: &String) -> String {
+        format!("{}_certificate.cose", filename)
+    }
+
     let certificates_dir = project_root.join("certificates");
     match create_dir(&certificates_dir) {
         Ok(_) => println!("Created certificates directory for the current project"),
@@ -193,6 +275,10 @@ fn main() -> io::Result<()> {
         let certif_file_path = certificates_dir.clone().join(format_filename(k));
         let file = File::create(certif_file_path)?;
         serde_json::to_writer_pretty(file, &v)?;
+        //Same certificate as a COSE_Sign1 envelope
+        let envelope = cose::sign_certificate(v, &skey)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        fs::write(certificates_dir.join(format_cose_filename(k)), envelope)?;
     }
 
     Ok(())
@@ -203,5 +289,19 @@ pub struct CliArgs {
     #[arg(short='p', long="project_folder")]
     project_folder: PathBuf,
     #[arg(short='k', long="signing_key_path")]
-    signing_key_path: PathBuf
+    signing_key_path: PathBuf,
+    //Defaults to an identifier derived from the public key
+    #[arg(short='i', long="key_id")]
+    key_id: Option<String>,
+    #[arg(short='d', long="validity_days", default_value_t = 365)]
+    validity_days: u64,
+    //Transparency log directory. Defaults to transparency_log/ in the project folder
+    #[arg(short='l', long="log_dir")]
+    log_dir: Option<PathBuf>,
+    //Key signing the log tree heads. Defaults to the certificate signing key
+    #[arg(long="log_key_path")]
+    log_key_path: Option<PathBuf>,
+    //Lists the services of each binary. Defaults to project_metadata.toml in the project folder
+    #[arg(short='m', long="project_metadata")]
+    metadata_path: Option<PathBuf>,
 }
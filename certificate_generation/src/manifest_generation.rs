@@ -1,23 +1,17 @@
-use crate::TahiniCertificate;
-use aws_lc_rs::signature::Ed25519KeyPair;
-use std::io;
+use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
+use hoodini_core::canonical::SigningPayload;
+use hoodini_core::keys;
+use hoodini_core::manifest::PolicyManifest;
+use hoodini_core::types::{
+    BinHash, CertificateVersion, IssuanceInfo, KeyId, PolicyHash, ServiceName, Signature,
+    TahiniCertificate, ToolchainInfo,
+};
 use std::path::Path;
-
-use super::{BinHash, PolicyHash};
-
-fn read_file(path: &std::path::Path) -> io::Result<Vec<u8>> {
-    use std::io::Read;
-
-    let mut file = std::fs::File::open(path)?;
-    let mut contents: Vec<u8> = Vec::new();
-    file.read_to_end(&mut contents)?;
-    Ok(contents)
-}
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_signing_key(path: &Path) -> Ed25519KeyPair {
-    let der_bytes = read_file(path).expect("Couldn't read private key certificate file");
-    let kpair = Ed25519KeyPair::from_pkcs8(&der_bytes);
-    kpair.expect("Couldn't parse certificate file ")
+    keys::load_signing_key(path).expect("Couldn't load certificate signing key")
 }
 
 // pub fn verify_pkey(path: &Path) -> UnparsedPublicKey<Vec<u8>> {
@@ -27,18 +21,65 @@ pub fn get_signing_key(path: &Path) -> Ed25519KeyPair {
 //     pkey
 // }
 
-pub fn gen_certificate(service_name: String, data: (PolicyHash, BinHash), key: &Ed25519KeyPair) -> TahiniCertificate {
-    let policy_u8 = hex::decode(&data.0.0).expect("policy hash is not hexadecimal");
-    let binary_u8 = hex::decode(&data.1.0).expect("policy hash is not hexadecimal");
-    let mut signing_data = policy_u8.clone();
-    signing_data.extend(binary_u8);
-    let sig = key.sign(signing_data.as_slice());
-    TahiniCertificate {
-        service_name,
+///Issuer metadata shared by every certificate of a run.
+pub fn issuance_info(
+    key: &Ed25519KeyPair,
+    key_id: Option<String>,
+    validity_days: u64,
+    project_root: &Path,
+) -> IssuanceInfo {
+    let issuer = key_id
+        .map(KeyId)
+        .unwrap_or_else(|| KeyId::from_public_key(key.public_key().as_ref()));
+    let issued_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before UNIX epoch")
+        .as_secs();
+    IssuanceInfo {
+        issuer,
+        issued_at,
+        expires_at: issued_at + validity_days * 24 * 60 * 60,
+        toolchain: ToolchainInfo {
+            generator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            rustc: rustc_version(project_root),
+        },
+    }
+}
+
+//Version of the compiler that the project toolchain resolves to
+fn rustc_version(project_root: &Path) -> String {
+    Command::new("rustc")
+        .arg("--version")
+        .current_dir(project_root)
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+///Certificates name the binary and list every service it hosts, so they are always v4.
+pub fn gen_certificate(
+    bin_name: String,
+    services: Vec<ServiceName>,
+    data: (PolicyHash, BinHash),
+    policy_manifest: Option<PolicyManifest>,
+    issuance: &IssuanceInfo,
+    key: &Ed25519KeyPair,
+) -> TahiniCertificate {
+    let mut certificate = TahiniCertificate {
+        version: CertificateVersion::V4,
+        service_name: ServiceName(bin_name),
         policy_hash: data.0,
         binary_hash: data.1,
-        signature: crate::Signature(hex::encode(sig.as_ref())),
-    }
+        issuance: Some(issuance.clone()),
+        policy_manifest,
+        services,
+        signature: Signature(String::new()),
+        transparency: None,
+    };
+    certificate.signature = key.sign(&certificate.signing_payload()).into();
+    certificate
 }
 
 // pub fn verify_certificate(path: &Path, pkey: &UnparsedPublicKey<Vec<u8>>) -> bool {
@@ -1,4 +1,8 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
@@ -14,18 +18,78 @@ struct CliArgs {
     key_signing_path: PathBuf,
     #[arg(short = 'C', long = "certif_config_path")]
     certif_config_path: PathBuf,
+    #[arg(short = 'v', long = "certificate_key_path")]
+    certificate_key_path: PathBuf,
+    #[arg(short = 'r', long = "revocation_list")]
+    revocation_lists: Vec<PathBuf>,
+    //Authority certificates of intermediate keys signing the certificates
+    #[arg(short = 'a', long = "authority")]
+    authorities: Vec<PathBuf>,
+    //Transparency log key. When given, certificates must carry an inclusion proof
+    #[arg(short = 'l', long = "log_key")]
+    log_key_path: Option<PathBuf>,
+    //Seconds between checks of the certificate files by the sidecar. No hot reload when omitted
+    #[arg(long = "reload_interval")]
+    reload_interval: Option<u64>,
+    //Seconds a session lives on its server before the client has to rekey
+    #[arg(long = "session_ttl")]
+    session_ttl: Option<u64>,
+    //Number of times a server may fetch the keys of a session
+    #[arg(long = "session_budget")]
+    session_budget: Option<u32>,
+    //File listing the clients the sidecar bans, one `binary:<name>` or `uid:<uid>` per line
+    #[arg(long = "banned_clients")]
+    banned_clients: Option<PathBuf>,
+    //Address the sidecar listens on, e.g. 0.0.0.0:4000. Loopback only when omitted
+    #[arg(long = "bind_address")]
+    bind_address: Option<String>,
+    //Only serve the Unix socket, whose access policy TCP clients would bypass
+    #[arg(long = "no_tcp", requires = "unix_socket")]
+    no_tcp: bool,
+    //PEM certificate chain and key the sidecar serves TLS with. Clients pin the certificate
+    #[arg(long = "tls_certificate", requires = "tls_key")]
+    tls_certificate: Option<PathBuf>,
+    #[arg(long = "tls_key", requires = "tls_certificate")]
+    tls_key: Option<PathBuf>,
+    //Serve plain TCP on a non-loopback bind_address, without TLS
+    #[arg(long = "insecure", conflicts_with = "tls_certificate")]
+    insecure: bool,
+    //Unix socket the sidecar also listens on, for clients on the same host
+    #[arg(long = "unix_socket")]
+    unix_socket: Option<PathBuf>,
+    //Users and groups allowed to attest over the Unix socket. Anyone able to open it when omitted
+    #[arg(long = "allowed_uid", requires = "unix_socket")]
+    allowed_uids: Vec<u32>,
+    #[arg(long = "allowed_gid", requires = "unix_socket")]
+    allowed_gids: Vec<u32>,
+    //File attestation requests are recorded in
+    #[arg(long = "audit_log")]
+    audit_log: Option<PathBuf>,
+    //Codecs clients may use for the attestation RPC. Both when omitted
+    #[arg(long = "codec", value_parser = ["cbor", "json"])]
+    codecs: Vec<String>,
 }
 
 #[derive(Deserialize)]
 struct MetadataConfig {
     binaries: Table,
-    service_mapping: Table,
 }
 
 #[derive(Serialize)]
 struct CertificateConfig {
     certificates: Table,
-    service_mapping: Table,
+    cose_certificates: Table,
+    keys: KeyConf,
+    authorities: Vec<String>,
+    revocation_lists: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transparency: Option<TransparencyConf>,
+}
+
+#[derive(Serialize)]
+struct TransparencyConf {
+    log_keys: String,
+    require_inclusion_proof: bool,
 }
 
 #[derive(Serialize)]
@@ -33,7 +97,41 @@ struct RuntimeConfig {
     binaries: Table,
     certificates_config: CertifConfForRuntime,
     signing_key: KeyConf,
-    service_mapping: Table,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reload_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_budget: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    banned_clients: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bind_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen_tcp: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsConf>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    insecure: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unix_socket: Option<UnixSocketConf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audit_log: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    codecs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UnixSocketConf {
+    path: String,
+    allowed_uids: Vec<u32>,
+    allowed_gids: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct TlsConf {
+    certificate: String,
+    key: String,
 }
 
 #[derive(Serialize)]
@@ -47,8 +145,16 @@ struct KeyConf {
 }
 
 impl MetadataConfig {
-    fn parse_to_cert_conf(&self, cert_path: &PathBuf) -> CertificateConfig {
+    fn parse_to_cert_conf(
+        &self,
+        cert_path: &Path,
+        key_path: &Path,
+        authorities: &[PathBuf],
+        revocation_lists: &[PathBuf],
+        log_key_path: Option<&Path>,
+    ) -> CertificateConfig {
         let mut table = Table::new();
+        let mut cose_table = Table::new();
         for keys in self.binaries.keys() {
             let cert_name = format!("{}_certificate.json", keys);
             let full_path = cert_path.join(cert_name);
@@ -56,10 +162,33 @@ impl MetadataConfig {
                 keys.to_string(),
                 toml::Value::String(full_path.to_str().unwrap().to_string()),
             );
+            //Envelopes are only produced by recent certificate generators
+            let cose_path = cert_path.join(format!("{}_certificate.cose", keys));
+            if cose_path.exists() {
+                cose_table.insert(
+                    keys.to_string(),
+                    toml::Value::String(cose_path.to_str().unwrap().to_string()),
+                );
+            }
         }
         CertificateConfig {
             certificates: table,
-            service_mapping: self.service_mapping.clone(),
+            cose_certificates: cose_table,
+            keys: KeyConf {
+                path: key_path.to_str().unwrap().to_string(),
+            },
+            authorities: authorities
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect(),
+            revocation_lists: revocation_lists
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect(),
+            transparency: log_key_path.map(|path| TransparencyConf {
+                log_keys: path.to_str().unwrap().to_string(),
+                require_inclusion_proof: true,
+            }),
         }
     }
     fn parse_to_runtime_conf(self, args: CliArgs) -> RuntimeConfig {
@@ -72,7 +201,34 @@ impl MetadataConfig {
             signing_key: KeyConf {
                 path: args.key_signing_path.to_str().unwrap().to_string(),
             },
-            service_mapping: self.service_mapping,
+            reload_interval: args.reload_interval,
+            session_ttl: args.session_ttl,
+            session_budget: args.session_budget,
+            banned_clients: args
+                .banned_clients
+                .as_ref()
+                .map(|path| path.to_str().unwrap().to_string()),
+            bind_address: args.bind_address,
+            listen_tcp: args.no_tcp.then_some(false),
+            tls: args
+                .tls_certificate
+                .as_ref()
+                .zip(args.tls_key.as_ref())
+                .map(|(certificate, key)| TlsConf {
+                    certificate: certificate.to_str().unwrap().to_string(),
+                    key: key.to_str().unwrap().to_string(),
+                }),
+            insecure: args.insecure,
+            unix_socket: args.unix_socket.as_ref().map(|path| UnixSocketConf {
+                path: path.to_str().unwrap().to_string(),
+                allowed_uids: args.allowed_uids.clone(),
+                allowed_gids: args.allowed_gids.clone(),
+            }),
+            audit_log: args
+                .audit_log
+                .as_ref()
+                .map(|path| path.to_str().unwrap().to_string()),
+            codecs: args.codecs,
         }
     }
 }
@@ -83,13 +239,19 @@ fn main() -> Result<(), ()> {
         std::fs::read_to_string(&args.metadata_path).expect("Couldn't read metadata file provided");
     let base_config: MetadataConfig = toml::from_str(&contents).expect("Toml file malformed");
 
-    let cert_conf = base_config.parse_to_cert_conf(&args.certificate_path);
+    let cert_conf = base_config.parse_to_cert_conf(
+        &args.certificate_path,
+        &args.certificate_key_path,
+        &args.authorities,
+        &args.revocation_lists,
+        args.log_key_path.as_deref(),
+    );
     let runtime_conf = base_config.parse_to_runtime_conf(args);
 
     let mut cert_file =
         File::create("./certificate_config.toml").expect("Couldn't create config file");
     cert_file
-        .write(
+        .write_all(
             toml::to_string_pretty(&cert_conf)
                 .expect("Cert config is malformed TOML")
                 .as_bytes(),
@@ -99,7 +261,7 @@ fn main() -> Result<(), ()> {
     let mut runtime_file =
         File::create("./sidecar_config.toml").expect("Couldn't create config file");
     runtime_file
-        .write(
+        .write_all(
             toml::to_string(&runtime_conf)
                 .expect("Cert config is malformed TOML")
                 .as_bytes(),
@@ -1,18 +1,84 @@
 use std::{
     collections::HashMap,
-    path::{Path, PathBuf},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
 };
 
 use serde::Deserialize;
-use tahini_attest::types::{BinaryName, ServiceName};
+use hoodini_core::codec::Codec;
+use hoodini_core::session::SessionLimits;
+use hoodini_core::types::BinaryName;
+
+use crate::peer::AccessPolicy;
 use toml::{Table, Value};
 
+const DEFAULT_PORT: u16 = 4000;
+
 #[derive(Deserialize)]
 pub(crate) struct SideCarConfig {
     binaries: Table,
     certificates_config: CertificateConfig,
     signing_key: KeyConfig,
-    service_mapping: HashMap<ServiceName, ServiceName>
+    //Seconds between checks of the certificate files for hot reload. No reload when absent
+    reload_interval: Option<u64>,
+    //Seconds a session lives on its server before the client has to rekey
+    session_ttl: Option<u64>,
+    //Number of times a server may fetch the keys of a session
+    session_budget: Option<u32>,
+    //File listing the clients to ban, by caller binary or Unix uid, one per line, read at
+    //startup and at every session sweep
+    banned_clients: Option<String>,
+    //Address clients connect to. Loopback only when absent
+    bind_address: Option<SocketAddr>,
+    //Whether clients may connect over TCP on bind_address. On when absent. The Unix socket
+    //access policy doesn't apply to TCP clients, turn it off to only serve the socket
+    listen_tcp: Option<bool>,
+    //Required for clients on other hosts
+    tls: Option<TlsConfig>,
+    //Allows plain TCP on a non-loopback bind_address, exposing the services asked for and the
+    //key shares to the network
+    #[serde(default)]
+    insecure: bool,
+    //Additional listener for clients on the same host
+    unix_socket: Option<UnixSocketConfig>,
+    //File attestation requests are recorded in. Printed when absent
+    audit_log: Option<String>,
+    //Codecs clients may use for the attestation RPC. All of them when absent
+    codecs: Option<Vec<Codec>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UnixSocketConfig {
+    path: String,
+    //Permissions of the socket file
+    #[serde(default = "default_socket_mode")]
+    mode: u32,
+    #[serde(flatten)]
+    access_policy: AccessPolicy,
+}
+
+//Only the user running the sidecar may connect
+fn default_socket_mode() -> u32 {
+    0o600
+}
+
+impl UnixSocketConfig {
+    pub fn get_path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
+    pub fn get_mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+//Both PEM
+#[derive(Deserialize)]
+pub(crate) struct TlsConfig {
+    //Certificate chain, leaf first. Clients pin the leaf
+    certificate: String,
+    key: String,
 }
 
 #[derive(Deserialize)]
@@ -45,7 +111,63 @@ impl SideCarConfig {
         Path::new(&self.certificates_config.path)
     }
 
-    pub fn get_binaries(&self) -> HashMap<ServiceName, BinaryConfig> {
+    pub fn get_reload_interval(&self) -> Option<Duration> {
+        self.reload_interval.map(Duration::from_secs)
+    }
+
+    pub fn get_session_limits(&self) -> SessionLimits {
+        let defaults = SessionLimits::default();
+        SessionLimits {
+            ttl: self.session_ttl.unwrap_or(defaults.ttl),
+            budget: self.session_budget.unwrap_or(defaults.budget),
+        }
+    }
+
+    pub fn get_banned_clients_path(&self) -> Option<&Path> {
+        self.banned_clients.as_deref().map(Path::new)
+    }
+
+    pub fn get_bind_address(&self) -> SocketAddr {
+        self.bind_address
+            .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT))
+    }
+
+    pub fn get_listen_tcp(&self) -> bool {
+        self.listen_tcp.unwrap_or(true)
+    }
+
+    pub fn get_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    //(certificate, key) paths
+    pub fn get_tls_paths(&self) -> Option<(&Path, &Path)> {
+        self.tls
+            .as_ref()
+            .map(|tls| (Path::new(&tls.certificate), Path::new(&tls.key)))
+    }
+
+    pub fn get_unix_socket(&self) -> Option<&UnixSocketConfig> {
+        self.unix_socket.as_ref()
+    }
+
+    //Policy applying to Unix socket clients
+    pub fn get_access_policy(&self) -> AccessPolicy {
+        self.unix_socket
+            .as_ref()
+            .map(|socket| socket.access_policy.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn get_audit_log_path(&self) -> Option<&Path> {
+        self.audit_log.as_deref().map(Path::new)
+    }
+
+    pub fn get_codecs(&self) -> Vec<Codec> {
+        self.codecs.clone().unwrap_or(Codec::ALL.to_vec())
+    }
+
+    pub fn get_binaries(&self) -> HashMap<BinaryName, BinaryConfig> {
         let mut hashmap = HashMap::new();
         for (k, v) in self.binaries.iter() {
             match v {
@@ -68,13 +190,4 @@ impl SideCarConfig {
         }
         hashmap
     }
-
-
-    pub fn yield_mapping(&self) -> HashMap<ServiceName, ServiceName> {
-        self.service_mapping.clone()
-    }
-
-    pub fn get_service_name(&self, binary_name: &ServiceName) -> Option<&ServiceName> {
-        self.service_mapping.get(&binary_name)
-    }
 }
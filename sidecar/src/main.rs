@@ -1,59 +1,85 @@
 use aws_lc_rs::rand::{SecureRandom, SystemRandom};
 use std::future::Future;
 use std::mem::size_of;
-use aws_lc_rs::signature::Ed25519KeyPair;
+use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
 use futures::StreamExt;
 use std::collections::HashMap;
-use std::io::Read;
-use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::path::Path;
 use std::sync::Arc;
-use tahini_attest::loader::{CertificateLoader, CertificateProvider};
-use tahini_attest::service::{AttestationService, compute_local_share, derive_key_from_shares};
-use tahini_attest::sidecar::{FifoWriterHandle, hash_bin, launch_binary};
-use tahini_attest::types::{
-    BinHash, ClientId, DynamicAttestationData, DynamicAttestationReport, ServiceName,
+use std::time::Duration;
+use hoodini_core::canonical::SigningPayload;
+use hoodini_core::codec::{self, Codec};
+use hoodini_core::certificate::{CertificateLoader, CertificateProvider};
+use hoodini_core::cose;
+use hoodini_core::keys;
+use hoodini_core::reload::watch_config;
+use hoodini_core::service::{AttestationService, ByteBuf, compute_local_share};
+use hoodini_core::tls::{self, TlsAcceptor};
+use hoodini_core::session::{
+    CallerCredential, CallerIdentity, CallerProof, RekeyRequest, RevocationReason, SessionLimits, SessionTranscript, derive_session_keys,
+    unix_now,
+};
+use hoodini_sidecar::{
+    FifoWriterHandle, SessionTracker, TrackedSession, hash_bin, launch_binary,
+};
+use hoodini_core::types::{
+    AttestationFailure, BinHash, BinaryName, ClientId, DynamicAttestationData, DynamicAttestationReport, KeyId,
+    ServiceName, TahiniCertificate,
 };
 use tarpc::serde_transport::new as new_transport;
 use tarpc::server::{BaseChannel, Channel};
-use tarpc::tokio_serde::formats::Json;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
 use tokio_util::codec::LengthDelimitedCodec;
 
 use tokio::sync::{Mutex, RwLock};
 
 mod config;
+mod peer;
+
+use peer::{AccessPolicy, AuditLog, BanList, Peer};
 
-static SERVER_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+//Seconds between session sweeps when certificates are not hot reloaded
+const DEFAULT_SWEEP_INTERVAL: u64 = 60;
+//Milliseconds to wait before accepting again when out of file descriptors
+const ACCEPT_BACKOFF: u64 = 100;
 
 #[derive(Clone)]
 pub struct SideCarServer {
     //For a given binary_name, gives its hash
-    service_bin_map: Arc<RwLock<HashMap<ServiceName, BinHash>>>,
-    //Stuff that loads certificates from disk for attestation
+    service_bin_map: Arc<RwLock<HashMap<BinaryName, BinHash>>>,
+    //Stuff that loads certificates from disk for attestation. Also tells which binary hosts a
+    //service
     certificate_server: Arc<RwLock<CertificateLoader>>,
     //Runtime attestation signing key
     signing_key: Arc<RwLock<Ed25519KeyPair>>,
-    //For a given binary_name, give the functional service living inside
-    service_mapping: Arc<RwLock<HashMap<ServiceName, ServiceName>>>,
-    //For given service, yields the pipe write handler
-    service_key_passing_sessions: Arc<Mutex<HashMap<ServiceName, FifoWriterHandle>>>,
+    //For given binary, yields the pipe write handler
+    service_key_passing_sessions: Arc<Mutex<HashMap<BinaryName, FifoWriterHandle>>>,
+    //Sessions handed out, to rekey them and to revoke them along with their certificate
+    sessions: Arc<Mutex<SessionTracker>>,
+    session_limits: SessionLimits,
+    //For given binary, yields the credential it was launched with, to recognize it as a caller
+    caller_credentials: Arc<RwLock<HashMap<BinaryName, CallerCredential>>>,
+    //Client end of the connection, set on the clone serving it
+    peer: Option<Peer>,
+    //Which Unix socket clients may attest
+    access_policy: Arc<AccessPolicy>,
+    //Clients refused attestation, and whose sessions are evicted
+    banned: Arc<RwLock<BanList>>,
+    audit_log: Arc<AuditLog>,
 }
 
 //Load runtime attestation signing key from disk
 fn load_signing_attestation_key(path: &Path) -> Ed25519KeyPair {
-    let mut file = std::fs::File::open(path).expect("Couldn't find signing key file");
-    let mut contents: Vec<u8> = Vec::new();
-    file.read_to_end(&mut contents)
-        .expect("Couldn't read key file");
-    Ed25519KeyPair::from_pkcs8(&contents).expect("Couldn't parse key bytes")
+    keys::load_signing_key(path).expect("Couldn't load signing key")
 }
 
 impl SideCarServer {
     pub fn new(
         certificate_config_path: &Path,
         key_path: &Path,
-        mapping: HashMap<ServiceName, ServiceName>,
+        session_limits: SessionLimits,
     ) -> Self {
         Self {
             service_bin_map: Arc::new(RwLock::new(HashMap::new())),
@@ -62,15 +88,69 @@ impl SideCarServer {
                     .expect("Couldn't generate certificate handler for the sidecar"),
             )),
             signing_key: Arc::new(RwLock::new(load_signing_attestation_key(key_path))),
-            service_mapping: Arc::new(RwLock::new(mapping)),
             service_key_passing_sessions: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(SessionTracker::new())),
+            session_limits,
+            caller_credentials: Arc::new(RwLock::new(HashMap::new())),
+            peer: None,
+            access_policy: Arc::new(AccessPolicy::default()),
+            banned: Arc::new(RwLock::new(BanList::default())),
+            audit_log: Arc::new(AuditLog::open(None).expect("Couldn't open audit log")),
+        }
+    }
+
+    pub(crate) fn set_access_control(&mut self, access_policy: AccessPolicy, audit_log: AuditLog) {
+        self.access_policy = Arc::new(access_policy);
+        self.audit_log = Arc::new(audit_log);
+    }
+
+    //Clone serving the connection of a given client
+    pub(crate) fn with_peer(&self, peer: Peer) -> Self {
+        Self {
+            peer: Some(peer),
+            ..self.clone()
+        }
+    }
+
+    fn check_access(&self) -> Result<(), AttestationFailure> {
+        match &self.peer {
+            Some(peer) if !self.access_policy.allows(peer) => {
+                Err(AttestationFailure::AccessDeniedError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn check_ban(&self, caller: Option<&CallerIdentity>) -> Result<(), AttestationFailure> {
+        let peer_uid = self.peer.as_ref().and_then(Peer::uid);
+        if self.banned.read().await.bans(peer_uid, caller) {
+            println!(
+                "Refusing banned client {}",
+                self.peer.as_ref().map_or("unknown".to_string(), Peer::to_string)
+            );
+            return Err(AttestationFailure::AccessDeniedError);
         }
+        Ok(())
+    }
+
+    fn audit(
+        &self,
+        request: &str,
+        service_name: &ServiceName,
+        outcome: &Result<DynamicAttestationReport, AttestationFailure>,
+    ) {
+        self.audit_log.record(
+            self.peer.as_ref(),
+            request,
+            service_name,
+            outcome.as_ref().map(|report| &report.client_id),
+        );
     }
 
     //Registers mapping bin_name -> bin_hash
-    pub async fn register_running_service(&mut self, service_name: ServiceName, hash: BinHash) {
+    pub async fn register_running_service(&mut self, bin_name: BinaryName, hash: BinHash) {
         let mut map = self.service_bin_map.write().await;
-        map.insert(service_name, hash);
+        map.insert(bin_name, hash);
     }
 
     //Debugging purposes
@@ -78,114 +158,422 @@ impl SideCarServer {
         println!("{:#?}", self.service_bin_map.read().await);
     }
 
-    //Registers bin_name -> pipe handler
+    //Registers bin_name -> pipe handler, and hands the binary its caller credential
     pub async fn setup_service_key_channel(
         &mut self,
-        service_name: ServiceName,
-        handler: FifoWriterHandle,
+        bin_name: BinaryName,
+        mut handler: FifoWriterHandle,
     ) {
+        let credential = CallerCredential::generate(bin_name.clone())
+            .expect("Couldn't generate caller credential");
+        handler
+            .send_credential(&credential)
+            .expect("Couldn't write credential to service pipe");
+        self.caller_credentials
+            .write()
+            .await
+            .insert(bin_name.clone(), credential);
         let mut map = self.service_key_passing_sessions.lock().await;
-        match map.insert(service_name.clone(), handler) {
-            None => println!("Registered service {}", &service_name),
-            Some(_) => panic!("Service shouldn't be registered for the sidecar"),
+        match map.insert(bin_name.clone(), handler) {
+            None => println!("Registered binary {}", &bin_name),
+            Some(_) => panic!("Binary shouldn't be registered for the sidecar"),
         }
     }
-}
 
-impl AttestationService for SideCarServer {
-    //API exposed to client.
     //Does the following (functionally):
-    //Generates client session key (via key agreement protocol)
+    //Finds the binary hosting the service
     //Generates client ID
     //Generates attestation report
     //Signs attestation report
-    //Sends (client_id, session_key) to server via pipe
+    //Generates client session keys (via key agreement protocol, bound to the report)
+    //Sends (client_id, session_keys, expiry, budget, caller) to server via pipe
     //Returns (client_id, server_key_share, attestation_report) to client
-    async fn attest_binary(
-        self,
-        _context: tarpc::context::Context,
+    async fn attest(
+        &self,
         service_name: ServiceName,
         nonce: u128,
         key_share: Vec<u8>,
-    ) -> DynamicAttestationReport {
-        let bin_map = self.service_bin_map.read().await;
+        caller: Option<CallerProof>,
+    ) -> Result<DynamicAttestationReport, AttestationFailure> {
+        let attestation = async {
+            self.check_access()?;
+            let caller = match caller {
+                Some(proof) => Some(
+                    self.caller_identity(&proof, &service_name, nonce, &key_share)
+                        .await?,
+                ),
+                None => None,
+            };
+            self.check_ban(caller.as_ref()).await?;
+            self.attest_client(service_name.clone(), nonce, key_share, None, caller)
+                .await
+        };
+        let outcome = attestation.await.inspect_err(|failure| {
+            println!("Couldn't attest service {}: {}", service_name, failure)
+        });
+        self.audit("attest", &service_name, &outcome);
+        outcome
+    }
 
-        let bin = bin_map
-            .get(&service_name)
-            .expect("Binary doesn't exist in sidecar map");
+    //Attests the service again for a live session, once the request proved it holds its keys
+    async fn rekey_session(
+        &self,
+        request: RekeyRequest,
+    ) -> Result<DynamicAttestationReport, AttestationFailure> {
+        self.check_access()?;
+        let sessions = self.sessions.lock().await;
+        //The caller stays the one the session was opened for
+        let caller = sessions
+            .get(&request.client_id, unix_now())
+            .filter(|session| {
+                session.service_name == request.service_name
+                    && session.keys.check_rekey_proof(&request)
+            })
+            .map(|session| session.caller.clone());
+        drop(sessions);
+        let Some(caller) = caller else {
+            println!("Refusing to rekey session of client {}", request.client_id);
+            return Err(AttestationFailure::RekeyRefusedError);
+        };
+        self.check_ban(caller.as_ref()).await?;
+        self.attest_client(
+            request.service_name,
+            request.nonce,
+            request.key_share,
+            Some(request.client_id),
+            caller,
+        )
+        .await
+    }
 
+    //Binary a caller proved to be, with its certificate. The caller must have been launched by
+    //this sidecar, and its certificate must not be revoked
+    async fn caller_identity(
+        &self,
+        proof: &CallerProof,
+        service_name: &ServiceName,
+        nonce: u128,
+        key_share: &[u8],
+    ) -> Result<CallerIdentity, AttestationFailure> {
+        let credentials = self.caller_credentials.read().await;
+        let proven = credentials
+            .get(&proof.bin_name)
+            .is_some_and(|credential| credential.check(proof, service_name, nonce, key_share));
+        if !proven {
+            println!("Caller claiming to be binary {} failed to prove it", proof.bin_name);
+            return Err(AttestationFailure::CallerIdentityError);
+        }
+        let certificate_handler = self.certificate_server.read().await;
+        let certificate = certificate_handler
+            .get_binary_certificate(&proof.bin_name)
+            .filter(|certificate| !certificate_handler.is_revoked(certificate))
+            .ok_or(AttestationFailure::CallerIdentityError)?;
+        Ok(CallerIdentity::new(proof.bin_name.clone(), certificate.clone()))
+    }
+
+    //Same as attest, keeping the client id and the caller of a rekeyed session
+    async fn attest_client(
+        &self,
+        service_name: ServiceName,
+        nonce: u128,
+        key_share: Vec<u8>,
+        client_id: Option<ClientId>,
+        caller: Option<CallerIdentity>,
+    ) -> Result<DynamicAttestationReport, AttestationFailure> {
         let certificate_handler = self.certificate_server.read().await;
 
         println!(
             "We are requested certificate for service {:?}",
             service_name
         );
-        let certificate = certificate_handler.get_certificate(&service_name).unwrap();
+        let bin_name = certificate_handler
+            .binary_for(&service_name)
+            .ok_or(AttestationFailure::UnknownServiceError)?;
+        let certificate = certificate_handler
+            .get_binary_certificate(bin_name)
+            .ok_or(AttestationFailure::MissingCertificateError)?;
 
-        let mut usize_b = [0u8; size_of::<usize>()];
-        let rng = SystemRandom::new();
-        rng.fill(&mut usize_b).expect("Couldn't generate client id");
-        let client_id = ClientId::from(usize::from_be_bytes(usize_b));
+        let bin_map = self.service_bin_map.read().await;
+        let bin = bin_map
+            .get(bin_name)
+            .ok_or(AttestationFailure::BinaryNotRunningError)?;
+        if certificate_handler.is_revoked(certificate) {
+            return Err(AttestationFailure::CertificateRevokedError);
+        }
+
+        let client_id = match client_id {
+            Some(client_id) => client_id,
+            None => {
+                let mut usize_b = [0u8; size_of::<usize>()];
+                let rng = SystemRandom::new();
+                rng.fill(&mut usize_b)
+                    .map_err(|_| AttestationFailure::InternalError)?;
+                ClientId::from(usize::from_be_bytes(usize_b))
+            }
+        };
 
         let (sk, pk) = compute_local_share();
-        let usable_key = derive_key_from_shares(sk, key_share);
 
         let signing_data = DynamicAttestationData {
             cert: certificate,
             nonce,
             service_name: service_name.clone(),
             current_bin_hash: bin.clone(),
+            client_key_share: key_share.clone(),
             server_key_share: pk.as_ref().to_vec(),
             client_id: client_id.clone(),
         };
 
-        let sign_data_u8 =
-            serde_json::to_vec(&signing_data).expect("Couldn't transform signing data to bytes");
+        let sign_data_u8 = signing_data.signing_payload();
         let signer = self.signing_key.read().await;
         let sig = signer.sign(&sign_data_u8).into();
 
-        println!("Trying to access handler for service {}", &service_name);
+        //Session keys are bound to everything the report attests to
+        //Certificates are verified on load, so their hashes are hex
+        let transcript = SessionTranscript::from_data(&signing_data)
+            .map_err(|_| AttestationFailure::InternalError)?;
+        //The sidecar share is freshly generated, so only the client share can be at fault
+        let session_keys = derive_session_keys(sk, &key_share, &transcript)
+            .map_err(|_| AttestationFailure::MalformedKeyShareError)?;
+        let expires_at = unix_now() + self.session_limits.ttl;
+
+        println!("Trying to access handler for binary {}", bin_name);
         let mut locked_session_handler = self.service_key_passing_sessions.lock().await;
         locked_session_handler
-            .get_mut(
-                self.service_mapping
-                    .read()
-                    .await
-                    .get(&service_name)
-                    .expect("Provided binary is not registered"),
+            .get_mut(bin_name)
+            .ok_or(AttestationFailure::ServiceUnavailableError)?
+            .open_session(
+                &client_id,
+                session_keys.clone(),
+                expires_at,
+                &self.session_limits,
+                caller.clone(),
             )
-            .expect("Service should have a handler but didn't")
-            .write_session_key(&usable_key.to_vec(), &client_id)
-            .expect("Couldn't write session to service pipe");
+            .map_err(|e| {
+                println!("Couldn't hand session keys to binary {}: {}", bin_name, e);
+                AttestationFailure::ServiceUnavailableError
+            })?;
         drop(locked_session_handler);
-        DynamicAttestationReport {
+        self.sessions.lock().await.track(
+            client_id.clone(),
+            TrackedSession {
+                bin_name: bin_name.clone(),
+                service_name: service_name.clone(),
+                certificate: certificate.clone(),
+                keys: session_keys,
+                expires_at,
+                caller,
+                peer_uid: self.peer.as_ref().and_then(Peer::uid),
+            },
+        );
+        Ok(DynamicAttestationReport {
             certificate: certificate.clone(),
             current_bin_hash: bin.clone(),
             nonce,
             service_name,
+            client_key_share: key_share,
             server_key_share: pk.as_ref().to_vec(),
             client_id,
             signature: sig,
+        })
+    }
+}
+
+impl SideCarServer {
+    //Forgets expired sessions, and makes servers evict the sessions attested under certificates
+    //revoked since
+    pub async fn sweep_sessions(&self) {
+        let revoked = {
+            let certificate_handler = self.certificate_server.read().await;
+            let mut sessions = self.sessions.lock().await;
+            sessions.remove_expired(unix_now());
+            sessions.revoke_where(|certificate| certificate_handler.is_revoked(certificate))
+        };
+        self.push_revocations(revoked, RevocationReason::CertificateRevoked)
+            .await;
+    }
+
+    //Refuses attestation to the banned clients from now on, and makes servers evict their
+    //sessions. Banned clients can't rekey either, their sessions being forgotten
+    pub(crate) async fn ban_clients(&self, banned: BanList) {
+        let evicted = self
+            .sessions
+            .lock()
+            .await
+            .remove_where(|session| banned.bans_session(session));
+        *self.banned.write().await = banned;
+        self.push_revocations(evicted, RevocationReason::ClientBanned)
+            .await;
+    }
+
+    async fn push_revocations(
+        &self,
+        revoked: HashMap<BinaryName, Vec<ClientId>>,
+        reason: RevocationReason,
+    ) {
+        let mut locked_session_handler = self.service_key_passing_sessions.lock().await;
+        for (bin_name, client_ids) in revoked.into_iter() {
+            println!(
+                "Revoking {} sessions of binary {}: {:?}",
+                client_ids.len(),
+                bin_name,
+                reason
+            );
+            let Some(handler) = locked_session_handler.get_mut(&bin_name) else {
+                println!("No handler for binary {}, its sessions are left to expire", bin_name);
+                continue;
+            };
+            if let Err(e) = handler.revoke_sessions(client_ids, reason) {
+                println!("Couldn't write revocation to binary {}: {}", bin_name, e);
+            }
         }
     }
 }
 
+//Keeps the previous list when the file can't be read, rather than lifting every ban
+async fn reload_banned_clients(server: &SideCarServer, path: &Path) {
+    match BanList::read(path) {
+        Ok(banned) => server.ban_clients(banned).await,
+        Err(e) => println!("Couldn't read banned clients file {}: {}", path.display(), e),
+    }
+}
+
+impl AttestationService for SideCarServer {
+    //API exposed to client.
+    async fn attest_binary(
+        self,
+        _context: tarpc::context::Context,
+        service_name: ServiceName,
+        nonce: u128,
+        key_share: ByteBuf,
+        caller: Option<CallerProof>,
+    ) -> Result<DynamicAttestationReport, AttestationFailure> {
+        self.attest(service_name, nonce, key_share.into_vec(), caller).await
+    }
+
+    //Same report, as a COSE_Sign1 envelope embedding the issuer-signed certificate envelope
+    async fn attest_binary_cose(
+        self,
+        _context: tarpc::context::Context,
+        service_name: ServiceName,
+        nonce: u128,
+        key_share: ByteBuf,
+        caller: Option<CallerProof>,
+    ) -> Result<ByteBuf, AttestationFailure> {
+        //Before telling anything about the service
+        if let Err(failure) = self.check_access() {
+            self.audit("attest", &service_name, &Err(failure));
+            return Err(failure);
+        }
+        //Checked first, so that no session is opened for a report that can't be sent
+        if self
+            .certificate_server
+            .read()
+            .await
+            .get_certificate_envelope(&service_name)
+            .is_none()
+        {
+            println!("No COSE certificate registered for service {}", service_name);
+            return Err(AttestationFailure::MissingCoseCertificateError);
+        }
+        let report = self.attest(service_name, nonce, key_share.into_vec(), caller).await?;
+        let certificate_handler = self.certificate_server.read().await;
+        //Certificates may have been reloaded in between
+        let certificate_envelope = certificate_handler
+            .get_certificate_envelope(&report.service_name)
+            .ok_or(AttestationFailure::MissingCoseCertificateError)?;
+        let signing_data = report.attestation_data();
+        let signer = self.signing_key.read().await;
+        let key_id = KeyId::from_public_key(signer.public_key().as_ref());
+        cose::sign_report(&signing_data, certificate_envelope, &key_id, &signer)
+            .map(ByteBuf::from)
+            .map_err(|e| {
+                println!("Couldn't encode COSE attestation report: {:?}", e);
+                AttestationFailure::InternalError
+            })
+    }
+
+    //New keys for a live session, with a fresh report. The client proves it holds the current keys
+    async fn rekey(
+        self,
+        _context: tarpc::context::Context,
+        request: RekeyRequest,
+    ) -> Result<DynamicAttestationReport, AttestationFailure> {
+        let service_name = request.service_name.clone();
+        let outcome = self.rekey_session(request).await.inspect_err(|failure| {
+            println!("Couldn't rekey service {}: {}", service_name, failure)
+        });
+        self.audit("rekey", &service_name, &outcome);
+        outcome
+    }
+
+    async fn get_certificate(
+        self,
+        _context: tarpc::context::Context,
+        service_name: ServiceName,
+    ) -> Option<TahiniCertificate> {
+        let certificate_handler = self.certificate_server.read().await;
+        certificate_handler.get_certificate(&service_name).cloned()
+    }
+
+    async fn list_services(self, _context: tarpc::context::Context) -> Vec<ServiceName> {
+        let certificate_handler = self.certificate_server.read().await;
+        certificate_handler.services().cloned().collect()
+    }
+}
+
 async fn wait_upon(fut: impl Future<Output = ()>) {
     fut.await
 }
 
 #[tokio::main]
-#[allow(unreachable_code)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("In sidecar main");
-    let listener = TcpListener::bind(&(SERVER_ADDRESS, 4000)).await.unwrap();
-    let codec_builder = LengthDelimitedCodec::builder();
-
     let config = config::SideCarConfig::new(Path::new("./sidecar_config.toml"));
+    let bind_address = config.get_bind_address();
+    //Checked before binding, so that nothing is exposed
+    let plain_remote_tcp = config.get_listen_tcp()
+        && config.get_tls_paths().is_none()
+        && !bind_address.ip().is_loopback();
+    if plain_remote_tcp {
+        if !config.get_insecure() {
+            return Err(format!(
+                "Refusing to serve plain TCP on non-loopback address {}, configure [tls] or set insecure = true",
+                bind_address
+            )
+            .into());
+        }
+        println!("Warning: serving plain TCP on non-loopback address {}", bind_address);
+    }
+    let listener = match config.get_listen_tcp() {
+        true => Some(TcpListener::bind(bind_address).await?),
+        false if config.get_unix_socket().is_none() => {
+            return Err("listen_tcp is off and no [unix_socket] is configured".into())
+        }
+        false => None,
+    };
+    if listener.is_some() && config.get_access_policy().restricts() {
+        println!(
+            "Warning: the Unix socket access policy doesn't apply to TCP clients on {}, set listen_tcp = false to only serve the socket",
+            bind_address
+        );
+    }
+    let acceptor = config.get_tls_paths().map(|(certificate_path, key_path)| {
+        TlsAcceptor::from(
+            tls::server_config(certificate_path, key_path).expect("Couldn't load TLS certificate"),
+        )
+    });
+    //Codecs clients may ask for, see hoodini_core::codec
+    let codecs: Arc<[Codec]> = config.get_codecs().into();
     let mut server = SideCarServer::new(
         config.get_certificate_config_path(),
         config.get_key_path(),
-        config.yield_mapping(),
+        config.get_session_limits(),
+    );
+
+    server.set_access_control(
+        config.get_access_policy(),
+        AuditLog::open(config.get_audit_log_path()).expect("Couldn't open audit log"),
     );
 
     let binaries = config.get_binaries();
@@ -196,13 +584,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let handler =
             launch_binary(bin_setup.bin_path, bin_setup.run_path).expect("Couldn't start binary");
         server
-            .setup_service_key_channel(
-                config
-                    .get_service_name(&bin_name)
-                    .expect("Binary->Service mapping does not exist")
-                    .clone(),
-                handler,
-            )
+            .setup_service_key_channel(bin_name.clone(), handler)
             .await;
         server.register_running_service(bin_name, hash).await;
     }
@@ -211,17 +593,361 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server = server;
     server.show_running_binaries().await;
 
-    //Expose sidecar to clients (usual tarpc way)
-    loop {
-        let (stream, _peer_addr) = listener.accept().await.unwrap();
-        println!("Accepted a connection");
-        let framed = codec_builder.new_framed(stream);
+    //Certificates are swapped in place when their files change, binaries keep running
+    if let Some(interval) = config.get_reload_interval() {
+        let config_path = config.get_certificate_config_path().to_path_buf();
+        tokio::spawn(watch_config(
+            config_path.clone(),
+            server.certificate_server.clone(),
+            interval,
+            move |reloaded| match reloaded {
+                Ok(()) => println!("Reloaded certificates from {:?}", config_path),
+                Err(e) => println!(
+                    "Couldn't reload certificates from {:?}, keeping the previous ones: {}",
+                    config_path, e
+                ),
+            },
+        ));
+    }
+
+    //Sessions of revoked certificates are evicted once the revocation list is reloaded
+    let sweeper = server.clone();
+    let sweep_interval = config
+        .get_reload_interval()
+        .unwrap_or(Duration::from_secs(DEFAULT_SWEEP_INTERVAL));
+    let banned_clients_path = config.get_banned_clients_path().map(Path::to_path_buf);
+    //Bans hold from the first connection
+    if let Some(path) = &banned_clients_path {
+        reload_banned_clients(&server, path).await;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(sweep_interval).await;
+            sweeper.sweep_sessions().await;
+            if let Some(path) = &banned_clients_path {
+                reload_banned_clients(&sweeper, path).await;
+            }
+        }
+    });
+
+    let mut listeners = Vec::new();
+
+    //Co-located clients, identified by the credentials of their process
+    if let Some(socket_config) = config.get_unix_socket() {
+        let unix_listener = bind_unix_socket(socket_config.get_path(), socket_config.get_mode())
+            .expect("Couldn't bind Unix socket");
+        let server = server.clone();
+        let codecs = codecs.clone();
+        listeners.push(tokio::spawn(async move {
+            loop {
+                let (stream, _addr) = match unix_listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        accept_failed(e).await;
+                        continue;
+                    }
+                };
+                match Peer::from_unix_stream(&stream) {
+                    Ok(peer) => {
+                        println!("Accepted a connection from {}", peer);
+                        tokio::spawn(serve_connection(
+                            server.with_peer(peer),
+                            stream,
+                            codecs.clone(),
+                        ));
+                    }
+                    Err(e) => println!("Couldn't read credentials of Unix socket peer: {}", e),
+                }
+            }
+        }));
+    }
+
+    //Expose sidecar to clients (usual tarpc way), after the TLS handshake if configured
+    if let Some(listener) = listener {
+        listeners.push(tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        accept_failed(e).await;
+                        continue;
+                    }
+                };
+                println!("Accepted a connection from {}", peer_addr);
+                let server = server.with_peer(Peer::Tcp(peer_addr));
+                let codecs = codecs.clone();
+                match acceptor.clone() {
+                    None => {
+                        tokio::spawn(serve_connection(server, stream, codecs));
+                    }
+                    Some(acceptor) => {
+                        tokio::spawn(async move {
+                            match acceptor.accept(stream).await {
+                                Ok(stream) => serve_connection(server, stream, codecs).await,
+                                Err(e) => println!("TLS handshake with {} failed: {}", peer_addr, e),
+                            }
+                        });
+                    }
+                }
+            }
+        }));
+    }
+
+    for listener in listeners {
+        listener.await?;
+    }
+    Ok(())
+}
+
+//Binds the socket and restricts its permissions. A socket file left by a previous run is replaced,
+//any other file is not
+//Accept errors only concern the connection being accepted, so listeners keep going. Running out of
+//file descriptors would fail every accept until some are released, hence the pause
+async fn accept_failed(e: std::io::Error) {
+    println!("Couldn't accept a connection: {}", e);
+    if matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) {
+        tokio::time::sleep(Duration::from_millis(ACCEPT_BACKOFF)).await;
+    }
+}
+
+fn bind_unix_socket(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(_) => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    //Clients can only connect once bound, so tightening right after leaves no usable window as
+    //long as the parent directory is private
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    println!("Listening on Unix socket {} with mode {:o}", path.display(), mode);
+    Ok(listener)
+}
+
+//Serves the connection in the codec the client asks for, if it is one of `codecs`
+async fn serve_connection<S>(server: SideCarServer, mut stream: S, codecs: Arc<[Codec]>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let codec = match codec::accept_codec(&mut stream, &codecs).await {
+        Ok(codec) => codec,
+        Err(e) => {
+            println!("Closing connection: {}", e);
+            return;
+        }
+    };
+    let framed = LengthDelimitedCodec::builder().new_framed(stream);
+    let transport = new_transport(framed, codec);
+    BaseChannel::with_defaults(transport)
+        .execute(server.serve())
+        .for_each(wait_upon)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hoodini_core::service::AttestationServiceClient;
+    use hoodini_core::session::SessionKeys;
+    use hoodini_core::types::{CertificateVersion, PolicyHash, Signature};
+    use tarpc::context;
+
+    //Unsigned v1 certificate of a binary hosting the service of the same name
+    fn legacy_certificate(bin_name: &str) -> TahiniCertificate {
+        TahiniCertificate {
+            version: CertificateVersion::V1,
+            service_name: ServiceName(bin_name.to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: None,
+            policy_manifest: None,
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        }
+    }
+
+    //Sidecar with no certificate nor binary, tracking a single session for hoodini-server
+    async fn server_with_session(client_id: &ClientId, keys: &SessionKeys) -> SideCarServer {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let server = SideCarServer {
+            service_bin_map: Arc::new(RwLock::new(HashMap::new())),
+            certificate_server: Arc::new(RwLock::new(CertificateLoader::new())),
+            signing_key: Arc::new(RwLock::new(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap())),
+            service_key_passing_sessions: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(SessionTracker::new())),
+            session_limits: SessionLimits::default(),
+            caller_credentials: Arc::new(RwLock::new(HashMap::new())),
+            peer: None,
+            access_policy: Arc::new(AccessPolicy::default()),
+            banned: Arc::new(RwLock::new(BanList::default())),
+            audit_log: Arc::new(AuditLog::open(None).unwrap()),
+        };
+        let certificate = legacy_certificate("hoodini-server");
+        server.sessions.lock().await.track(
+            client_id.clone(),
+            TrackedSession {
+                bin_name: BinaryName::from("hoodini-server".to_string()),
+                service_name: certificate.service_name.clone(),
+                certificate,
+                keys: keys.clone(),
+                expires_at: unix_now() + 60,
+                caller: None,
+                peer_uid: None,
+            },
+        );
+        server
+    }
+
+    fn rekey_request(client_id: &ClientId, keys: &SessionKeys) -> RekeyRequest {
+        let mut request = RekeyRequest {
+            client_id: client_id.clone(),
+            service_name: ServiceName("hoodini-server".to_string()),
+            nonce: 1,
+            key_share: vec![3; 32],
+            proof: Vec::new(),
+        };
+        request.proof = keys.rekey_proof(&request).unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn rekeying_needs_a_valid_proof() {
+        let client_id = ClientId::from(1);
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        let server = server_with_session(&client_id, &keys).await;
+        let refused = |result: Result<DynamicAttestationReport, AttestationFailure>| {
+            matches!(result, Err(AttestationFailure::RekeyRefusedError))
+        };
+
+        let mut other_keys = keys.clone();
+        other_keys.client_to_server[0] ^= 1;
+        assert!(refused(server.rekey_session(rekey_request(&client_id, &other_keys)).await));
+
+        let mut truncated = rekey_request(&client_id, &keys);
+        truncated.proof.pop();
+        assert!(refused(server.rekey_session(truncated).await));
+
+        let mut other_service = rekey_request(&client_id, &keys);
+        other_service.service_name = ServiceName("hoodini-admin".to_string());
+        other_service.proof = keys.rekey_proof(&other_service).unwrap();
+        assert!(refused(server.rekey_session(other_service).await));
+
+        let other_client = ClientId::from(2);
+        assert!(refused(server.rekey_session(rekey_request(&other_client, &keys)).await));
+
+        //A valid proof gets past the session check, and attestation then fails on the missing
+        //certificate
+        assert!(matches!(
+            server.rekey_session(rekey_request(&client_id, &keys)).await,
+            Err(AttestationFailure::UnknownServiceError)
+        ));
+    }
 
-        let transport = new_transport(framed, Json::default());
-        let fut = BaseChannel::with_defaults(transport)
-            .execute(server.clone().serve())
-            .for_each(wait_upon);
-        tokio::spawn(fut);
+    //Client of the sidecar, over an in-memory stream in the given codec
+    async fn connect(server: SideCarServer, codec: Codec) -> AttestationServiceClient {
+        let (mut client, sidecar) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(server, sidecar, Arc::from(Codec::ALL)));
+        codec::request_codec(&mut client, codec).await.unwrap();
+        let framed = LengthDelimitedCodec::builder().new_framed(client);
+        AttestationServiceClient::new(Default::default(), new_transport(framed, codec)).spawn()
+    }
+
+    #[tokio::test]
+    async fn failures_reach_the_client() {
+        let client_id = ClientId::from(1);
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        for codec in Codec::ALL {
+            let server = server_with_session(&client_id, &keys).await;
+            let client = connect(server, codec).await;
+            let service_name = ServiceName("hoodini-server".to_string());
+            let key_share = ByteBuf::from(vec![3; 32]);
+            let attested = client
+                .attest_binary(context::current(), service_name.clone(), 1, key_share.clone(), None)
+                .await
+                .unwrap();
+            assert_eq!(attested.unwrap_err(), AttestationFailure::UnknownServiceError);
+            let attested = client
+                .attest_binary_cose(context::current(), service_name, 1, key_share, None)
+                .await
+                .unwrap();
+            assert_eq!(attested.unwrap_err(), AttestationFailure::MissingCoseCertificateError);
+
+            let mut refused = rekey_request(&client_id, &keys);
+            refused.proof.pop();
+            let rekeyed = client.rekey(context::current(), refused).await.unwrap();
+            assert_eq!(rekeyed.unwrap_err(), AttestationFailure::RekeyRefusedError);
+        }
+    }
+
+    #[tokio::test]
+    async fn callers_must_prove_their_identity() {
+        let client_id = ClientId::from(1);
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        let server = server_with_session(&client_id, &keys).await;
+        let caller = BinaryName::from("hoodini-caller".to_string());
+        let credential = CallerCredential::generate(caller.clone()).unwrap();
+        server
+            .caller_credentials
+            .write()
+            .await
+            .insert(caller.clone(), credential.clone());
+        let service_name = ServiceName("hoodini-server".to_string());
+        let key_share = [3; 32];
+        let identity = |proof: CallerProof| {
+            let server = &server;
+            let service_name = &service_name;
+            async move { server.caller_identity(&proof, service_name, 1, &key_share).await }
+        };
+
+        //Wrong credential for the binary, binary unknown to the sidecar, or proof of another
+        //request
+        let impostor = CallerCredential::generate(caller.clone()).unwrap();
+        let unknown =
+            CallerCredential::generate(BinaryName::from("hoodini-other".to_string())).unwrap();
+        for proof in [
+            impostor.prove(&service_name, 1, &key_share),
+            unknown.prove(&service_name, 1, &key_share),
+            credential.prove(&service_name, 2, &key_share),
+        ] {
+            assert!(matches!(
+                server.attest(service_name.clone(), 1, key_share.to_vec(), Some(proof)).await,
+                Err(AttestationFailure::CallerIdentityError)
+            ));
+        }
+
+        //A valid proof still needs the caller's certificate
+        let proof = credential.prove(&service_name, 1, &key_share);
+        assert_eq!(
+            identity(proof.clone()).await.unwrap_err(),
+            AttestationFailure::CallerIdentityError
+        );
+        //Key pair shared with the hoodini-core key loading tests
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../tahini_attest/crates/hoodini-core/testdata/keys");
+        let issuer = keys::load_signing_key(&testdata.join("ed25519_pkcs8_v1.pem")).unwrap();
+        let mut certificate = legacy_certificate("hoodini-caller");
+        certificate.signature = issuer.sign(&certificate.signed_data().unwrap()).into();
+        {
+            let mut loader = server.certificate_server.write().await;
+            loader.allow_legacy_certificates(true);
+            loader.load_certificate_key(&testdata.join("ed25519_public.pem")).unwrap();
+            loader.register_certificate(certificate).unwrap();
+        }
+        assert_eq!(identity(proof).await.unwrap().bin_name, caller);
     }
-    unreachable!()
 }
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use hoodini_core::session::{unix_now, CallerIdentity};
+use hoodini_core::types::{AttestationFailure, BinaryName, ClientId, ServiceName};
+use hoodini_sidecar::TrackedSession;
+use serde::Deserialize;
+use tokio::net::UnixStream;
+
+//Client end of a connection to the sidecar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Peer {
+    Tcp(SocketAddr),
+    //Credentials the kernel recorded when the client connected (SO_PEERCRED). They hold for every
+    //request made over the connection
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+        //Groups of the user, see user_groups
+        groups: Vec<u32>,
+    },
+}
+
+impl Peer {
+    pub fn from_unix_stream(stream: &UnixStream) -> io::Result<Self> {
+        let credentials = stream.peer_cred()?;
+        Ok(Peer::Unix {
+            uid: credentials.uid(),
+            gid: credentials.gid(),
+            pid: credentials.pid(),
+            groups: user_groups(credentials.uid(), credentials.gid())?,
+        })
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        match self {
+            Peer::Tcp(_) => None,
+            Peer::Unix { uid, .. } => Some(*uid),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(address) => write!(f, "tcp:{}", address),
+            Peer::Unix { uid, gid, pid, .. } => {
+                write!(f, "unix:uid={},gid={}", uid, gid)?;
+                match pid {
+                    Some(pid) => write!(f, ",pid={}", pid),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+//Groups `uid` belongs to in the group database, supplementary ones included, along with `gid`.
+//Read when the client connects, so this is the membership the database records, not groups the
+//process itself dropped or joined
+fn user_groups(uid: u32, gid: u32) -> io::Result<Vec<u32>> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut entry: *mut libc::passwd = std::ptr::null_mut();
+    loop {
+        let status = unsafe {
+            libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut entry)
+        };
+        match status {
+            0 => break,
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            _ => return Err(io::Error::from_raw_os_error(status)),
+        }
+    }
+    //Users without an entry, e.g. in containers, only have the group they run as
+    if entry.is_null() {
+        return Ok(vec![gid]);
+    }
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let status =
+            unsafe { libc::getgrouplist(passwd.pw_name, gid, groups.as_mut_ptr(), &mut count) };
+        if status >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        //The buffer was too small, count now holds the number of groups
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+//Local users allowed to attest over the Unix socket. With both lists empty, anyone able to open
+//the socket is. A user is allowed through any of its groups, supplementary ones included.
+//TCP clients are not restricted here: set listen_tcp to false in the sidecar config to only
+//serve the socket, as any local user can reach the loopback listener
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct AccessPolicy {
+    #[serde(default)]
+    allowed_uids: Vec<u32>,
+    #[serde(default)]
+    allowed_gids: Vec<u32>,
+}
+
+impl AccessPolicy {
+    pub fn allows(&self, peer: &Peer) -> bool {
+        match peer {
+            Peer::Tcp(_) => true,
+            Peer::Unix { uid, groups, .. } => {
+                !self.restricts()
+                    || self.allowed_uids.contains(uid)
+                    || groups.iter().any(|gid| self.allowed_gids.contains(gid))
+            }
+        }
+    }
+
+    pub fn restricts(&self) -> bool {
+        !self.allowed_uids.is_empty() || !self.allowed_gids.is_empty()
+    }
+}
+
+//Clients banned from attesting, listed in the banned clients file. Client ids are drawn for every
+//session, so bans name what outlives them: the binary a caller proved to be (`binary:<name>`),
+//or the local user of a Unix socket client (`uid:<uid>`). TCP clients presenting no caller
+//credential can't be told apart, and can't be banned here
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct BanList {
+    binaries: HashSet<BinaryName>,
+    uids: HashSet<u32>,
+}
+
+impl BanList {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    //One entry per line, skipping blank lines and # comments
+    pub fn parse(contents: &str) -> Self {
+        let mut list = Self::default();
+        for line in contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            match line.split_once(':') {
+                Some(("binary", name)) if !name.trim().is_empty() => {
+                    list.binaries.insert(BinaryName::from(name.trim().to_string()));
+                }
+                Some(("uid", uid)) => match uid.trim().parse() {
+                    Ok(uid) => {
+                        list.uids.insert(uid);
+                    }
+                    Err(_) => println!("Ignoring malformed banned uid {}", line),
+                },
+                _ => println!("Ignoring malformed ban {}", line),
+            }
+        }
+        list
+    }
+
+    pub fn bans(&self, peer_uid: Option<u32>, caller: Option<&CallerIdentity>) -> bool {
+        peer_uid.is_some_and(|uid| self.uids.contains(&uid))
+            || caller.is_some_and(|caller| self.binaries.contains(&caller.bin_name))
+    }
+
+    pub fn bans_session(&self, session: &TrackedSession) -> bool {
+        self.bans(session.peer_uid, session.caller.as_ref())
+    }
+}
+
+//One line per attestation or rekey request: time, peer, request, service and outcome.
+//Appended to a file when configured, printed otherwise
+pub(crate) struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&Path>) -> io::Result<Self> {
+        let file = match path {
+            None => None,
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+        };
+        Ok(Self { file })
+    }
+
+    pub fn record(
+        &self,
+        peer: Option<&Peer>,
+        request: &str,
+        service_name: &ServiceName,
+        outcome: Result<&ClientId, &AttestationFailure>,
+    ) {
+        let peer = peer.map_or("unknown".to_string(), Peer::to_string);
+        let outcome = match outcome {
+            Ok(client_id) => format!("ok client_id={}", client_id),
+            Err(failure) => format!("failed code={} ({})", failure.code(), failure),
+        };
+        let line = format!(
+            "{} peer={} request={} service={} {}",
+            unix_now(),
+            peer,
+            request,
+            service_name,
+            outcome
+        );
+        match &self.file {
+            None => println!("Audit: {}", line),
+            Some(file) => {
+                let mut file = file.lock().expect("Audit log lock poisoned");
+                if writeln!(file, "{}", line).is_err() {
+                    println!("Couldn't write to audit log: {}", line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix_peer(uid: u32, groups: Vec<u32>) -> Peer {
+        Peer::Unix {
+            uid,
+            gid: groups[0],
+            pid: None,
+            groups,
+        }
+    }
+
+    #[test]
+    fn policy_allows_listed_users_and_groups() {
+        let policy = AccessPolicy {
+            allowed_uids: vec![1000],
+            allowed_gids: vec![27],
+        };
+        assert!(policy.allows(&unix_peer(1000, vec![1000])));
+        //Through a supplementary group
+        assert!(policy.allows(&unix_peer(1001, vec![1001, 27])));
+        assert!(!policy.allows(&unix_peer(1001, vec![1001, 100])));
+        assert!(AccessPolicy::default().allows(&unix_peer(1001, vec![1001])));
+    }
+
+    #[test]
+    fn groups_include_the_primary_group() {
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+        assert!(user_groups(uid, gid).unwrap().contains(&gid));
+        //Unknown users only have the group they run as
+        assert_eq!(user_groups(u32::MAX - 1, 4242).unwrap(), vec![4242]);
+    }
+
+    #[test]
+    fn ban_list_names_binaries_and_uids() {
+        let list = BanList::parse(
+            "# banned after the incident\n\nbinary:hoodini-server\nuid: 1001\nuid:nobody\n42\n",
+        );
+        assert!(list
+            .binaries
+            .contains(&BinaryName::from("hoodini-server".to_string())));
+        assert_eq!(list.binaries.len(), 1);
+        assert_eq!(list.uids, HashSet::from([1001]));
+    }
+
+    #[test]
+    fn ban_list_bans_unix_peers_by_uid() {
+        let list = BanList::parse("uid:1001");
+        assert!(list.bans(Some(1001), None));
+        assert!(!list.bans(Some(1000), None));
+        //TCP peers carry no uid
+        assert!(!list.bans(None, None));
+        assert!(!BanList::default().bans(Some(1001), None));
+    }
+}
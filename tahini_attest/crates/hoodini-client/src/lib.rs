@@ -1,66 +1,226 @@
 use std::{
-    fs::File,
-    io::Read,
-    net::{IpAddr, Ipv4Addr},
-    path::Path,
-    u128,
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
-use aws_lc_rs::{
-    aead::{AES_256_GCM, RandomizedNonceKey},
-    signature::UnparsedPublicKey,
-};
+use aws_lc_rs::signature::UnparsedPublicKey;
 use serde::Deserialize;
-use tarpc::{context, tokio_serde::formats::Json};
+use tarpc::context;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_util::codec::LengthDelimitedCodec;
 use toml::{Table, Value};
 
 pub use hoodini_core::{
-    certificate::{CertificateLoader, CertificateProvider},
-    service::{AttestationServiceClient, compute_local_share, derive_key_from_shares},
+    appraisal::{Appraisal, AppraisalConfig, AppraisalPolicy, PredicateRule},
+    canonical::SigningPayload,
+    codec::{self, Codec, CodecError},
+    cose::{self, CertificateClaims},
+    certificate::{
+        CertificateLoader, CertificateProvider, IssuerKeysConfig, PinnedCertificateProvider,
+        TransparencyConfig,
+    },
+    keys,
+    manifest::PolicyManifest,
+    record::{self, RecordError, RecordLimits, RecordOpener, RecordSealer, client_records},
+    reload::FileWatcher,
+    replay::{DEFAULT_REPLAY_CACHE_SIZE, ReplayCache},
+    service::{AttestationServiceClient, compute_local_share},
+    session::{
+        CallerCredential, CallerProof, ClientSession, ConnectionKeys, RekeyRequest, SessionKeys,
+        SessionTranscript, derive_session_keys,
+    },
+    tls::{self, ServerName},
+    transport::SealedTransport,
     types::{
-        AttestErrors, AttestResult, ClientId, DynamicAttestationData, ServiceName,
-        TahiniCertificate,
+        AttestErrors, AttestResult, BinHash, BinaryName, ClientId, DynamicAttestationData,
+        DynamicAttestationReport, PolicyHash, ServiceName, Signature, TahiniCertificate,
     },
+    verify::{self, SignatureVerifier, VerifyError},
 };
 
+///Clones share the same certificate handler, so a reload is seen by all of them.
+#[derive(Clone)]
 pub struct DynamicAttestationVerifier {
-    //Certificate handler
-    certificate_handler: CertificateLoader,
+    //Certificate handler, holding local certificates and those fetched for pinned services.
+    //Replaced as a whole on reload
+    certificate_handler: Arc<RwLock<Arc<PinnedCertificateProvider>>>,
     //Allowed public keys for runtime attestation verification
     allowed_keys: UnparsedPublicKey<Vec<u8>>,
     //Config for connecting to sidecar
-    sidecar_host: SidecarHost,
+    sidecar_endpoint: SidecarEndpoint,
+    //Codec asked for when connecting to the sidecar
+    sidecar_codec: Codec,
+    //Config the certificate handler is reloaded from
+    config_path: PathBuf,
+    //When set, decides which verified reports are accepted, instead of requiring the remote
+    //certificate to be the locally held one
+    appraisal: Option<Arc<dyn AppraisalPolicy>>,
+    //Signatures of the last accepted reports, shared by clones
+    replay_cache: Arc<Mutex<ReplayCache>>,
+    //Identity presented to the sidecar when the client is itself a binary launched by it
+    caller_credential: Option<CallerCredential>,
 }
 
-struct SidecarHost {
-    hostname: IpAddr,
-    port: u16,
+#[derive(Clone)]
+enum SidecarEndpoint {
+    Tcp {
+        hostname: String,
+        port: u16,
+        //Plain TCP when absent, only allowed to a sidecar on the same host unless insecure is set
+        tls: Option<SidecarTls>,
+    },
+    //Sidecar on the same host, which identifies the client by its process credentials
+    Unix(PathBuf),
+}
+
+#[derive(Clone)]
+struct SidecarTls {
+    connector: tls::TlsConnector,
+    server_name: ServerName<'static>,
 }
 
 impl DynamicAttestationVerifier {
     pub fn from_config(config_path: &Path) -> AttestResult<Self> {
-        let contents =
-            std::fs::read_to_string(config_path).map_err(|e| AttestErrors::IoError(e))?;
-        let data: Config =
-            toml::from_str(&contents).map_err(|e| AttestErrors::ConfigError(e.to_string()))?;
-        data.into_verifier()
+        Config::read(config_path)?.into_verifier(config_path)
+    }
+
+    ///Same as from_config, then fetches the certificates of pinned services from the sidecar.
+    pub async fn load(config_path: &Path) -> AttestResult<Self> {
+        let verifier = Self::from_config(config_path)?;
+        if !verifier.certificates().pins().is_empty() {
+            verifier.reload_certificates().await?;
+        }
+        Ok(verifier)
+    }
+
+    ///Appraises reports with the given policy, replacing the one from the config if any.
+    ///Remote certificates then only need to verify against the issuer keys, not to be the locally
+    ///held ones.
+    pub fn with_appraisal_policy(mut self, policy: impl AppraisalPolicy + 'static) -> Self {
+        self.appraisal = Some(Arc::new(policy));
+        self
+    }
+
+    ///Presents the credential with every attestation request, so that the servers called learn
+    ///which attested binary is calling. For clients running in a binary launched by the sidecar,
+    ///with the credential from `hoodini_server::caller_credential`.
+    pub fn with_caller_credential(mut self, credential: CallerCredential) -> Self {
+        self.caller_credential = Some(credential);
+        self
+    }
+
+    fn caller_proof(
+        &self,
+        service_name: &ServiceName,
+        nonce: u128,
+        key_share: &[u8],
+    ) -> Option<CallerProof> {
+        self.caller_credential
+            .as_ref()
+            .map(|credential| credential.prove(service_name, nonce, key_share))
+    }
+
+    //Current certificate handler. Stays consistent for the caller even if a reload happens
+    fn certificates(&self) -> Arc<PinnedCertificateProvider> {
+        self.certificate_handler
+            .read()
+            .expect("Certificate handler lock poisoned")
+            .clone()
     }
 
-    ///Verify remote certificate against the one from disk
-    pub fn verify_certificate(&self, remote_certificate: &TahiniCertificate) -> bool {
-        let key = self.certificate_handler.get_key();
-        if key.is_none() {
-            return false;
+    ///Rebuilds the certificate handler from the config and the files it refers to, fetches the
+    ///certificates of pinned services from the sidecar, then swaps it in.
+    ///The current handler is kept if any step fails.
+    pub async fn reload_certificates(&self) -> AttestResult<()> {
+        let mut certificate_handler =
+            Config::read(&self.config_path)?.into_certificate_handler(&self.config_path)?;
+        if !certificate_handler.pins().is_empty() {
+            let client = self.connect().await?;
+            certificate_handler.fetch_pinned(&client).await?;
         }
-        let local_certificate = self
+        *self
             .certificate_handler
-            .get_certificate(&remote_certificate.service_name);
-        if local_certificate.is_none() {
-            return false;
+            .write()
+            .expect("Certificate handler lock poisoned") = Arc::new(certificate_handler);
+        Ok(())
+    }
+
+    ///Spawns a task reloading certificates whenever the config, or a file it refers to, changes.
+    ///The outcome of each reload is passed to `report`. A failed reload keeps the previous
+    ///certificates, see reload_certificates.
+    pub fn watch_certificates<F>(
+        &self,
+        interval: Duration,
+        mut report: F,
+    ) -> tokio::task::JoinHandle<()>
+    where
+        F: FnMut(AttestResult<()>) + Send + 'static,
+    {
+        let verifier = self.clone();
+        tokio::spawn(async move {
+            let mut watcher = FileWatcher::new(verifier.certificates().loader().sources());
+            loop {
+                tokio::time::sleep(interval).await;
+                if !watcher.changed() {
+                    continue;
+                }
+                let reloaded = verifier.reload_certificates().await;
+                if reloaded.is_ok() {
+                    watcher.watch(verifier.certificates().loader().sources());
+                }
+                report(reloaded);
+            }
+        })
+    }
+
+    ///Verify the remote certificate signature against the issuer key,
+    ///then check it against the one from disk for the same binary
+    pub fn verify_certificate(&self, remote_certificate: &TahiniCertificate) -> AttestResult<()> {
+        let certificates = self.certificates();
+        certificates
+            .loader()
+            .verify_certificate(remote_certificate)?;
+        let local_certificate = certificates
+            .loader()
+            .get_binary_certificate(&remote_certificate.binary_name())
+            .ok_or(AttestErrors::UnknownCertificateError)?;
+        if local_certificate != remote_certificate {
+            return Err(AttestErrors::CertificateMismatchError);
         }
+        Ok(())
+    }
 
-        let local_certificate = local_certificate.unwrap();
-        local_certificate == remote_certificate
+    ///Checks that the binary certified for the service implements `policy`, e.g.
+    ///`ConsentPolicy`, with the expected hash. Only v3 and later certificates carry
+    ///per-policy hashes.
+    pub fn check_policy(
+        &self,
+        service_name: &ServiceName,
+        policy: &str,
+        expected_hash: &str,
+    ) -> AttestResult<()> {
+        let certificates = self.certificates();
+        let certificate = certificates
+            .get_certificate(service_name)
+            .ok_or(AttestErrors::UnknownCertificateError)?;
+        Ok(verify::verify_policy(certificate, policy, expected_hash)?)
+    }
+
+    //Records the signature of a verified report, failing if it was already accepted
+    fn check_replay(&self, signature: &[u8]) -> AttestResult<()> {
+        let fresh = self
+            .replay_cache
+            .lock()
+            .expect("Replay cache lock poisoned")
+            .insert(signature);
+        if !fresh {
+            return Err(AttestErrors::ReplayedReportError);
+        }
+        Ok(())
     }
 
     ///Main function for client-side verification.
@@ -68,129 +228,490 @@ impl DynamicAttestationVerifier {
     ///In order:
     ///Generate local_key_share
     ///Connect to sidecar to get (client_id, server_key_share, attestation_report)
-    ///Verify attestation
-    ///Finish key agreement protocol
-    ///Return client_id and key to the Tahini tarpc client handler 
+    ///Verify attestation: certificate, then service name, nonce, key share, binary hash and
+    ///signature of the report, each with its own error, and that the report was not seen before
+    ///Finish key agreement protocol, bound to the attestation transcript
+    ///Return client_id and both session keys to the Tahini tarpc client handler, which wraps them
+    ///in a `ClientSession` and seals the messages of each connection with the keys from
+    ///`ClientSession::next_connection`, through `client_records` or `SealedTransport::client`
     pub async fn verify_binary(
         &self,
         service_name: ServiceName,
-    ) -> AttestResult<(ClientId, RandomizedNonceKey)> {
-        let mut dest = [0u8; 16];
-        if aws_lc_rs::rand::fill(&mut dest).is_err() {
-            return Err(AttestErrors::CryptoError);
-        }
-        let nonce = u128::from_be_bytes(dest);
+    ) -> AttestResult<(ClientId, SessionKeys)> {
+        self.verify_binary_appraised(service_name)
+            .await
+            .map(|(client_id, key, _)| (client_id, key))
+    }
 
-        let bin_name = self.certificate_handler.get_reverse_mapping(&service_name);
-        if bin_name.is_none() {
-            return Err(AttestErrors::ServiceMismatchError);
-        }
+    ///Same as verify_binary, also returning which appraisal rules the report passed.
+    ///Without an appraisal policy, the remote certificate must be the locally held one and the
+    ///appraisal is empty. With one, it only has to verify against the issuer keys and the pins,
+    ///and the report is rejected with AppraisalRejectedError if any rule fails, or if no rule
+    ///applies to it.
+    pub async fn verify_binary_appraised(
+        &self,
+        service_name: ServiceName,
+    ) -> AttestResult<(ClientId, SessionKeys, Appraisal)> {
+        let nonce = fresh_nonce()?;
 
-        let bin_name = bin_name.expect("Binary reverse lookup should exist");
+        let certificates = self.certificates();
+        //Without an appraisal policy, the service must be hosted by a locally certified binary
+        if self.appraisal.is_none() && certificates.get_certificate(&service_name).is_none() {
+            return Err(AttestErrors::UnknownServiceError(service_name));
+        }
 
         let (sk, pkey) = compute_local_share();
-        let host = (self.sidecar_host.hostname, self.sidecar_host.port);
-        let stream = tarpc::serde_transport::tcp::connect(host, Json::default);
-        let client = AttestationServiceClient::new(Default::default(), stream.await.unwrap());
-        let report = client
-            .spawn()
+        let report = self
+            .connect()
+            .await?
             .attest_binary(
                 context::current(),
-                bin_name.clone(),
+                service_name.clone(),
                 nonce,
-                pkey.as_ref().to_vec(),
+                pkey.as_ref().to_vec().into(),
+                self.caller_proof(&service_name, nonce, pkey.as_ref()),
             )
             .await
-            .map_err(|e| AttestErrors::NetworkError(e))?;
-        let certificate = report.certificate;
-        if self.verify_certificate(&certificate) == false {
-            println!("Certificate is not verified");
-            return Err(AttestErrors::InvalidAttestation);
+            .map_err(AttestErrors::NetworkError)?
+            .map_err(|failure| AttestErrors::from_failure(failure, service_name.clone()))?;
+        let appraisal = self.check_report(&report, &service_name, nonce, pkey.as_ref())?;
+
+        let transcript = SessionTranscript::from_data(&report.attestation_data())
+            .map_err(|_| AttestErrors::MalformedCertificateError)?;
+        let session_keys = derive_session_keys(sk, &report.server_key_share, &transcript)
+            .map_err(|_| AttestErrors::CryptoError)?;
+        Ok((report.client_id, session_keys, appraisal))
+    }
+
+    ///Replaces the keys of a live session before it expires or runs out of budget. The sidecar
+    ///attests again for the same client id, once the request proves the client holds the current
+    ///keys. The new report is verified as in verify_binary_appraised.
+    pub async fn rekey(
+        &self,
+        service_name: ServiceName,
+        client_id: &ClientId,
+        keys: &SessionKeys,
+    ) -> AttestResult<SessionKeys> {
+        let nonce = fresh_nonce()?;
+        let (sk, pkey) = compute_local_share();
+        let mut request = RekeyRequest {
+            client_id: client_id.clone(),
+            service_name: service_name.clone(),
+            nonce,
+            key_share: pkey.as_ref().to_vec(),
+            proof: Vec::new(),
+        };
+        request.proof = keys
+            .rekey_proof(&request)
+            .map_err(|_| AttestErrors::CryptoError)?;
+        let report = self
+            .connect()
+            .await?
+            .rekey(context::current(), request)
+            .await
+            .map_err(AttestErrors::NetworkError)?
+            .map_err(|failure| AttestErrors::from_failure(failure, service_name.clone()))?;
+        self.check_report(&report, &service_name, nonce, pkey.as_ref())?;
+        if report.client_id != *client_id {
+            return Err(AttestErrors::ClientIdMismatchError);
         }
-        if report.current_bin_hash != certificate.binary_hash {
-            println!("Mismatch of hashes");
-            return Err(AttestErrors::InvalidAttestation);
+
+        let transcript = SessionTranscript::from_data(&report.attestation_data())
+            .map_err(|_| AttestErrors::MalformedCertificateError)?;
+        derive_session_keys(sk, &report.server_key_share, &transcript)
+            .map_err(|_| AttestErrors::CryptoError)
+    }
+
+    //Certificate, report, appraisal and replay checks shared by attestation and rekeying
+    fn check_report(
+        &self,
+        report: &DynamicAttestationReport,
+        service_name: &ServiceName,
+        nonce: u128,
+        key_share: &[u8],
+    ) -> AttestResult<Appraisal> {
+        let certificates = self.certificates();
+        let appraisal = match &self.appraisal {
+            None => {
+                self.verify_certificate(&report.certificate)?;
+                verify::verify_report(
+                    report,
+                    service_name,
+                    nonce,
+                    key_share,
+                    &self.allowed_keys,
+                )?;
+                Appraisal::new()
+            }
+            Some(policy) => {
+                certificates.loader().verify_certificate(&report.certificate)?;
+                if certificates.pins().contains_key(service_name) {
+                    certificates.check_pin(&report.certificate, service_name)?;
+                }
+                verify::verify_report(
+                    report,
+                    service_name,
+                    nonce,
+                    key_share,
+                    &self.allowed_keys,
+                )?;
+                appraise(policy.as_ref(), report)?
+            }
+        };
+        //verify_report already checked the signature is valid hex
+        let signature =
+            hex::decode(&report.signature.0).map_err(|_| AttestErrors::AttestationSignatureError)?;
+        self.check_replay(&signature)?;
+        Ok(appraisal)
+    }
+
+    ///Same as verify_binary, with the attestation report and the certificate it embeds
+    ///exchanged as COSE_Sign1 envelopes instead of JSON. The certificate must be the locally held
+    ///one even with an appraisal policy, and the report must then also pass the policy.
+    pub async fn verify_binary_cose(
+        &self,
+        service_name: ServiceName,
+    ) -> AttestResult<(ClientId, SessionKeys)> {
+        let nonce = fresh_nonce()?;
+
+        let certificates = self.certificates();
+        if certificates.get_certificate(&service_name).is_none() {
+            return Err(AttestErrors::UnknownServiceError(service_name));
+        }
+
+        let (sk, pkey) = compute_local_share();
+        let envelope = self
+            .connect()
+            .await?
+            .attest_binary_cose(
+                context::current(),
+                service_name.clone(),
+                nonce,
+                pkey.as_ref().to_vec().into(),
+                self.caller_proof(&service_name, nonce, pkey.as_ref()),
+            )
+            .await
+            .map_err(AttestErrors::NetworkError)?
+            .map_err(|failure| AttestErrors::from_failure(failure, service_name.clone()))?;
+        let report = cose::open_report(&envelope, &self.allowed_keys).map_err(|e| match e {
+            cose::CoseEnvelopeError::SignatureError => AttestErrors::AttestationSignatureError,
+            e => AttestErrors::CoseError(e),
+        })?;
+
+        //The embedded certificate must be issuer-signed and match the locally held one
+        let certificate_claims = certificates
+            .loader()
+            .open_certificate_envelope(&report.certificate)?;
+        let local_certificate = certificates
+            .loader()
+            .get_binary_certificate(&BinaryName::from(certificate_claims.service_name.clone()))
+            .ok_or(AttestErrors::UnknownCertificateError)?;
+        let local_claims =
+            CertificateClaims::try_from(local_certificate).map_err(AttestErrors::CoseError)?;
+        if local_claims != certificate_claims {
+            return Err(AttestErrors::CertificateMismatchError);
         }
+        certificates
+            .loader()
+            .verify_certificate(local_certificate)?;
 
-        let client_id = report.client_id;
-        let server_key_share = report.server_key_share.clone();
-        let usable_key = derive_key_from_shares(sk, server_key_share);
-        let aes_key = RandomizedNonceKey::new(&AES_256_GCM, &usable_key)
-            .expect("Couldn't generate the AES session key client side");
+        if report.service_name != service_name.0 || !local_certificate.hosts(&service_name) {
+            return Err(AttestErrors::ServiceMismatchError);
+        }
+        if report.nonce != nonce.to_be_bytes() {
+            return Err(AttestErrors::NonceMismatchError);
+        }
+        if report.client_key_share != pkey.as_ref() {
+            return Err(AttestErrors::KeyShareMismatchError);
+        }
+        if report.current_bin_hash != certificate_claims.binary_hash {
+            return Err(AttestErrors::BinaryHashMismatchError {
+                expected: BinHash(hex::encode(&certificate_claims.binary_hash)),
+                reported: BinHash(hex::encode(&report.current_bin_hash)),
+            });
+        }
+        let signature = cose::envelope_signature(&envelope).map_err(AttestErrors::CoseError)?;
+        let client_id = ClientId::from(report.client_id as usize);
+        if let Some(policy) = &self.appraisal {
+            //Policies appraise the JSON form of the report, over the certificate checked above
+            let report = DynamicAttestationReport {
+                certificate: local_certificate.clone(),
+                nonce,
+                service_name: service_name.clone(),
+                current_bin_hash: BinHash(hex::encode(&report.current_bin_hash)),
+                client_key_share: report.client_key_share.clone(),
+                server_key_share: report.server_key_share.clone(),
+                client_id: client_id.clone(),
+                signature: Signature(hex::encode(&signature)),
+            };
+            appraise(policy.as_ref(), &report)?;
+        }
+        self.check_replay(&signature)?;
 
-        let attestation_data = DynamicAttestationData {
-            cert: &certificate,
+        //Same transcript as the sidecar's, which used the certificate the claims were checked against
+        let transcript = SessionTranscript {
+            certificate_id: local_certificate
+                .id()
+                .map_err(|_| AttestErrors::MalformedCertificateError)?,
+            service_name,
             nonce,
-            service_name: bin_name.clone(),
-            current_bin_hash: certificate.binary_hash.clone(),
             client_id: client_id.clone(),
+            client_key_share: report.client_key_share,
             server_key_share: report.server_key_share,
         };
+        let session_keys = derive_session_keys(sk, &transcript.server_key_share, &transcript)
+            .map_err(|_| AttestErrors::CryptoError)?;
+        Ok((client_id, session_keys))
+    }
 
-        let sign_data_u8 =
-            serde_json::to_vec(&attestation_data).expect("Couldnt serialize attestation data");
+    async fn connect(&self) -> AttestResult<AttestationServiceClient> {
+        let (hostname, port, tls) = match &self.sidecar_endpoint {
+            SidecarEndpoint::Tcp {
+                hostname,
+                port,
+                tls,
+            } => (hostname, port, tls),
+            SidecarEndpoint::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .map_err(AttestErrors::TransportError)?;
+                return spawn_client(stream, self.sidecar_codec).await;
+            }
+        };
+        let stream = TcpStream::connect((hostname.as_str(), *port))
+            .await
+            .map_err(AttestErrors::TransportError)?;
+        match tls {
+            None => spawn_client(stream, self.sidecar_codec).await,
+            Some(sidecar_tls) => {
+                let stream = sidecar_tls
+                    .connector
+                    .connect(sidecar_tls.server_name.clone(), stream)
+                    .await
+                    .map_err(AttestErrors::TransportError)?;
+                spawn_client(stream, self.sidecar_codec).await
+            }
+        }
+    }
+}
 
-        self.allowed_keys
-            .verify(
-                &sign_data_u8,
-                &hex::decode(report.signature.0).expect("Non hex signature"),
-            )
-            .map(|_| {
-                println!("Signature was verified for bin{:?}", bin_name);
-                (client_id, aes_key)
-            }).map_err(|_| AttestErrors::InvalidAttestation)
+//Same framing as tarpc::serde_transport::tcp, over any stream, once the codec is agreed on
+async fn spawn_client<S>(mut stream: S, codec: Codec) -> AttestResult<AttestationServiceClient>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    codec::request_codec(&mut stream, codec)
+        .await
+        .map_err(|e| match e {
+            CodecError::IoError(e) => AttestErrors::TransportError(e),
+            e => AttestErrors::CodecError(e),
+        })?;
+    let framed = LengthDelimitedCodec::builder().new_framed(stream);
+    let transport = tarpc::serde_transport::new(framed, codec);
+    Ok(AttestationServiceClient::new(Default::default(), transport).spawn())
+}
+
+//Runs an appraisal policy over a verified report. The local certificate check is skipped when a
+//policy is set, so a policy that checked nothing rejects the report
+fn appraise(
+    policy: &dyn AppraisalPolicy,
+    report: &DynamicAttestationReport,
+) -> AttestResult<Appraisal> {
+    let mut appraisal = policy.appraise(report);
+    if appraisal.outcomes.is_empty() {
+        appraisal.record("appraisal", false, "no rule applies to the report");
+    }
+    if !appraisal.is_accepted() {
+        return Err(AttestErrors::AppraisalRejectedError(appraisal));
     }
+    Ok(appraisal)
+}
+
+fn fresh_nonce() -> AttestResult<u128> {
+    let mut dest = [0u8; 16];
+    if aws_lc_rs::rand::fill(&mut dest).is_err() {
+        return Err(AttestErrors::CryptoError);
+    }
+    Ok(u128::from_be_bytes(dest))
 }
 
 #[derive(Deserialize)]
 struct Config {
+    //Binary name to local certificate file. Not needed for services with a pinned policy hash
+    #[serde(default)]
     certificates: Table,
+    //Service name to expected policy hash. Certificates of pinned services are fetched from the
+    //sidecar by DynamicAttestationVerifier::load
+    #[serde(default)]
+    pins: HashMap<String, String>,
     keys: KeyConfig,
     sidecar: SidecarConfig,
-    service_mapping: Table,
+    //Authority certificates of intermediate keys, signed by a certificate key
+    #[serde(default)]
+    authorities: Vec<String>,
+    #[serde(default)]
+    revocation_lists: Vec<String>,
+    #[serde(default)]
+    allow_legacy_certificates: bool,
+    transparency: Option<TransparencyConfig>,
+    //Rules deciding which verified reports verify_binary accepts
+    appraisal: Option<AppraisalConfig>,
+    //Number of accepted report signatures remembered to detect replays. 0 disables the check
+    #[serde(default = "default_replay_cache_size")]
+    replay_cache_size: usize,
+}
+
+fn default_replay_cache_size() -> usize {
+    DEFAULT_REPLAY_CACHE_SIZE
 }
 
 #[derive(Deserialize)]
 struct KeyConfig {
-    certificate_key: String,
+    //Either a single key path or a list of issuer keys with ids and validity windows
+    #[serde(alias = "certificate_keys")]
+    certificate_key: IssuerKeysConfig,
     attestation_key: String,
 }
 
 #[derive(Deserialize)]
 #[allow(unused)]
 struct SidecarConfig {
+    //Hostname or IP address
+    #[serde(default = "default_sidecar_host")]
     host: String,
+    #[serde(default = "default_sidecar_port")]
     port: u16,
+    tls: Option<SidecarTlsConfig>,
+    //Allows plain TCP to a sidecar on another host. Reports stay signed, but the services asked
+    //for and the key shares are visible on the network
+    #[serde(default)]
+    insecure: bool,
+    //Unix socket of a sidecar on the same host. Used instead of host and port when set
+    socket: Option<String>,
+    //CBOR unless set to json, which is easier to inspect but several times larger
+    #[serde(default)]
+    codec: Codec,
+}
+
+fn default_sidecar_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_sidecar_port() -> u16 {
+    4000
+}
+
+#[derive(Deserialize)]
+struct SidecarTlsConfig {
+    //PEM certificate the sidecar must present, any other is refused
+    certificate: String,
+    //Name the sidecar certificate is requested under. Defaults to the host
+    server_name: Option<String>,
+}
+
+//Loopback addresses, and localhost, which is trusted to resolve to one: pointing it elsewhere
+//through /etc/hosts takes control of this host already. Other hostnames need TLS even if they
+//are local
+fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || unbracketed(host)
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+//Hosts may be written as in URLs, with IPv6 addresses in brackets, which resolvers don't expect
+fn unbracketed(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+impl SidecarConfig {
+    fn tcp_endpoint(&self) -> AttestResult<SidecarEndpoint> {
+        let tls = match &self.tls {
+            None if !self.insecure && !is_loopback_host(&self.host) => {
+                return Err(AttestErrors::ConfigError(format!(
+                    "sidecar {} is not on this host, configure [sidecar.tls] or set insecure = true",
+                    self.host
+                )))
+            }
+            None => None,
+            Some(tls_config) => {
+                let config = tls::pinned_client_config(Path::new(&tls_config.certificate))
+                    .map_err(|e| AttestErrors::ConfigError(e.to_string()))?;
+                let server_name = tls::server_name(
+                    tls_config
+                        .server_name
+                        .as_deref()
+                        .unwrap_or(unbracketed(&self.host)),
+                )
+                .map_err(|e| AttestErrors::ConfigError(e.to_string()))?;
+                Some(SidecarTls {
+                    connector: tls::TlsConnector::from(config),
+                    server_name,
+                })
+            }
+        };
+        Ok(SidecarEndpoint::Tcp {
+            hostname: unbracketed(&self.host).to_string(),
+            port: self.port,
+            tls,
+        })
+    }
 }
 
 impl Config {
-    fn into_verifier(self) -> AttestResult<DynamicAttestationVerifier> {
-        let mut loader = CertificateLoader::new();
+    fn read(config_path: &Path) -> AttestResult<Self> {
+        let contents = std::fs::read_to_string(config_path).map_err(AttestErrors::IoError)?;
+        toml::from_str(&contents).map_err(|e| AttestErrors::ConfigError(e.to_string()))
+    }
 
-        let path = Path::new(&self.keys.certificate_key);
-        loader.load_certificate_key(path)?;
+    fn into_verifier(mut self, config_path: &Path) -> AttestResult<DynamicAttestationVerifier> {
         let path = Path::new(&self.keys.attestation_key);
-        let mut file = File::open(path).map_err(|e| AttestErrors::IoError(e))?;
-        let mut pkey_bytes: Vec<u8> = Vec::new();
-        file.read_to_end(&mut pkey_bytes)
-            .map_err(|e| AttestErrors::IoError(e))?;
-        //Hacky: Last 32-bytes of DER format are key bytes. aws-lc-rs requires straight key
-        //material
-        let key_material = &pkey_bytes[pkey_bytes.len() - 32..];
-        let allowed_keys =
-            UnparsedPublicKey::new(&aws_lc_rs::signature::ED25519, key_material.to_vec());
-
-        for (bin_name, service_name) in self.service_mapping.into_iter() {
-            loader.register_bin_mapping(
-                ServiceName(bin_name),
-                ServiceName(service_name.as_str().unwrap().to_string()),
-            );
-        }
-        for (service_name, v) in self.certificates.into_iter() {
+        let allowed_keys = keys::load_public_key(path).map_err(AttestErrors::KeyError)?;
+        let sidecar_endpoint = match &self.sidecar.socket {
+            Some(socket) => SidecarEndpoint::Unix(PathBuf::from(socket)),
+            None => self.sidecar.tcp_endpoint()?,
+        };
+        let appraisal = self
+            .appraisal
+            .take()
+            .map(|config| Arc::new(config) as Arc<dyn AppraisalPolicy>);
+        let replay_cache = ReplayCache::new(self.replay_cache_size);
+        let sidecar_codec = self.sidecar.codec;
+        let certificate_handler = self.into_certificate_handler(config_path)?;
+        Ok(DynamicAttestationVerifier {
+            certificate_handler: Arc::new(RwLock::new(Arc::new(certificate_handler))),
+            allowed_keys,
+            sidecar_endpoint,
+            sidecar_codec,
+            config_path: config_path.to_path_buf(),
+            appraisal,
+            replay_cache: Arc::new(Mutex::new(replay_cache)),
+            caller_credential: None,
+        })
+    }
+
+    fn into_certificate_handler(self, config_path: &Path) -> AttestResult<PinnedCertificateProvider> {
+        let mut loader = CertificateLoader::new();
+        loader.add_source(config_path);
+        loader.allow_legacy_certificates(self.allow_legacy_certificates);
+
+        self.keys.certificate_key.load_into(&mut loader)?;
+        if let Some(transparency) = self.transparency {
+            transparency.load_into(&mut loader)?;
+        }
+        for authority_path in self.authorities.iter() {
+            loader.load_authority_certificate(Path::new(authority_path))?;
+        }
+        for list_path in self.revocation_lists.iter() {
+            loader.load_revocation_list(Path::new(list_path))?;
+        }
+        for (bin_name, v) in self.certificates.into_iter() {
             match v {
                 Value::String(certif_path) => {
                     let path = Path::new(&certif_path);
-                    loader.register_service(path, ServiceName(service_name))?;
+                    loader.register_binary(path, BinaryName::from(bin_name))?;
                 }
                 _ => {
                     return Err(AttestErrors::ConfigError(
@@ -199,13 +720,67 @@ impl Config {
                 }
             }
         }
-        Ok(DynamicAttestationVerifier {
-            certificate_handler: loader,
-            allowed_keys,
-            sidecar_host: SidecarHost {
-                hostname: IpAddr::V4(Ipv4Addr::LOCALHOST),
-                port: self.sidecar.port,
-            },
-        })
+        let pins = self
+            .pins
+            .into_iter()
+            .map(|(service_name, policy_hash)| (ServiceName(service_name), PolicyHash(policy_hash)))
+            .collect();
+        let certificate_handler = PinnedCertificateProvider::new(loader, pins);
+        //Local certificates must also match the pins of their services
+        for service_name in certificate_handler.pins().keys() {
+            if let Some(certificate) = certificate_handler.get_certificate(service_name) {
+                certificate_handler.check_pin(certificate, service_name)?;
+            }
+        }
+        Ok(certificate_handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sidecar_config(host: &str, insecure: bool) -> SidecarConfig {
+        SidecarConfig {
+            host: host.to_string(),
+            port: default_sidecar_port(),
+            tls: None,
+            insecure,
+            socket: None,
+            codec: Codec::default(),
+        }
+    }
+
+    #[test]
+    fn loopback_hosts_are_recognized() {
+        for host in ["localhost", "127.0.0.1", "127.1.2.3", "::1", "[::1]"] {
+            assert!(is_loopback_host(host), "{}", host);
+        }
+        let others = ["sidecar.internal", "10.0.0.4", "0.0.0.0", "::", "localhost.example.com", "[::1"];
+        for host in others {
+            assert!(!is_loopback_host(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn bracketed_hosts_are_connected_to_without_brackets() {
+        for (host, hostname) in [("[::1]", "::1"), ("::1", "::1"), ("127.0.0.1", "127.0.0.1")] {
+            match sidecar_config(host, false).tcp_endpoint() {
+                Ok(SidecarEndpoint::Tcp { hostname: connected, .. }) => {
+                    assert_eq!(connected, hostname)
+                }
+                _ => panic!("no TCP endpoint for {}", host),
+            }
+        }
+    }
+
+    #[test]
+    fn plain_tcp_to_other_hosts_needs_insecure() {
+        assert!(sidecar_config("127.0.0.1", false).tcp_endpoint().is_ok());
+        assert!(matches!(
+            sidecar_config("10.0.0.4", false).tcp_endpoint(),
+            Err(AttestErrors::ConfigError(_))
+        ));
+        assert!(sidecar_config("10.0.0.4", true).tcp_endpoint().is_ok());
     }
 }
@@ -0,0 +1,459 @@
+//!Client-side appraisal of attestation reports.
+//!
+//!Once a report and the certificate it embeds are verified, an appraisal policy decides whether
+//!what they attest to is acceptable. Policies are either written in Rust against the
+//!`AppraisalPolicy` trait, or declared in the client configuration with `AppraisalConfig`.
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{DynamicAttestationReport, KeyId, PolicyHash};
+
+///Decides whether an attestation report is acceptable.
+pub trait AppraisalPolicy: Send + Sync {
+    ///Evaluates every rule that applies to the report. The report signature and its certificate
+    ///are already verified.
+    fn appraise(&self, report: &DynamicAttestationReport) -> Appraisal;
+}
+
+///Result of a single rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleOutcome {
+    pub rule: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+///Outcome of every rule an appraisal policy evaluated, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Appraisal {
+    pub outcomes: Vec<RuleOutcome>,
+}
+
+impl Appraisal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, rule: impl Into<String>, passed: bool, detail: impl Into<String>) {
+        self.outcomes.push(RuleOutcome {
+            rule: rule.into(),
+            passed,
+            detail: detail.into(),
+        });
+    }
+
+    ///Appends the outcomes of another appraisal.
+    pub fn merge(&mut self, other: Appraisal) {
+        self.outcomes.extend(other.outcomes);
+    }
+
+    ///A report is accepted when no rule failed.
+    pub fn is_accepted(&self) -> bool {
+        self.outcomes.iter().all(|outcome| outcome.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &RuleOutcome> {
+        self.outcomes.iter().filter(|outcome| !outcome.passed)
+    }
+}
+
+impl fmt::Display for Appraisal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, outcome) in self.outcomes.iter().enumerate() {
+            if index > 0 {
+                write!(f, "; ")?;
+            }
+            let verdict = if outcome.passed { "passed" } else { "failed" };
+            write!(f, "{} {} ({})", outcome.rule, verdict, outcome.detail)?;
+        }
+        Ok(())
+    }
+}
+
+///Every policy of the list must accept the report.
+impl AppraisalPolicy for Vec<Box<dyn AppraisalPolicy>> {
+    fn appraise(&self, report: &DynamicAttestationReport) -> Appraisal {
+        let mut appraisal = Appraisal::new();
+        for policy in self.iter() {
+            appraisal.merge(policy.appraise(report));
+        }
+        appraisal
+    }
+}
+
+///Named rule accepting the reports an arbitrary predicate holds for.
+pub struct PredicateRule<F> {
+    name: String,
+    predicate: F,
+}
+
+impl<F> PredicateRule<F>
+where
+    F: Fn(&DynamicAttestationReport) -> bool + Send + Sync,
+{
+    pub fn new(name: impl Into<String>, predicate: F) -> Self {
+        Self {
+            name: name.into(),
+            predicate,
+        }
+    }
+}
+
+impl<F> AppraisalPolicy for PredicateRule<F>
+where
+    F: Fn(&DynamicAttestationReport) -> bool + Send + Sync,
+{
+    fn appraise(&self, report: &DynamicAttestationReport) -> Appraisal {
+        let mut appraisal = Appraisal::new();
+        let passed = (self.predicate)(report);
+        appraisal.record(self.name.clone(), passed, "predicate");
+        appraisal
+    }
+}
+
+///Declarative rules. Every rule that is set must pass. Unknown keys are refused, so that a
+///misspelled rule isn't silently left out.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AppraisalRules {
+    //Lowest accepted certificate version
+    pub min_version: Option<u8>,
+    //Issuer keys certificates may be signed by
+    pub allowed_issuers: Option<Vec<KeyId>>,
+    //Aggregate policy hashes the certificate may attest to
+    pub allowed_policy_hashes: Option<Vec<PolicyHash>>,
+    //Policies the binary must implement, whatever their hash
+    #[serde(default)]
+    pub required_policies: Vec<String>,
+    //Policies the binary must implement, with the hash of their implementation
+    #[serde(default)]
+    pub policy_impl_hashes: BTreeMap<String, String>,
+}
+
+impl AppraisalRules {
+    //Rule names are prefixed with the scope they were declared in
+    fn appraise_into(
+        &self,
+        scope: &str,
+        report: &DynamicAttestationReport,
+        appraisal: &mut Appraisal,
+    ) {
+        let certificate = &report.certificate;
+        if let Some(min_version) = self.min_version {
+            appraisal.record(
+                format!("{}.min_version", scope),
+                certificate.version.0 >= min_version,
+                format!("certificate is {}, at least v{} required", certificate.version, min_version),
+            );
+        }
+        if let Some(issuers) = &self.allowed_issuers {
+            let issuer = certificate.issuance.as_ref().map(|issuance| &issuance.issuer);
+            appraisal.record(
+                format!("{}.allowed_issuers", scope),
+                issuer.is_some_and(|issuer| issuers.contains(issuer)),
+                match issuer {
+                    Some(issuer) => format!("issued by {}", issuer),
+                    None => "certificate names no issuer".to_string(),
+                },
+            );
+        }
+        if let Some(hashes) = &self.allowed_policy_hashes {
+            appraisal.record(
+                format!("{}.allowed_policy_hashes", scope),
+                hashes.contains(&certificate.policy_hash),
+                format!("policy hash {}", certificate.policy_hash.0),
+            );
+        }
+        let manifest = certificate.policy_manifest.as_ref();
+        for policy in self.required_policies.iter() {
+            let found = manifest.and_then(|manifest| manifest.policy_hash(policy));
+            appraisal.record(
+                format!("{}.required_policies.{}", scope, policy),
+                found.is_some(),
+                implementation_detail(manifest.is_some(), found),
+            );
+        }
+        for (policy, expected) in self.policy_impl_hashes.iter() {
+            let found = manifest.and_then(|manifest| manifest.policy_hash(policy));
+            appraisal.record(
+                format!("{}.policy_impl_hashes.{}", scope, policy),
+                found == Some(expected.as_str()),
+                implementation_detail(manifest.is_some(), found),
+            );
+        }
+    }
+}
+
+fn implementation_detail(has_manifest: bool, found: Option<&str>) -> String {
+    match (has_manifest, found) {
+        (false, _) => "certificate carries no policy manifest".to_string(),
+        (true, None) => "not implemented".to_string(),
+        (true, Some(hash)) => format!("implemented with hash {}", hash),
+    }
+}
+
+///Appraisal section of a client configuration. Top-level rules apply to every service, and
+///rules under `services.<name>` only to reports for that service, on top of the top-level ones.
+///A report no rule applies to is rejected.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(from = "AppraisalConfigFields")]
+pub struct AppraisalConfig {
+    #[serde(flatten)]
+    pub rules: AppraisalRules,
+    #[serde(default)]
+    pub services: BTreeMap<String, AppraisalRules>,
+}
+
+//Flattened fields can't refuse unknown keys, so the top-level rules are spelled out here
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AppraisalConfigFields {
+    min_version: Option<u8>,
+    allowed_issuers: Option<Vec<KeyId>>,
+    allowed_policy_hashes: Option<Vec<PolicyHash>>,
+    #[serde(default)]
+    required_policies: Vec<String>,
+    #[serde(default)]
+    policy_impl_hashes: BTreeMap<String, String>,
+    #[serde(default)]
+    services: BTreeMap<String, AppraisalRules>,
+}
+
+impl From<AppraisalConfigFields> for AppraisalConfig {
+    fn from(fields: AppraisalConfigFields) -> Self {
+        Self {
+            rules: AppraisalRules {
+                min_version: fields.min_version,
+                allowed_issuers: fields.allowed_issuers,
+                allowed_policy_hashes: fields.allowed_policy_hashes,
+                required_policies: fields.required_policies,
+                policy_impl_hashes: fields.policy_impl_hashes,
+            },
+            services: fields.services,
+        }
+    }
+}
+
+impl AppraisalPolicy for AppraisalConfig {
+    fn appraise(&self, report: &DynamicAttestationReport) -> Appraisal {
+        let mut appraisal = Appraisal::new();
+        self.rules.appraise_into("appraisal", report, &mut appraisal);
+        if let Some(rules) = self.services.get(&report.service_name.0) {
+            let scope = format!("appraisal.services.{}", report.service_name);
+            rules.appraise_into(&scope, report, &mut appraisal);
+        }
+        if appraisal.outcomes.is_empty() {
+            appraisal.record(
+                "appraisal",
+                false,
+                format!("no rule applies to service {}", report.service_name),
+            );
+        }
+        appraisal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::manifest::PolicyManifest;
+    use crate::types::{
+        BinHash, CertificateVersion, ClientId, IssuanceInfo, ServiceName, Signature,
+        TahiniCertificate, ToolchainInfo,
+    };
+
+    //Report for hoodini-server, from a v3 certificate issued by "issuer-a"
+    fn report() -> DynamicAttestationReport {
+        let certificate = TahiniCertificate {
+            version: CertificateVersion::V3,
+            service_name: ServiceName("hoodini-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: Some(IssuanceInfo {
+                issuer: KeyId("issuer-a".to_string()),
+                issued_at: 1_700_000_000,
+                expires_at: 1_800_000_000,
+                toolchain: ToolchainInfo {
+                    generator: "certificate_generation 0.1.0".to_string(),
+                    rustc: "rustc 1.87.0".to_string(),
+                },
+            }),
+            policy_manifest: Some(PolicyManifest {
+                dependency_hashes: BTreeMap::new(),
+                local_summary_hash: "bb".repeat(32),
+                local_impls_hashes: BTreeMap::from([(
+                    "<impl Policy for ConsentPolicy>".to_string(),
+                    "cc".repeat(32),
+                )]),
+            }),
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        };
+        DynamicAttestationReport {
+            certificate,
+            nonce: 1,
+            service_name: ServiceName("hoodini-server".to_string()),
+            current_bin_hash: BinHash("02".repeat(32)),
+            client_key_share: vec![3; 32],
+            server_key_share: vec![4; 32],
+            client_id: ClientId::from(1),
+            signature: Signature(String::new()),
+        }
+    }
+
+    fn config(rules: AppraisalRules) -> AppraisalConfig {
+        AppraisalConfig {
+            rules,
+            services: BTreeMap::new(),
+        }
+    }
+
+    //Names of the failed rules
+    fn failures(config: &AppraisalConfig) -> Vec<String> {
+        config
+            .appraise(&report())
+            .failures()
+            .map(|outcome| outcome.rule.clone())
+            .collect()
+    }
+
+    #[test]
+    fn min_version_is_checked() {
+        let accepting = config(AppraisalRules {
+            min_version: Some(3),
+            ..Default::default()
+        });
+        assert!(accepting.appraise(&report()).is_accepted());
+        let rejecting = config(AppraisalRules {
+            min_version: Some(4),
+            ..Default::default()
+        });
+        assert_eq!(failures(&rejecting), vec!["appraisal.min_version"]);
+    }
+
+    #[test]
+    fn allowed_issuers_are_checked() {
+        let accepting = config(AppraisalRules {
+            allowed_issuers: Some(vec![KeyId("issuer-b".to_string()), KeyId("issuer-a".to_string())]),
+            ..Default::default()
+        });
+        assert!(accepting.appraise(&report()).is_accepted());
+        let rejecting = config(AppraisalRules {
+            allowed_issuers: Some(vec![KeyId("issuer-b".to_string())]),
+            ..Default::default()
+        });
+        assert_eq!(failures(&rejecting), vec!["appraisal.allowed_issuers"]);
+
+        //Legacy certificates name no issuer
+        let mut legacy = report();
+        legacy.certificate.issuance = None;
+        assert!(!accepting.appraise(&legacy).is_accepted());
+    }
+
+    #[test]
+    fn allowed_policy_hashes_are_checked() {
+        let accepting = config(AppraisalRules {
+            allowed_policy_hashes: Some(vec![PolicyHash("01".repeat(32))]),
+            ..Default::default()
+        });
+        assert!(accepting.appraise(&report()).is_accepted());
+        let rejecting = config(AppraisalRules {
+            allowed_policy_hashes: Some(vec![PolicyHash("ff".repeat(32))]),
+            ..Default::default()
+        });
+        assert_eq!(failures(&rejecting), vec!["appraisal.allowed_policy_hashes"]);
+    }
+
+    #[test]
+    fn required_policies_are_checked() {
+        let rules = config(AppraisalRules {
+            required_policies: vec!["ConsentPolicy".to_string(), "RetentionPolicy".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(failures(&rules), vec!["appraisal.required_policies.RetentionPolicy"]);
+
+        let mut without_manifest = report();
+        without_manifest.certificate.policy_manifest = None;
+        let appraisal = rules.appraise(&without_manifest);
+        assert_eq!(appraisal.failures().count(), 2);
+        assert!(appraisal
+            .failures()
+            .all(|outcome| outcome.detail == "certificate carries no policy manifest"));
+    }
+
+    #[test]
+    fn policy_impl_hashes_are_checked() {
+        let accepting = config(AppraisalRules {
+            policy_impl_hashes: BTreeMap::from([("ConsentPolicy".to_string(), "cc".repeat(32))]),
+            ..Default::default()
+        });
+        assert!(accepting.appraise(&report()).is_accepted());
+        let rejecting = config(AppraisalRules {
+            policy_impl_hashes: BTreeMap::from([("ConsentPolicy".to_string(), "dd".repeat(32))]),
+            ..Default::default()
+        });
+        assert_eq!(failures(&rejecting), vec!["appraisal.policy_impl_hashes.ConsentPolicy"]);
+    }
+
+    #[test]
+    fn service_rules_only_apply_to_their_service() {
+        let strict = AppraisalRules {
+            min_version: Some(4),
+            ..Default::default()
+        };
+        let mut config = config(AppraisalRules {
+            min_version: Some(2),
+            ..Default::default()
+        });
+        config.services.insert("hoodini-admin".to_string(), strict.clone());
+        let appraisal = config.appraise(&report());
+        assert!(appraisal.is_accepted());
+        assert_eq!(appraisal.outcomes.len(), 1);
+
+        //On top of the top-level rules
+        config.services.insert("hoodini-server".to_string(), strict);
+        assert_eq!(failures(&config), vec!["appraisal.services.hoodini-server.min_version"]);
+    }
+
+    #[test]
+    fn reports_no_rule_applies_to_are_rejected() {
+        assert_eq!(failures(&AppraisalConfig::default()), vec!["appraisal"]);
+
+        let mut other_service = AppraisalConfig::default();
+        other_service.services.insert(
+            "hoodini-admin".to_string(),
+            AppraisalRules {
+                min_version: Some(2),
+                ..Default::default()
+            },
+        );
+        assert_eq!(failures(&other_service), vec!["appraisal"]);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        let config: AppraisalConfig = serde_json::from_str(
+            r#"{"min_version": 3, "services": {"hoodini-server": {"required_policies": ["ConsentPolicy"]}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.rules.min_version, Some(3));
+        assert_eq!(config.services["hoodini-server"].required_policies, vec!["ConsentPolicy"]);
+
+        assert!(serde_json::from_str::<AppraisalConfig>(r#"{"min_verison": 3}"#).is_err());
+        assert!(serde_json::from_str::<AppraisalConfig>(
+            r#"{"services": {"hoodini-server": {"min_verison": 3}}}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use alloc::{string::String, vec::Vec};
+#[cfg(feature="std")]
+use aws_lc_rs::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
+use crate::types::{KeyId, ServiceName, Signature};
+
+///Certificate of an intermediate ("build authority") key, signed by an offline root key.
+///The intermediate key then signs TahiniCertificates within its scope and validity window.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuthorityCertificate {
+    //Id that TahiniCertificates signed by this authority name as their issuer
+    pub key_id: KeyId,
+    //Raw Ed25519 public key, in hex
+    pub public_key: String,
+    //Root key that signed this certificate
+    pub issuer: KeyId,
+    //Seconds since UNIX epoch
+    pub not_before: u64,
+    //Seconds since UNIX epoch
+    pub not_after: u64,
+    //Services this authority may certify. None allows every service
+    #[serde(default)]
+    pub allowed_services: Option<Vec<ServiceName>>,
+    pub signature: Signature,
+}
+
+impl AuthorityCertificate {
+    #[cfg(feature="std")]
+    ///Builds and signs an authority certificate with the root key.
+    pub fn new(
+        key_id: KeyId,
+        public_key: &[u8],
+        issuer: KeyId,
+        not_before: u64,
+        not_after: u64,
+        allowed_services: Option<Vec<ServiceName>>,
+        root_key: &Ed25519KeyPair,
+    ) -> Self {
+        let mut certificate = Self {
+            key_id,
+            public_key: hex::encode(public_key),
+            issuer,
+            not_before,
+            not_after,
+            allowed_services,
+            signature: Signature(String::new()),
+        };
+        certificate.signature = root_key.sign(&certificate.signing_payload()).into();
+        certificate
+    }
+}
+
+impl SigningPayload for AuthorityCertificate {
+    const DOMAIN: &'static [u8] = b"tahini-authority-certificate-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(&self.key_id.0)
+            .str(&self.public_key)
+            .str(&self.issuer.0)
+            .u64(self.not_before)
+            .u64(self.not_after);
+        match &self.allowed_services {
+            None => {
+                encoder.u8(0);
+            }
+            Some(services) => {
+                encoder.u8(1).u32(services.len() as u32);
+                for service in services.iter() {
+                    encoder.str(&service.0);
+                }
+            }
+        }
+    }
+}
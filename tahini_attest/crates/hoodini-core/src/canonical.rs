@@ -0,0 +1,74 @@
+//!Deterministic, domain-separated encoding of every structure Tahini signs.
+//!
+//!Signers and verifiers must agree on the signed bytes byte-for-byte, so they never depend on
+//!serde field order or on a serialization format. Each signed structure starts with its own
+//!domain tag, fixed-width integers are big-endian and variable-length fields are prefixed by
+//!their length as a big-endian u32.
+
+use alloc::vec::Vec;
+
+///Accumulates the bytes of a signing payload.
+pub struct CanonicalEncoder {
+    data: Vec<u8>,
+}
+
+impl CanonicalEncoder {
+    ///Starts a payload with its domain separation tag.
+    pub fn new(domain: &[u8]) -> Self {
+        Self {
+            data: domain.to_vec(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u128(&mut self, value: u128) -> &mut Self {
+        self.data.extend(value.to_be_bytes());
+        self
+    }
+
+    ///Length-prefixed byte string.
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.data.extend(value);
+        self
+    }
+
+    ///Length-prefixed UTF-8 string.
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+///A structure with a canonical signing payload.
+pub trait SigningPayload {
+    ///Domain separation tag, unique per signed structure and format version.
+    const DOMAIN: &'static [u8];
+
+    ///Appends every signed field, in a fixed order.
+    fn encode(&self, encoder: &mut CanonicalEncoder);
+
+    ///Bytes covered by the signature of this structure.
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut encoder = CanonicalEncoder::new(Self::DOMAIN);
+        self.encode(&mut encoder);
+        encoder.finish()
+    }
+}
@@ -1,144 +1,623 @@
 use aws_lc_rs::signature::UnparsedPublicKey;
 use serde::Deserialize;
-use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
 use toml::{Table, Value};
 
-use crate::types::{AttestErrors, AttestResult, ServiceName, TahiniCertificate};
+use crate::authority::AuthorityCertificate;
+use crate::cose::{self, CertificateClaims};
+use crate::keys;
+use crate::revocation::{RevocationList, RevokedEntry};
+use crate::session::unix_now;
+use crate::service::AttestationServiceClient;
+use crate::types::{
+    AttestErrors, AttestResult, BinaryName, KeyId, PolicyHash, ServiceName, TahiniCertificate,
+};
+use crate::verify::{self, SignatureVerifier};
+
+///Public key trusted to sign certificates within its validity window.
+pub struct IssuerKey {
+    pub id: KeyId,
+    key: UnparsedPublicKey<Vec<u8>>,
+    //Seconds since UNIX epoch
+    pub not_before: u64,
+    //Seconds since UNIX epoch
+    pub not_after: u64,
+    //Services this key may certify. None allows every service
+    pub allowed_services: Option<Vec<ServiceName>>,
+    //Root key that certified this key, for intermediate authorities
+    pub certified_by: Option<KeyId>,
+}
+
+impl IssuerKey {
+    ///Loads an issuer key from a SubjectPublicKeyInfo file (DER or PEM). Without an explicit id, the id is derived from the key
+    ///material. Without explicit bounds, the key is valid forever.
+    pub fn from_file(
+        path: &Path,
+        id: Option<KeyId>,
+        not_before: Option<u64>,
+        not_after: Option<u64>,
+    ) -> AttestResult<Self> {
+        let key_material = keys::load_public_key_material(path).map_err(AttestErrors::KeyError)?;
+        Ok(Self {
+            id: id.unwrap_or_else(|| KeyId::from_public_key(&key_material)),
+            key: UnparsedPublicKey::new(&aws_lc_rs::signature::ED25519, key_material),
+            not_before: not_before.unwrap_or(0),
+            not_after: not_after.unwrap_or(u64::MAX),
+            allowed_services: None,
+            certified_by: None,
+        })
+    }
+
+    ///Intermediate key described by an authority certificate. Does not check the certificate signature.
+    pub fn from_authority(certificate: &AuthorityCertificate) -> AttestResult<Self> {
+        let key_material = hex::decode(&certificate.public_key)
+            .map_err(|_| AttestErrors::KeyError(keys::KeyError::InvalidKeyMaterial))?;
+        if key_material.len() != 32 {
+            return Err(AttestErrors::KeyError(keys::KeyError::InvalidKeyMaterial));
+        }
+        Ok(Self {
+            id: certificate.key_id.clone(),
+            key: UnparsedPublicKey::new(&aws_lc_rs::signature::ED25519, key_material),
+            not_before: certificate.not_before,
+            not_after: certificate.not_after,
+            allowed_services: certificate.allowed_services.clone(),
+            certified_by: Some(certificate.issuer.clone()),
+        })
+    }
+
+    pub fn is_valid_at(&self, timestamp: u64) -> bool {
+        self.not_before <= timestamp && timestamp < self.not_after
+    }
+
+    pub fn allows_service(&self, service_name: &ServiceName) -> bool {
+        self.allowed_services
+            .as_ref()
+            .is_none_or(|services| services.contains(service_name))
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.key.verify_signature(message, signature)
+    }
+
+    pub fn public_key(&self) -> &UnparsedPublicKey<Vec<u8>> {
+        &self.key
+    }
+}
 
 #[derive(Default)]
 pub struct CertificateLoader {
-    certificates: HashMap<ServiceName, TahiniCertificate>,
-    accepted_keys: Option<UnparsedPublicKey<Vec<u8>>>,
-    //FIXME: Lazy to handle types and propagate it everywhere. The value field of the map should be
-    //BinaryName. The goal is for the client to be able to supply a ServiceName via a generated
-    //Tahini Client stub, and this gets handled internally. Other solution is to segment
-    //certificates per service...
-    service_to_bin: HashMap<ServiceName, ServiceName>,
+    certificates: HashMap<BinaryName, TahiniCertificate>,
+    //COSE_Sign1 encoding of registered certificates, when one was provided
+    certificate_envelopes: HashMap<BinaryName, Vec<u8>>,
+    //Issuer keys, indexed by the key id certificates refer to
+    accepted_keys: HashMap<KeyId, IssuerKey>,
+    //Binary hosting each service, as listed by the registered certificates
+    service_to_bin: HashMap<ServiceName, BinaryName>,
+    //Whether v1 certificates (which do not sign the service name) are accepted
+    allow_legacy: bool,
+    //Entries of every verified revocation list
+    revoked: HashSet<RevokedEntry>,
+    //Keys trusted to sign transparency log tree heads
+    log_keys: HashMap<KeyId, IssuerKey>,
+    //Whether certificates must carry a valid transparency log inclusion proof
+    require_inclusion_proof: bool,
+    //Files the loader state was read from, watched for hot reload
+    sources: Vec<PathBuf>,
+}
+
+impl SignatureVerifier for IssuerKey {
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.verify(message, signature)
+    }
 }
 
 impl CertificateLoader {
     pub fn new() -> Self {
         Self {
             certificates: HashMap::new(),
-            accepted_keys: None,
+            certificate_envelopes: HashMap::new(),
+            accepted_keys: HashMap::new(),
             service_to_bin: HashMap::new(),
+            allow_legacy: false,
+            revoked: HashSet::new(),
+            log_keys: HashMap::new(),
+            require_inclusion_proof: false,
+            sources: Vec::new(),
         }
     }
 
-    ///Registers the certificate for a given Tahini service to the loader.
-    ///Only supports loading from filesystem.
-    pub fn register_service(
-        &mut self,
-        path: &Path,
-        service_name: ServiceName,
-    ) -> AttestResult<bool> {
+    ///Records a file the loader state depends on. Files read by the loader methods are recorded
+    ///automatically.
+    pub fn add_source(&mut self, path: &Path) {
+        self.sources.push(path.to_path_buf());
+    }
+
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    ///Requires certificates to carry an inclusion proof against a tree head signed by a
+    ///registered log key. Disabled by default.
+    pub fn require_inclusion_proof(&mut self, require: bool) {
+        self.require_inclusion_proof = require;
+    }
+
+    ///Adds a key to the set of trusted transparency log keys.
+    ///Returns false if a key with the same id was already registered.
+    pub fn register_log_key(&mut self, key: IssuerKey) -> bool {
+        if self.log_keys.contains_key(&key.id) {
+            return false;
+        }
+        self.log_keys.insert(key.id.clone(), key);
+        true
+    }
+
+    ///Enables loading v1 certificates. Disabled by default.
+    pub fn allow_legacy_certificates(&mut self, allow: bool) {
+        self.allow_legacy = allow;
+    }
+
+    ///Checks that the certificate format is one this loader accepts.
+    pub fn check_version(&self, certificate: &TahiniCertificate) -> AttestResult<()> {
+        Ok(verify::check_version(certificate, self.allow_legacy)?)
+    }
+
+    ///Verifies the certificate against the issuer keys and the loaded revocation lists.
+    pub fn verify_certificate(&self, certificate: &TahiniCertificate) -> AttestResult<()> {
+        self.verify_issuance(certificate)?;
+        if self.is_revoked(certificate) {
+            return Err(AttestErrors::CertificateRevokedError);
+        }
+        Ok(())
+    }
+
+    ///Verifies the certificate signature against the issuer key it names.
+    ///The issuer key must be valid both now and at the certificate issuance time.
+    ///v1 certificates do not name their issuer and are tried against every valid key.
+    ///When required, also checks the certificate transparency log inclusion proof.
+    ///Does not consult revocation lists.
+    pub fn verify_issuance(&self, certificate: &TahiniCertificate) -> AttestResult<()> {
+        self.verify_signature(certificate)?;
+        if self.require_inclusion_proof {
+            self.verify_inclusion(certificate)?;
+        }
+        Ok(())
+    }
+
+    ///Checks that the certificate was appended to a transparency log with a trusted key.
+    pub fn verify_inclusion(&self, certificate: &TahiniCertificate) -> AttestResult<()> {
+        let proof = certificate
+            .transparency
+            .as_ref()
+            .ok_or(AttestErrors::MissingInclusionProofError)?;
+        let tree_head = &proof.tree_head;
+        let key = self
+            .log_keys
+            .get(&tree_head.log_id)
+            .ok_or(AttestErrors::UnknownLogKeyError)?;
+        if !key.is_valid_at(tree_head.timestamp) {
+            return Err(AttestErrors::TreeHeadSignatureError);
+        }
+        Ok(verify::verify_inclusion(certificate, key)?)
+    }
+
+    fn verify_signature(&self, certificate: &TahiniCertificate) -> AttestResult<()> {
+        self.check_version(certificate)?;
+        if self.accepted_keys.is_empty() {
+            return Err(AttestErrors::MissingCertificateKey);
+        }
+        let now = unix_now();
+        verify::check_validity(certificate, now)?;
+        match &certificate.issuance {
+            Some(issuance) => {
+                let key = self.check_issuer(
+                    &issuance.issuer,
+                    certificate.hosted_services(),
+                    issuance.issued_at,
+                    now,
+                )?;
+                Ok(verify::verify_certificate_signature(certificate, key)?)
+            }
+            None => {
+                //Malformed hex fails against every key, so report it before trying them
+                if certificate.signed_data().is_err() || hex::decode(&certificate.signature.0).is_err() {
+                    return Err(AttestErrors::MalformedCertificateError);
+                }
+                let verified = self
+                    .accepted_keys
+                    .values()
+                    .filter(|key| key.certified_by.is_none() && key.is_valid_at(now))
+                    .any(|key| verify::verify_certificate_signature(certificate, key).is_ok());
+                if !verified {
+                    return Err(AttestErrors::CertificateSignatureError);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    ///Resolves the key that issued a certificate for the given services, and checks its chain.
+    ///The key and, for intermediates, its root must be valid now and at issuance time, and every
+    ///service must be within the intermediate scope.
+    fn check_issuer(
+        &self,
+        issuer: &KeyId,
+        services: &[ServiceName],
+        issued_at: u64,
+        now: u64,
+    ) -> AttestResult<&IssuerKey> {
+        let key = self
+            .accepted_keys
+            .get(issuer)
+            .ok_or(AttestErrors::UnknownIssuerError)?;
+        if !self.chain_valid_at(key, now) || !self.chain_valid_at(key, issued_at) {
+            return Err(AttestErrors::IssuerKeyExpiredError);
+        }
+        if !services.iter().all(|service| key.allows_service(service)) {
+            return Err(AttestErrors::AuthorityScopeError);
+        }
+        Ok(key)
+    }
+
+    //Whether the key and the root that certified it are valid at the given time
+    fn chain_valid_at(&self, key: &IssuerKey, timestamp: u64) -> bool {
+        let root_valid = match &key.certified_by {
+            None => true,
+            Some(root_id) => self
+                .accepted_keys
+                .get(root_id)
+                .is_some_and(|root| root.is_valid_at(timestamp)),
+        };
+        root_valid && key.is_valid_at(timestamp)
+    }
 
-        let file = File::open(path).map_err(|e| AttestErrors::IoError(e))?;
+    ///Registers the certificate of a given binary to the loader.
+    ///The certificate must verify against the issuer key, so keys have to be loaded first.
+    ///Revoked certificates are still registered, and are refused when they are used.
+    ///Only supports loading from filesystem.
+    pub fn register_binary(&mut self, path: &Path, binary_name: BinaryName) -> AttestResult<bool> {
+        self.add_source(path);
+        let file = File::open(path).map_err(AttestErrors::IoError)?;
         let certificate: TahiniCertificate =
-            serde_json::from_reader(file).map_err(|e| AttestErrors::AttestDataMalformedError(e))?;
-        if service_name != certificate.service_name {
+            serde_json::from_reader(file).map_err(AttestErrors::AttestDataMalformedError)?;
+        if binary_name != certificate.binary_name() {
             return Err(AttestErrors::ServiceMismatchError);
         }
+        self.register_certificate(certificate)
+    }
+
+    ///Registers a certificate under the binary it names, once it verifies against the issuer key.
+    ///Each service can only be hosted by one binary.
+    ///Returns false if it replaced a certificate for the same binary.
+    pub fn register_certificate(&mut self, certificate: TahiniCertificate) -> AttestResult<bool> {
+        self.verify_issuance(&certificate)?;
+        let binary_name = certificate.binary_name();
+        for service in certificate.hosted_services() {
+            match self.service_to_bin.get(service) {
+                Some(other) if other != &binary_name => {
+                    return Err(AttestErrors::DuplicateServiceError(service.clone()))
+                }
+                _ => {}
+            }
+        }
+        //Services the binary no longer hosts are released
+        self.service_to_bin.retain(|_, binary| binary != &binary_name);
+        for service in certificate.hosted_services() {
+            self.service_to_bin.insert(service.clone(), binary_name.clone());
+        }
         Ok(self
             .certificates
-            .insert(service_name.clone(), certificate)
+            .insert(binary_name, certificate)
             .is_none())
-        // match self.service_to_bin.get(&service_name) {
-        //     None => {
-        //             println!(
-        //                 "Got a mismatch : no locally held for {:?} vs certificate {:?}", service_name, certificate.service_name
-        //             );
-        //         return Err(AttestErrors::ServiceMismatchError)}
-        //     ,
-        //     Some(serv) => {
-        //         if serivi != certificate.service_name {
-        //             println!(
-        //                 "Got a mismatch : locally held {:?} vs certificate {:?}",
-        //                 serv, certificate.service_name
-        //             );
-        //             return Err(AttestErrors::ServiceMismatchError);
-        //         }
-        //         Ok(self
-        //             .certificates
-        //             .insert(serv.clone(), certificate)
-        //             .is_none())
-        //     }
-        // }
-    }
-
-    //Loads a public key to verify certificates
+    }
+
+    ///Services hosted by the binaries of registered certificates.
+    pub fn services(&self) -> impl Iterator<Item = &ServiceName> {
+        self.service_to_bin.keys()
+    }
+
+    pub fn binaries(&self) -> impl Iterator<Item = &BinaryName> {
+        self.certificates.keys()
+    }
+
+    ///Binary hosting the service, among those with a registered certificate.
+    pub fn binary_for(&self, service_name: &ServiceName) -> Option<&BinaryName> {
+        self.service_to_bin.get(service_name)
+    }
+
+    pub fn get_binary_certificate(&self, binary_name: &BinaryName) -> Option<&TahiniCertificate> {
+        self.certificates.get(binary_name)
+    }
+
+    ///Registers the COSE_Sign1 encoding of an already registered certificate.
+    ///The envelope must verify against the issuer key and carry the same claims as the certificate.
+    pub fn register_certificate_envelope(
+        &mut self,
+        path: &Path,
+        binary_name: BinaryName,
+    ) -> AttestResult<bool> {
+        self.add_source(path);
+        let envelope = std::fs::read(path).map_err(AttestErrors::IoError)?;
+        let certificate = self
+            .certificates
+            .get(&binary_name)
+            .ok_or(AttestErrors::UnknownCertificateError)?;
+        let claims = self.open_certificate_envelope(&envelope)?;
+        let expected = CertificateClaims::try_from(certificate).map_err(AttestErrors::CoseError)?;
+        if claims != expected {
+            return Err(AttestErrors::CertificateMismatchError);
+        }
+        Ok(self
+            .certificate_envelopes
+            .insert(binary_name, envelope)
+            .is_none())
+    }
+
+    ///Verifies a certificate envelope against the issuer key named in its protected header.
+    ///Like verify_issuance, does not consult revocation lists.
+    pub fn open_certificate_envelope(&self, envelope: &[u8]) -> AttestResult<CertificateClaims> {
+        let key_id = cose::envelope_key_id(envelope).map_err(AttestErrors::CoseError)?;
+        let key = self
+            .accepted_keys
+            .get(&key_id)
+            .ok_or(AttestErrors::UnknownIssuerError)?;
+        let claims =
+            cose::open_certificate(envelope, key.public_key()).map_err(AttestErrors::CoseError)?;
+        if claims.issuer != key_id.0 {
+            return Err(AttestErrors::CertificateMismatchError);
+        }
+        let now = unix_now();
+        if now < claims.issued_at || now >= claims.expires_at {
+            return Err(AttestErrors::CertificateExpiredError);
+        }
+        let services: Vec<ServiceName> = if claims.services.is_empty() {
+            vec![ServiceName(claims.service_name.clone())]
+        } else {
+            claims.services.iter().cloned().map(ServiceName).collect()
+        };
+        self.check_issuer(&key_id, &services, claims.issued_at, now)?;
+        Ok(claims)
+    }
+
+    ///Envelope of the certificate of the binary hosting the service.
+    pub fn get_certificate_envelope(&self, service_name: &ServiceName) -> Option<&Vec<u8>> {
+        self.certificate_envelopes.get(self.binary_for(service_name)?)
+    }
+
+    ///Loads a public key to verify certificates, with a derived id and no validity bounds.
+    ///Returns false if a key with the same id was already registered.
     pub fn load_certificate_key(&mut self, path: &Path) -> AttestResult<bool> {
-        if self.accepted_keys.is_some() {
-            return Ok(false);
+        self.add_source(path);
+        let key = IssuerKey::from_file(path, None, None, None)?;
+        Ok(self.register_issuer_key(key))
+    }
+
+    ///Adds a key to the set of trusted issuers.
+    ///Returns false if a key with the same id was already registered.
+    pub fn register_issuer_key(&mut self, key: IssuerKey) -> bool {
+        if self.accepted_keys.contains_key(&key.id) {
+            return false;
         }
-        let mut file = File::open(path).map_err(|e| AttestErrors::IoError(e))?;
-        let mut pkey_bytes: Vec<u8> = Vec::new();
-        file.read_to_end(&mut pkey_bytes)
-            .map_err(|e| AttestErrors::IoError(e))?;
-        //Hacky: Last 32-bytes of DER format are key bytes. aws-lc-rs requires straight key
-        //material
-        let key_material = &pkey_bytes[pkey_bytes.len() - 32..];
-        let pkey = UnparsedPublicKey::new(&aws_lc_rs::signature::ED25519, key_material.to_vec());
-        self.accepted_keys = Some(pkey);
-        Ok(true)
+        self.accepted_keys.insert(key.id.clone(), key);
+        true
     }
 
-    pub fn get_key(&self) -> &Option<UnparsedPublicKey<Vec<u8>>> {
-        &self.accepted_keys
+    ///Loads an authority certificate from disk. See register_authority.
+    pub fn load_authority_certificate(&mut self, path: &Path) -> AttestResult<bool> {
+        self.add_source(path);
+        let file = File::open(path).map_err(AttestErrors::IoError)?;
+        let certificate: AuthorityCertificate =
+            serde_json::from_reader(file).map_err(AttestErrors::AttestDataMalformedError)?;
+        self.register_authority(certificate)
     }
 
-    pub fn from_config(config_path: &Path) -> AttestResult<Self> {
-        let contents =
-            std::fs::read_to_string(config_path).map_err(|e| AttestErrors::IoError(e))?;
-        let data: Config =
-            toml::from_str(&contents).map_err(|e| AttestErrors::ConfigError(e.to_string()))?;
-        data.into_loader()
+    ///Trusts an intermediate key certified by a currently valid root key.
+    ///Intermediates cannot certify other intermediates, so roots have to be loaded first.
+    ///Returns false if a key with the same id was already registered.
+    pub fn register_authority(&mut self, certificate: AuthorityCertificate) -> AttestResult<bool> {
+        let root = self
+            .accepted_keys
+            .get(&certificate.issuer)
+            .ok_or(AttestErrors::UnknownIssuerError)?;
+        if root.certified_by.is_some() {
+            return Err(AttestErrors::AuthorityChainError);
+        }
+        if !root.is_valid_at(unix_now()) {
+            return Err(AttestErrors::IssuerKeyExpiredError);
+        }
+        verify::verify_authority(&certificate, root)?;
+        Ok(self.register_issuer_key(IssuerKey::from_authority(&certificate)?))
     }
 
-    //FIXME: This method should not exist. It is an artifact of not including service names in the
-    //manifest, and having to retrieve them post-hoc. If the manifest includes the actual service
-    //name for which it is generate, this method should be removed.
-    pub fn register_bin_mapping(&mut self, bin: ServiceName, service: ServiceName) {
-        self.service_to_bin.insert(service, bin);
+    ///Loads a revocation list from disk. It must be signed by a currently valid issuer key.
+    pub fn load_revocation_list(&mut self, path: &Path) -> AttestResult<()> {
+        self.add_source(path);
+        let file = File::open(path).map_err(AttestErrors::IoError)?;
+        let list: RevocationList =
+            serde_json::from_reader(file).map_err(AttestErrors::AttestDataMalformedError)?;
+        self.register_revocation_list(list)
     }
 
-    pub fn get_reverse_mapping(&self, service_name: &ServiceName) -> Option<&ServiceName> {
-        self.service_to_bin.get(service_name)
+    ///Registers the entries of a revocation list signed by a currently valid root key.
+    ///Entries such as a policy hash match certificates of every issuer, so lists signed by
+    ///intermediate keys, which are scoped to some services, are refused.
+    pub fn register_revocation_list(&mut self, list: RevocationList) -> AttestResult<()> {
+        let key = self
+            .accepted_keys
+            .get(&list.issuer)
+            .ok_or(AttestErrors::UnknownIssuerError)?;
+        if key.certified_by.is_some() {
+            return Err(AttestErrors::RevocationIssuerError);
+        }
+        if !self.chain_valid_at(key, unix_now()) {
+            return Err(AttestErrors::IssuerKeyExpiredError);
+        }
+        verify::verify_revocation_list(&list, key)?;
+        self.revoked.extend(list.entries);
+        Ok(())
+    }
+
+    pub fn is_revoked(&self, certificate: &TahiniCertificate) -> bool {
+        self.revoked.iter().any(|entry| entry.matches(certificate))
+    }
+
+    pub fn get_key(&self, id: &KeyId) -> Option<&IssuerKey> {
+        self.accepted_keys.get(id)
+    }
+
+    pub fn get_keys(&self) -> impl Iterator<Item = &IssuerKey> {
+        self.accepted_keys.values()
+    }
+
+    pub fn from_config(config_path: &Path) -> AttestResult<Self> {
+        let contents = std::fs::read_to_string(config_path).map_err(AttestErrors::IoError)?;
+        let data: Config =
+            toml::from_str(&contents).map_err(|e| AttestErrors::ConfigError(e.to_string()))?;
+        let mut loader = data.into_loader()?;
+        loader.add_source(config_path);
+        Ok(loader)
     }
 }
 
 #[derive(Deserialize, Debug)]
 struct Config {
+    //Binary name to certificate path
     certificates: Table,
-    keys: Option<KeyConfig>,
-    service_mapping: Table,
+    keys: Option<IssuerKeysConfig>,
+    //Authority certificates of intermediate keys, signed by one of the keys above
+    #[serde(default)]
+    authorities: Vec<String>,
+    #[serde(default)]
+    revocation_lists: Vec<String>,
+    //Binary name to COSE_Sign1 certificate path
+    #[serde(default)]
+    cose_certificates: Table,
+    #[serde(default)]
+    allow_legacy_certificates: bool,
+    transparency: Option<TransparencyConfig>,
 }
 
+///Issuer key entry of a configuration file.
 #[derive(Deserialize, Debug)]
-struct KeyConfig {
+pub struct IssuerKeyConfig {
     path: String,
+    id: Option<String>,
+    not_before: Option<u64>,
+    not_after: Option<u64>,
+}
+
+///Issuer keys of a configuration file. Accepts a bare path, a single key table or a list of
+///key tables.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum IssuerKeysConfig {
+    Path(String),
+    Single(IssuerKeyConfig),
+    List(Vec<IssuerKeyConfig>),
+}
+
+///Transparency log section of a configuration file.
+#[derive(Deserialize, Debug)]
+pub struct TransparencyConfig {
+    //Keys signing the log tree heads, in the same formats as issuer keys
+    log_keys: IssuerKeysConfig,
+    #[serde(default)]
+    require_inclusion_proof: bool,
+}
+
+impl TransparencyConfig {
+    pub fn load_into(self, loader: &mut CertificateLoader) -> AttestResult<()> {
+        loader.require_inclusion_proof(self.require_inclusion_proof);
+        for path in self.log_keys.paths() {
+            loader.add_source(Path::new(path));
+        }
+        for key in self.log_keys.into_keys()? {
+            let id = key.id.clone();
+            if !loader.register_log_key(key) {
+                return Err(AttestErrors::ConfigError(format!(
+                    "Log key id {} is registered twice",
+                    id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IssuerKeysConfig {
+    pub fn load_into(self, loader: &mut CertificateLoader) -> AttestResult<()> {
+        for path in self.paths() {
+            loader.add_source(Path::new(path));
+        }
+        for key in self.into_keys()? {
+            let id = key.id.clone();
+            if !loader.register_issuer_key(key) {
+                return Err(AttestErrors::ConfigError(format!(
+                    "Issuer key id {} is registered twice",
+                    id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            IssuerKeysConfig::Path(path) => vec![path.as_str()],
+            IssuerKeysConfig::Single(key) => vec![key.path.as_str()],
+            IssuerKeysConfig::List(keys) => keys.iter().map(|key| key.path.as_str()).collect(),
+        }
+    }
+
+    pub fn into_keys(self) -> AttestResult<Vec<IssuerKey>> {
+        let keys = match self {
+            IssuerKeysConfig::Path(path) => vec![IssuerKeyConfig {
+                path,
+                id: None,
+                not_before: None,
+                not_after: None,
+            }],
+            IssuerKeysConfig::Single(key) => vec![key],
+            IssuerKeysConfig::List(keys) => keys,
+        };
+        keys.into_iter()
+            .map(|key_config| {
+                IssuerKey::from_file(
+                    Path::new(&key_config.path),
+                    key_config.id.map(KeyId),
+                    key_config.not_before,
+                    key_config.not_after,
+                )
+            })
+            .collect()
+    }
 }
 
 impl Config {
     fn into_loader(self) -> AttestResult<CertificateLoader> {
         let mut loader = CertificateLoader::new();
-        if self.keys.is_some() {
-            let key_path = self.keys.unwrap().path;
-            let path = Path::new(&key_path);
-            loader.load_certificate_key(path)?;
+        loader.allow_legacy_certificates(self.allow_legacy_certificates);
+        if let Some(keys) = self.keys {
+            keys.load_into(&mut loader)?;
         }
-        for (bin_name, service_name) in self.service_mapping.into_iter() {
-            loader.register_bin_mapping(
-                ServiceName(bin_name),
-                ServiceName(service_name.as_str().unwrap().to_string()),
-            );
+        if let Some(transparency) = self.transparency {
+            transparency.load_into(&mut loader)?;
         }
-
-        for (service_name, v) in self.certificates.into_iter() {
+        for authority_path in self.authorities.iter() {
+            loader.load_authority_certificate(Path::new(authority_path))?;
+        }
+        for list_path in self.revocation_lists.iter() {
+            loader.load_revocation_list(Path::new(list_path))?;
+        }
+        for (bin_name, v) in self.certificates.into_iter() {
             match v {
                 Value::String(certif_path) => {
                     let path = Path::new(&certif_path);
-                    loader.register_service(path, ServiceName(service_name))?;
+                    loader.register_binary(path, BinaryName(bin_name))?;
                 }
                 _ => {
                     return Err(AttestErrors::ConfigError(
@@ -147,16 +626,400 @@ impl Config {
                 }
             }
         }
+        for (bin_name, v) in self.cose_certificates.into_iter() {
+            match v {
+                Value::String(envelope_path) => {
+                    let path = Path::new(&envelope_path);
+                    loader.register_certificate_envelope(path, BinaryName(bin_name))?;
+                }
+                _ => {
+                    return Err(AttestErrors::ConfigError(
+                        "COSE certificate path is malformed".to_string(),
+                    ));
+                }
+            }
+        }
         Ok(loader)
     }
 }
 
 pub trait CertificateProvider {
+    ///Certificate of the binary hosting the service.
     fn get_certificate(&self, service_name: &ServiceName) -> Option<&TahiniCertificate>;
 }
 
 impl CertificateProvider for CertificateLoader {
     fn get_certificate(&self, service_name: &ServiceName) -> Option<&TahiniCertificate> {
-        self.certificates.get(service_name)
+        self.get_binary_certificate(self.binary_for(service_name)?)
+    }
+}
+
+///Certificates fetched from a sidecar instead of read from disk. A certificate is only accepted
+///if it verifies against the trust anchors of the loader, is not revoked, and attests to the
+///policy hash pinned for the service it was fetched for.
+pub struct PinnedCertificateProvider {
+    //Trust anchors, and store of accepted certificates
+    loader: CertificateLoader,
+    pins: HashMap<ServiceName, PolicyHash>,
+}
+
+impl PinnedCertificateProvider {
+    pub fn new(loader: CertificateLoader, pins: HashMap<ServiceName, PolicyHash>) -> Self {
+        Self { loader, pins }
+    }
+
+    pub fn loader(&self) -> &CertificateLoader {
+        &self.loader
+    }
+
+    pub fn pins(&self) -> &HashMap<ServiceName, PolicyHash> {
+        &self.pins
+    }
+
+    ///Checks a certificate for `service_name` against the pin of that service and the loader,
+    ///then stores it.
+    pub fn accept(
+        &mut self,
+        certificate: TahiniCertificate,
+        service_name: &ServiceName,
+    ) -> AttestResult<()> {
+        self.check_pin(&certificate, service_name)?;
+        self.loader.verify_certificate(&certificate)?;
+        self.loader.register_certificate(certificate)?;
+        Ok(())
+    }
+
+    ///Checks that the certificate hosts `service_name`, which must be pinned, and attests to the
+    ///pinned policy hash of every service it hosts. Pins of co-hosted services don't stand in for
+    ///the one of `service_name`.
+    pub fn check_pin(
+        &self,
+        certificate: &TahiniCertificate,
+        service_name: &ServiceName,
+    ) -> AttestResult<()> {
+        if !certificate.hosts(service_name) {
+            return Err(AttestErrors::ServiceMismatchError);
+        }
+        if !self.pins.contains_key(service_name) {
+            return Err(AttestErrors::UnpinnedServiceError);
+        }
+        for service in certificate.hosted_services() {
+            if let Some(pin) = self.pins.get(service) {
+                if &certificate.policy_hash != pin {
+                    return Err(AttestErrors::PolicyPinMismatchError);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///Fetches the certificate of the binary hosting a service from the sidecar.
+    pub async fn fetch(
+        &mut self,
+        client: &AttestationServiceClient,
+        service_name: &ServiceName,
+    ) -> AttestResult<()> {
+        let certificate = client
+            .get_certificate(tarpc::context::current(), service_name.clone())
+            .await
+            .map_err(AttestErrors::NetworkError)?
+            .ok_or(AttestErrors::UnknownCertificateError)?;
+        self.accept(certificate, service_name)
+    }
+
+    ///Fetches the certificate of every pinned service.
+    pub async fn fetch_pinned(&mut self, client: &AttestationServiceClient) -> AttestResult<()> {
+        let services: Vec<ServiceName> = self.pins.keys().cloned().collect();
+        for service_name in services.iter() {
+            self.fetch(client, service_name).await?;
+        }
+        Ok(())
+    }
+
+    pub fn into_loader(self) -> CertificateLoader {
+        self.loader
+    }
+}
+
+impl CertificateProvider for PinnedCertificateProvider {
+    fn get_certificate(&self, service_name: &ServiceName) -> Option<&TahiniCertificate> {
+        self.loader.get_certificate(service_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair, ED25519};
+
+    use super::*;
+    use crate::types::{BinHash, CertificateVersion, IssuanceInfo, Signature, ToolchainInfo};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    //Loader trusting `root`, and `intermediate` as an authority certified by it for one service
+    fn loader(root: &Ed25519KeyPair, intermediate: &Ed25519KeyPair) -> CertificateLoader {
+        let mut loader = CertificateLoader::new();
+        loader.register_issuer_key(IssuerKey {
+            id: KeyId("root".to_string()),
+            key: UnparsedPublicKey::new(&ED25519, root.public_key().as_ref().to_vec()),
+            not_before: 0,
+            not_after: u64::MAX,
+            allowed_services: None,
+            certified_by: None,
+        });
+        let authority = AuthorityCertificate::new(
+            KeyId("intermediate".to_string()),
+            intermediate.public_key().as_ref(),
+            KeyId("root".to_string()),
+            0,
+            u64::MAX,
+            Some(vec![ServiceName("scoped-server".to_string())]),
+            root,
+        );
+        assert!(loader.register_authority(authority).unwrap());
+        loader
+    }
+
+    fn certificate(binary_hash: &str) -> TahiniCertificate {
+        TahiniCertificate {
+            version: CertificateVersion::V1,
+            service_name: ServiceName("other-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash(binary_hash.to_string()),
+            issuance: None,
+            policy_manifest: None,
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        }
+    }
+
+    //Issuance by the given key, expiring in an hour
+    fn issuance(issuer: &str, issued_at: u64) -> IssuanceInfo {
+        IssuanceInfo {
+            issuer: KeyId(issuer.to_string()),
+            issued_at,
+            expires_at: unix_now() + 3600,
+            toolchain: ToolchainInfo {
+                generator: "test".to_string(),
+                rustc: "test".to_string(),
+            },
+        }
+    }
+
+    fn revoking(issuer: &str, binary_hash: &str, key: &Ed25519KeyPair) -> RevocationList {
+        RevocationList::new(
+            KeyId(issuer.to_string()),
+            unix_now(),
+            vec![RevokedEntry::BinaryHash(BinHash(binary_hash.to_string()))],
+            key,
+        )
+    }
+
+    #[test]
+    fn root_revocation_lists_are_applied() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        let revoked = "02".repeat(32);
+        loader
+            .register_revocation_list(revoking("root", &revoked, &root))
+            .unwrap();
+        assert!(loader.is_revoked(&certificate(&revoked)));
+        assert!(!loader.is_revoked(&certificate(&"03".repeat(32))));
+    }
+
+    #[test]
+    fn intermediate_revocation_lists_are_refused() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        let revoked = "02".repeat(32);
+        assert!(matches!(
+            loader.register_revocation_list(revoking("intermediate", &revoked, &intermediate)),
+            Err(AttestErrors::RevocationIssuerError)
+        ));
+        assert!(!loader.is_revoked(&certificate(&revoked)));
+    }
+
+    #[test]
+    fn forged_revocation_lists_are_refused() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        let revoked = "02".repeat(32);
+        assert!(matches!(
+            loader.register_revocation_list(revoking("root", &revoked, &intermediate)),
+            Err(AttestErrors::RevocationSignatureError)
+        ));
+        assert!(matches!(
+            loader.register_revocation_list(revoking("unknown", &revoked, &root)),
+            Err(AttestErrors::UnknownIssuerError)
+        ));
+        assert!(!loader.is_revoked(&certificate(&revoked)));
+    }
+
+    #[test]
+    fn revoked_certificates_are_refused() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        loader.allow_legacy_certificates(true);
+        let signed = |binary_hash: &str| {
+            let mut certificate = certificate(binary_hash);
+            certificate.signature = root.sign(&certificate.signed_data().unwrap()).into();
+            certificate
+        };
+        let (revoked, other) = (signed(&"02".repeat(32)), signed(&"03".repeat(32)));
+        loader.verify_certificate(&revoked).unwrap();
+        loader.register_certificate(revoked.clone()).unwrap();
+
+        loader
+            .register_revocation_list(revoking("root", &"02".repeat(32), &root))
+            .unwrap();
+        assert!(matches!(
+            loader.verify_certificate(&revoked),
+            Err(AttestErrors::CertificateRevokedError)
+        ));
+        assert!(loader.verify_certificate(&other).is_ok());
+    }
+
+    #[test]
+    fn requested_service_must_be_pinned() {
+        let pinned = ServiceName("pinned-server".to_string());
+        let unpinned = ServiceName("unpinned-server".to_string());
+        let mut co_hosting = certificate(&"02".repeat(32));
+        co_hosting.version = CertificateVersion::V4;
+        co_hosting.services = vec![pinned.clone(), unpinned.clone()];
+        let provider = PinnedCertificateProvider::new(
+            CertificateLoader::new(),
+            HashMap::from([(pinned.clone(), PolicyHash("01".repeat(32)))]),
+        );
+        assert!(provider.check_pin(&co_hosting, &pinned).is_ok());
+        assert!(matches!(
+            provider.check_pin(&co_hosting, &unpinned),
+            Err(AttestErrors::UnpinnedServiceError)
+        ));
+        assert!(matches!(
+            provider.check_pin(&co_hosting, &ServiceName("other-server".to_string())),
+            Err(AttestErrors::ServiceMismatchError)
+        ));
+
+        co_hosting.policy_hash = PolicyHash("03".repeat(32));
+        assert!(matches!(
+            provider.check_pin(&co_hosting, &pinned),
+            Err(AttestErrors::PolicyPinMismatchError)
+        ));
+    }
+
+    #[test]
+    fn certificates_are_verified_on_register() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        loader.allow_legacy_certificates(true);
+        let mut forged = certificate(&"02".repeat(32));
+        forged.signature = key_pair().sign(&forged.signed_data().unwrap()).into();
+        assert!(matches!(
+            loader.register_certificate(forged),
+            Err(AttestErrors::CertificateSignatureError)
+        ));
+        let service = ServiceName("other-server".to_string());
+        assert!(loader.binary_for(&service).is_none());
+
+        let mut signed = certificate(&"02".repeat(32));
+        signed.signature = root.sign(&signed.signed_data().unwrap()).into();
+        assert!(loader.register_certificate(signed).unwrap());
+        assert_eq!(loader.binary_for(&service), Some(&BinaryName("other-server".to_string())));
+    }
+
+    #[test]
+    fn issuer_keys_are_bound_to_their_window_and_services() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        let now = unix_now();
+        let issued = |issuer: &str, service: &str, issued_at: u64, key: &Ed25519KeyPair| {
+            let mut certificate = certificate(&"02".repeat(32));
+            certificate.version = CertificateVersion::V2;
+            certificate.service_name = ServiceName(service.to_string());
+            certificate.issuance = Some(issuance(issuer, issued_at));
+            certificate.signature = key.sign(&certificate.signed_data().unwrap()).into();
+            certificate
+        };
+        let scoped = issued("intermediate", "scoped-server", now - 100, &intermediate);
+        assert!(loader.verify_certificate(&scoped).is_ok());
+        let out_of_scope = issued("intermediate", "other-server", now - 100, &intermediate);
+        assert!(matches!(
+            loader.verify_certificate(&out_of_scope),
+            Err(AttestErrors::AuthorityScopeError)
+        ));
+
+        let (expired, recent) = (key_pair(), key_pair());
+        for (id, key, not_before, not_after) in [
+            ("expired", &expired, 0, now - 10),
+            ("recent", &recent, now - 10, u64::MAX),
+        ] {
+            loader.register_issuer_key(IssuerKey {
+                id: KeyId(id.to_string()),
+                key: UnparsedPublicKey::new(&ED25519, key.public_key().as_ref().to_vec()),
+                not_before,
+                not_after,
+                allowed_services: None,
+                certified_by: None,
+            });
+        }
+        //Valid at issuance but not now, then valid now but not at issuance
+        assert!(matches!(
+            loader.verify_certificate(&issued("expired", "other-server", now - 100, &expired)),
+            Err(AttestErrors::IssuerKeyExpiredError)
+        ));
+        assert!(matches!(
+            loader.verify_certificate(&issued("recent", "other-server", now - 100, &recent)),
+            Err(AttestErrors::IssuerKeyExpiredError)
+        ));
+        assert!(loader
+            .verify_certificate(&issued("recent", "other-server", now - 5, &recent))
+            .is_ok());
+    }
+
+    #[test]
+    fn services_are_looked_up_through_their_binary() {
+        let (root, intermediate) = (key_pair(), key_pair());
+        let mut loader = loader(&root, &intermediate);
+        let service = |name: &str| ServiceName(name.to_string());
+        let multi_service = |binary: &str, services: &[&str]| {
+            let mut certificate = certificate(&"02".repeat(32));
+            certificate.version = CertificateVersion::V4;
+            certificate.service_name = service(binary);
+            certificate.services = services.iter().map(|name| service(name)).collect();
+            certificate.issuance = Some(issuance("root", unix_now() - 5));
+            certificate.signature = root.sign(&certificate.signed_data().unwrap()).into();
+            certificate
+        };
+        let binary = BinaryName("hoodini-bin".to_string());
+        let hosting = multi_service("hoodini-bin", &["hoodini-server", "hoodini-admin"]);
+        assert!(hosting.hosts(&service("hoodini-admin")));
+        assert!(!hosting.hosts(&service("hoodini-bin")));
+        assert!(loader.register_certificate(hosting.clone()).unwrap());
+        for name in ["hoodini-server", "hoodini-admin"] {
+            assert_eq!(loader.binary_for(&service(name)), Some(&binary));
+            assert_eq!(loader.get_certificate(&service(name)), Some(&hosting));
+        }
+        assert!(loader.get_certificate(&service("hoodini-bin")).is_none());
+
+        assert!(matches!(
+            loader.register_certificate(multi_service("other-bin", &["hoodini-admin"])),
+            Err(AttestErrors::DuplicateServiceError(_))
+        ));
+        //Services dropped by a new certificate of the binary are released
+        assert!(!loader
+            .register_certificate(multi_service("hoodini-bin", &["hoodini-server"]))
+            .unwrap());
+        assert!(loader.binary_for(&service("hoodini-admin")).is_none());
+        assert!(loader
+            .register_certificate(multi_service("other-bin", &["hoodini-admin"]))
+            .unwrap());
+        let mut services: Vec<_> = loader.services().map(|service| service.0.as_str()).collect();
+        services.sort();
+        assert_eq!(services, ["hoodini-admin", "hoodini-server"]);
     }
 }
@@ -0,0 +1,307 @@
+//!Codecs the attestation RPC can be carried in, and their negotiation.
+//!
+//!Right after connecting, and after the TLS handshake if any, the client sends one byte naming
+//!the codec it wants. The sidecar answers with the same byte if it accepts it, or with 0 before
+//!closing the connection. Tarpc frames follow in the agreed codec.
+//!
+//!CBOR is the compact one. Formats that aren't self-describing, such as bincode or postcard, can't
+//!carry certificates: their optional fields are skipped when absent and issuance info is flattened.
+
+use std::{fmt, io, pin::Pin};
+
+use bytes::{Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tarpc::tokio_serde::{Deserializer, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//Sidecar answer to a codec it doesn't accept
+const REFUSED: u8 = 0;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    //Readable, for debugging
+    Json,
+    #[default]
+    Cbor,
+}
+
+impl Codec {
+    ///Codecs a sidecar accepts unless configured otherwise.
+    pub const ALL: [Codec; 2] = [Codec::Json, Codec::Cbor];
+
+    fn tag(&self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Cbor => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        Codec::ALL.into_iter().find(|codec| codec.tag() == tag)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    //Connection failed during the negotiation
+    IoError(io::Error),
+    //Peer named a codec this version doesn't know, or isn't speaking the negotiation at all
+    UnknownCodecError(u8),
+    //Sidecar doesn't accept the codec the client asked for
+    CodecRefusedError(Codec),
+    //Sidecar answered with another codec than the one asked for
+    UnexpectedCodecError { requested: Codec, received: u8 },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::IoError(e) => write!(f, "codec negotiation failed: {}", e),
+            CodecError::UnknownCodecError(tag) => write!(f, "unknown codec {}", tag),
+            CodecError::CodecRefusedError(codec) => {
+                write!(f, "sidecar doesn't accept the {} codec", codec)
+            }
+            CodecError::UnexpectedCodecError {
+                requested,
+                received,
+            } => write!(
+                f,
+                "asked for the {} codec, sidecar answered with codec {}",
+                requested, received
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(value: io::Error) -> Self {
+        CodecError::IoError(value)
+    }
+}
+
+///Client end: asks for `codec`, and fails unless the sidecar accepts it.
+pub async fn request_codec<S>(stream: &mut S, codec: Codec) -> Result<(), CodecError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&[codec.tag()]).await?;
+    stream.flush().await?;
+    match stream.read_u8().await? {
+        REFUSED => Err(CodecError::CodecRefusedError(codec)),
+        received if received == codec.tag() => Ok(()),
+        received => Err(CodecError::UnexpectedCodecError {
+            requested: codec,
+            received,
+        }),
+    }
+}
+
+///Sidecar end: reads the codec the client asks for, and accepts it if it is one of `accepted`.
+///The client is told about a refusal before the error is returned.
+pub async fn accept_codec<S>(stream: &mut S, accepted: &[Codec]) -> Result<Codec, CodecError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tag = stream.read_u8().await?;
+    let outcome = match Codec::from_tag(tag) {
+        Some(codec) if accepted.contains(&codec) => Ok(codec),
+        Some(codec) => Err(CodecError::CodecRefusedError(codec)),
+        None => Err(CodecError::UnknownCodecError(tag)),
+    };
+    let answer = outcome.as_ref().map_or(REFUSED, Codec::tag);
+    stream.write_all(&[answer]).await?;
+    stream.flush().await?;
+    outcome
+}
+
+//Lets a single transport type carry either codec, chosen at runtime
+impl<T: Serialize> Serializer<T> for Codec {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> io::Result<Bytes> {
+        match *self {
+            Codec::Json => Ok(Bytes::from(serde_json::to_vec(item)?)),
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(item, &mut bytes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok(Bytes::from(bytes))
+            }
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for Codec {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<T> {
+        match *self {
+            Codec::Json => Ok(serde_json::from_slice(src)?),
+            Codec::Cbor => ciborium::from_reader(src.as_ref())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::manifest::PolicyManifest;
+    use crate::types::{
+        BinHash, CertificateVersion, ClientId, DynamicAttestationReport, IssuanceInfo, KeyId,
+        PolicyHash, ServiceName, Signature, TahiniCertificate, ToolchainInfo,
+    };
+
+    #[tokio::test]
+    async fn accepted_codec_is_echoed() {
+        let (mut client, mut sidecar) = duplex(64);
+        let (requested, accepted) = tokio::join!(
+            request_codec(&mut client, Codec::Cbor),
+            accept_codec(&mut sidecar, &Codec::ALL)
+        );
+        assert!(requested.is_ok());
+        assert_eq!(accepted.unwrap(), Codec::Cbor);
+    }
+
+    #[tokio::test]
+    async fn refused_codec_fails_on_both_ends() {
+        let (mut client, mut sidecar) = duplex(64);
+        let (requested, accepted) = tokio::join!(
+            request_codec(&mut client, Codec::Json),
+            accept_codec(&mut sidecar, &[Codec::Cbor])
+        );
+        assert!(matches!(requested, Err(CodecError::CodecRefusedError(Codec::Json))));
+        assert!(matches!(accepted, Err(CodecError::CodecRefusedError(Codec::Json))));
+
+        //The refusal is a 0 on the wire
+        let (mut client, mut sidecar) = duplex(64);
+        client.write_all(&[Codec::Json.tag()]).await.unwrap();
+        assert!(accept_codec(&mut sidecar, &[Codec::Cbor]).await.is_err());
+        assert_eq!(client.read_u8().await.unwrap(), REFUSED);
+    }
+
+    #[tokio::test]
+    async fn unknown_codec_is_refused() {
+        let (mut client, mut sidecar) = duplex(64);
+        client.write_all(&[9]).await.unwrap();
+        assert!(matches!(
+            accept_codec(&mut sidecar, &Codec::ALL).await,
+            Err(CodecError::UnknownCodecError(9))
+        ));
+        assert_eq!(client.read_u8().await.unwrap(), REFUSED);
+    }
+
+    #[tokio::test]
+    async fn other_codec_in_answer_is_rejected() {
+        let (mut client, mut sidecar) = duplex(64);
+        sidecar.write_all(&[Codec::Json.tag()]).await.unwrap();
+        assert!(matches!(
+            request_codec(&mut client, Codec::Cbor).await,
+            Err(CodecError::UnexpectedCodecError {
+                requested: Codec::Cbor,
+                received: 1
+            })
+        ));
+        assert_eq!(sidecar.read_u8().await.unwrap(), Codec::Cbor.tag());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_an_io_error() {
+        let (mut client, sidecar) = duplex(64);
+        drop(sidecar);
+        assert!(matches!(
+            request_codec(&mut client, Codec::Cbor).await,
+            Err(CodecError::IoError(_))
+        ));
+    }
+
+    //Report whose certificate has flattened issuance info and optional fields, both set and not
+    fn report() -> DynamicAttestationReport {
+        let certificate = TahiniCertificate {
+            version: CertificateVersion::V4,
+            service_name: ServiceName("hoodini-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: Some(IssuanceInfo {
+                issuer: KeyId("0011223344556677".to_string()),
+                issued_at: 1_700_000_000,
+                expires_at: 1_800_000_000,
+                toolchain: ToolchainInfo {
+                    generator: "certificate_generation 0.1.0".to_string(),
+                    rustc: "rustc 1.87.0".to_string(),
+                },
+            }),
+            policy_manifest: Some(PolicyManifest {
+                dependency_hashes: BTreeMap::new(),
+                local_summary_hash: "bb".repeat(32),
+                local_impls_hashes: BTreeMap::from([(
+                    "<impl Policy for ConsentPolicy>".to_string(),
+                    "cc".repeat(32),
+                )]),
+            }),
+            services: vec![ServiceName("hoodini-server".to_string())],
+            signature: Signature("dd".repeat(64)),
+            transparency: None,
+        };
+        DynamicAttestationReport {
+            certificate,
+            nonce: u128::MAX - 1,
+            service_name: ServiceName("hoodini-server".to_string()),
+            current_bin_hash: BinHash("02".repeat(32)),
+            client_key_share: (0..32).collect(),
+            server_key_share: (32..64).collect(),
+            client_id: ClientId::from(7),
+            signature: Signature("ee".repeat(64)),
+        }
+    }
+
+    #[test]
+    fn reports_round_trip() {
+        let report = report();
+        for mut codec in Codec::ALL {
+            let bytes = Serializer::serialize(Pin::new(&mut codec), &report).unwrap();
+            let decoded: DynamicAttestationReport =
+                Deserializer::deserialize(Pin::new(&mut codec), &BytesMut::from(&bytes[..]))
+                    .unwrap();
+            assert_eq!(decoded.certificate, report.certificate, "{} codec", codec);
+            assert_eq!(decoded.nonce, report.nonce);
+            assert_eq!(decoded.service_name, report.service_name);
+            assert_eq!(decoded.current_bin_hash, report.current_bin_hash);
+            assert_eq!(decoded.client_key_share, report.client_key_share);
+            assert_eq!(decoded.server_key_share, report.server_key_share);
+            assert_eq!(decoded.client_id, report.client_id);
+            assert_eq!(decoded.signature, report.signature);
+        }
+    }
+
+    #[test]
+    fn cbor_is_smaller_than_json() {
+        let report = report();
+        let json = Serializer::serialize(Pin::new(&mut Codec::Json), &report).unwrap();
+        let cbor = Serializer::serialize(Pin::new(&mut Codec::Cbor), &report).unwrap();
+        assert!(cbor.len() < json.len());
+    }
+}
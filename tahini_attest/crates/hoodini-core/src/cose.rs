@@ -0,0 +1,403 @@
+//!COSE_Sign1 envelopes (RFC 9052) for certificates and attestation reports.
+//!
+//!Envelopes are tagged COSE_Sign1 structures with a CBOR map payload. The protected header
+//!carries the signature algorithm (EdDSA) and the id of the signing key, so consumers outside
+//!of Rust can verify them with any COSE library.
+
+use alloc::{format, string::String, vec::Vec};
+#[cfg(feature="std")]
+use aws_lc_rs::signature::Ed25519KeyPair;
+use coset::{iana, CoseSign1, TaggedCborSerializable};
+#[cfg(feature="std")]
+use coset::{CoseSign1Builder, HeaderBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(feature="std")]
+use crate::types::DynamicAttestationData;
+use crate::types::{KeyId, TahiniCertificate, ToolchainInfo};
+use crate::verify::SignatureVerifier;
+
+#[derive(Debug)]
+pub enum CoseEnvelopeError {
+    CoseError(coset::CoseError),
+    CborError(String),
+    //Protected header does not declare EdDSA
+    UnsupportedAlgorithm,
+    MissingPayload,
+    SignatureError,
+    //Structure cannot be represented as claims, e.g. non-hex hashes or a v1 certificate
+    MalformedClaims,
+}
+
+impl core::fmt::Display for CoseEnvelopeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CoseEnvelopeError::CoseError(_) => write!(f, "malformed COSE_Sign1 structure"),
+            CoseEnvelopeError::CborError(reason) => write!(f, "malformed CBOR payload: {}", reason),
+            CoseEnvelopeError::UnsupportedAlgorithm => write!(f, "envelope is not signed with EdDSA"),
+            CoseEnvelopeError::MissingPayload => write!(f, "envelope has no payload"),
+            CoseEnvelopeError::SignatureError => write!(f, "envelope signature is invalid"),
+            CoseEnvelopeError::MalformedClaims => write!(f, "malformed envelope claims"),
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl std::error::Error for CoseEnvelopeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CoseEnvelopeError::CoseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+///CBOR payload of a certificate envelope. Mirrors the signed fields of v2 and later certificates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CertificateClaims {
+    pub version: u8,
+    pub service_name: String,
+    #[serde(with = "serde_bytes")]
+    pub policy_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub binary_hash: Vec<u8>,
+    pub issuer: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub toolchain: ToolchainInfo,
+    //Digest of the policy manifest, for certificates that carry one
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub policy_manifest: Option<Vec<u8>>,
+    //Services hosted by the binary of v4 certificates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<String>,
+}
+
+impl TryFrom<&TahiniCertificate> for CertificateClaims {
+    type Error = CoseEnvelopeError;
+
+    fn try_from(certificate: &TahiniCertificate) -> Result<Self, Self::Error> {
+        let issuance = certificate
+            .issuance
+            .as_ref()
+            .ok_or(CoseEnvelopeError::MalformedClaims)?;
+        Ok(Self {
+            version: certificate.version.0,
+            service_name: certificate.service_name.0.clone(),
+            policy_hash: hex::decode(&certificate.policy_hash.0)
+                .map_err(|_| CoseEnvelopeError::MalformedClaims)?,
+            binary_hash: hex::decode(&certificate.binary_hash.0)
+                .map_err(|_| CoseEnvelopeError::MalformedClaims)?,
+            issuer: issuance.issuer.0.clone(),
+            issued_at: issuance.issued_at,
+            expires_at: issuance.expires_at,
+            toolchain: issuance.toolchain.clone(),
+            policy_manifest: certificate
+                .policy_manifest
+                .as_ref()
+                .map(|manifest| manifest.digest().to_vec()),
+            services: certificate
+                .services
+                .iter()
+                .map(|service| service.0.clone())
+                .collect(),
+        })
+    }
+}
+
+///CBOR payload of an attestation report envelope.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReportClaims {
+    //Certificate envelope, signed by the certificate issuer
+    #[serde(with = "serde_bytes")]
+    pub certificate: Vec<u8>,
+    //Big-endian u128
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    pub service_name: String,
+    #[serde(with = "serde_bytes")]
+    pub current_bin_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub client_key_share: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub server_key_share: Vec<u8>,
+    pub client_id: u64,
+}
+
+#[cfg(feature="std")]
+fn sign1<T: Serialize>(
+    claims: &T,
+    key_id: &KeyId,
+    key: &Ed25519KeyPair,
+) -> Result<Vec<u8>, CoseEnvelopeError> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(claims, &mut payload)
+        .map_err(|e| CoseEnvelopeError::CborError(e.to_string()))?;
+    let protected = HeaderBuilder::new()
+        .algorithm(iana::Algorithm::EdDSA)
+        .key_id(key_id.0.as_bytes().to_vec())
+        .build();
+    CoseSign1Builder::new()
+        .protected(protected)
+        .payload(payload)
+        .create_signature(b"", |tbs| key.sign(tbs).as_ref().to_vec())
+        .build()
+        .to_tagged_vec()
+        .map_err(CoseEnvelopeError::CoseError)
+}
+
+fn open1<T: DeserializeOwned>(
+    envelope: &[u8],
+    key: &impl SignatureVerifier,
+) -> Result<T, CoseEnvelopeError> {
+    let sign1 = CoseSign1::from_tagged_slice(envelope).map_err(CoseEnvelopeError::CoseError)?;
+    if sign1.protected.header.alg != Some(coset::Algorithm::Assigned(iana::Algorithm::EdDSA)) {
+        return Err(CoseEnvelopeError::UnsupportedAlgorithm);
+    }
+    sign1
+        .verify_signature(b"", |signature, tbs| {
+            key.verify_signature(tbs, signature).then_some(()).ok_or(())
+        })
+        .map_err(|_| CoseEnvelopeError::SignatureError)?;
+    let payload = sign1.payload.ok_or(CoseEnvelopeError::MissingPayload)?;
+    ciborium::from_reader(payload.as_slice())
+        .map_err(|e| CoseEnvelopeError::CborError(format!("{:?}", e)))
+}
+
+///Signature of the envelope, e.g. to recognize a replayed report.
+///Not authenticated until the envelope is opened.
+pub fn envelope_signature(envelope: &[u8]) -> Result<Vec<u8>, CoseEnvelopeError> {
+    let sign1 = CoseSign1::from_tagged_slice(envelope).map_err(CoseEnvelopeError::CoseError)?;
+    Ok(sign1.signature)
+}
+
+///Key id from the protected header, used to pick the verification key.
+///Not authenticated until the envelope is opened with that key.
+pub fn envelope_key_id(envelope: &[u8]) -> Result<KeyId, CoseEnvelopeError> {
+    let sign1 = CoseSign1::from_tagged_slice(envelope).map_err(CoseEnvelopeError::CoseError)?;
+    String::from_utf8(sign1.protected.header.key_id)
+        .map(KeyId)
+        .map_err(|_| CoseEnvelopeError::MalformedClaims)
+}
+
+#[cfg(feature="std")]
+///Signs a v2 or later certificate as an envelope. The key id is the certificate issuer.
+pub fn sign_certificate(
+    certificate: &TahiniCertificate,
+    key: &Ed25519KeyPair,
+) -> Result<Vec<u8>, CoseEnvelopeError> {
+    let claims = CertificateClaims::try_from(certificate)?;
+    sign1(&claims, &KeyId(claims.issuer.clone()), key)
+}
+
+pub fn open_certificate(
+    envelope: &[u8],
+    key: &impl SignatureVerifier,
+) -> Result<CertificateClaims, CoseEnvelopeError> {
+    open1(envelope, key)
+}
+
+#[cfg(feature="std")]
+///Signs an attestation report as an envelope embedding the certificate envelope.
+pub fn sign_report(
+    data: &DynamicAttestationData,
+    certificate_envelope: &[u8],
+    key_id: &KeyId,
+    key: &Ed25519KeyPair,
+) -> Result<Vec<u8>, CoseEnvelopeError> {
+    let claims = ReportClaims {
+        certificate: certificate_envelope.to_vec(),
+        nonce: data.nonce.to_be_bytes().to_vec(),
+        service_name: data.service_name.0.clone(),
+        current_bin_hash: hex::decode(&data.current_bin_hash.0)
+            .map_err(|_| CoseEnvelopeError::MalformedClaims)?,
+        client_key_share: data.client_key_share.clone(),
+        server_key_share: data.server_key_share.clone(),
+        client_id: usize::from(data.client_id.clone()) as u64,
+    };
+    sign1(&claims, key_id, key)
+}
+
+pub fn open_report(
+    envelope: &[u8],
+    key: &impl SignatureVerifier,
+) -> Result<ReportClaims, CoseEnvelopeError> {
+    open1(envelope, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{KeyPair, UnparsedPublicKey, ED25519};
+
+    use super::*;
+    use crate::types::{
+        BinHash, CertificateVersion, ClientId, IssuanceInfo, PolicyHash, ServiceName, Signature,
+    };
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn public_key(key: &Ed25519KeyPair) -> UnparsedPublicKey<Vec<u8>> {
+        UnparsedPublicKey::new(&ED25519, key.public_key().as_ref().to_vec())
+    }
+
+    fn certificate() -> TahiniCertificate {
+        TahiniCertificate {
+            version: CertificateVersion::V2,
+            service_name: ServiceName("hoodini-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: Some(IssuanceInfo {
+                issuer: KeyId("issuer".to_string()),
+                issued_at: 1_700_000_000,
+                expires_at: 1_800_000_000,
+                toolchain: ToolchainInfo {
+                    generator: "certificate_generation 0.1.0".to_string(),
+                    rustc: "rustc 1.87.0".to_string(),
+                },
+            }),
+            policy_manifest: None,
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        }
+    }
+
+    //Envelope with the same claims and signature scheme, declaring another algorithm
+    fn sign1_with(alg: iana::Algorithm, payload: Vec<u8>, key: &Ed25519KeyPair) -> Vec<u8> {
+        CoseSign1Builder::new()
+            .protected(HeaderBuilder::new().algorithm(alg).build())
+            .payload(payload)
+            .create_signature(b"", |tbs| key.sign(tbs).as_ref().to_vec())
+            .build()
+            .to_tagged_vec()
+            .unwrap()
+    }
+
+    #[test]
+    fn certificates_round_trip() {
+        let key = key_pair();
+        let envelope = sign_certificate(&certificate(), &key).unwrap();
+        let claims = open_certificate(&envelope, &public_key(&key)).unwrap();
+        assert_eq!(claims, CertificateClaims::try_from(&certificate()).unwrap());
+        assert_eq!(envelope_key_id(&envelope).unwrap(), KeyId("issuer".to_string()));
+        assert_eq!(envelope_signature(&envelope).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn reports_round_trip() {
+        let (issuer, sidecar) = (key_pair(), key_pair());
+        let certificate = certificate();
+        let certificate_envelope = sign_certificate(&certificate, &issuer).unwrap();
+        let data = DynamicAttestationData {
+            cert: &certificate,
+            nonce: 42,
+            service_name: ServiceName("hoodini-server".to_string()),
+            current_bin_hash: BinHash("02".repeat(32)),
+            client_key_share: vec![3; 32],
+            server_key_share: vec![4; 32],
+            client_id: ClientId::from(7),
+        };
+        let envelope =
+            sign_report(&data, &certificate_envelope, &KeyId("sidecar".to_string()), &sidecar)
+                .unwrap();
+        let claims = open_report(&envelope, &public_key(&sidecar)).unwrap();
+        assert_eq!(
+            claims,
+            ReportClaims {
+                certificate: certificate_envelope,
+                nonce: 42u128.to_be_bytes().to_vec(),
+                service_name: "hoodini-server".to_string(),
+                current_bin_hash: vec![2; 32],
+                client_key_share: vec![3; 32],
+                server_key_share: vec![4; 32],
+                client_id: 7,
+            }
+        );
+        assert!(open_certificate(&claims.certificate, &public_key(&issuer)).is_ok());
+    }
+
+    #[test]
+    fn other_algorithms_are_refused() {
+        let key = key_pair();
+        let mut payload = Vec::new();
+        ciborium::into_writer(&CertificateClaims::try_from(&certificate()).unwrap(), &mut payload)
+            .unwrap();
+        let envelope = sign1_with(iana::Algorithm::ES256, payload.clone(), &key);
+        assert!(matches!(
+            open_certificate(&envelope, &public_key(&key)),
+            Err(CoseEnvelopeError::UnsupportedAlgorithm)
+        ));
+        //Same payload and key, declaring EdDSA
+        let envelope = sign1_with(iana::Algorithm::EdDSA, payload, &key);
+        assert!(open_certificate(&envelope, &public_key(&key)).is_ok());
+    }
+
+    #[test]
+    fn tampered_envelopes_are_refused() {
+        let key = key_pair();
+        let envelope = sign_certificate(&certificate(), &key).unwrap();
+        let mut sign1 = CoseSign1::from_tagged_slice(&envelope).unwrap();
+        let mut changed = certificate();
+        changed.policy_hash = PolicyHash("03".repeat(32));
+        let mut payload = Vec::new();
+        ciborium::into_writer(&CertificateClaims::try_from(&changed).unwrap(), &mut payload)
+            .unwrap();
+        sign1.payload = Some(payload);
+        let tampered = sign1.to_tagged_vec().unwrap();
+        assert!(matches!(
+            open_certificate(&tampered, &public_key(&key)),
+            Err(CoseEnvelopeError::SignatureError)
+        ));
+
+        assert!(matches!(
+            open_certificate(&envelope, &public_key(&key_pair())),
+            Err(CoseEnvelopeError::SignatureError)
+        ));
+        assert!(matches!(
+            open_certificate(&envelope[1..], &public_key(&key)),
+            Err(CoseEnvelopeError::CoseError(_))
+        ));
+    }
+
+    #[test]
+    fn v1_certificates_have_no_envelope() {
+        let mut v1 = certificate();
+        v1.version = CertificateVersion::V1;
+        v1.issuance = None;
+        assert!(matches!(
+            sign_certificate(&v1, &key_pair()),
+            Err(CoseEnvelopeError::MalformedClaims)
+        ));
+    }
+
+    //Tag 18, protected header {1: -8, 4: "issuer"}, CBOR claims of certificate(), signature
+    const CERTIFICATE_ENVELOPE: &str = concat!(
+        "d2844ba201270446697373756572a058f5a86776657273696f6e026c736572766963655f6e616d65",
+        "6e686f6f64696e692d7365727665726b706f6c6963795f6861736858200101010101010101010101",
+        "0101010101010101010101010101010101010101016b62696e6172795f6861736858200202020202",
+        "02020202020202020202020202020202020202020202020202020266697373756572666973737565",
+        "72696973737565645f61741a6553f1006a657870697265735f61741a6b49d20069746f6f6c636861",
+        "696ea26967656e657261746f72781c63657274696669636174655f67656e65726174696f6e20302e",
+        "312e306572757374636c727573746320312e38372e3058405e035a26f2c3c6f3a7df53f13a60c523",
+        "08f46a0e082c47c66d8a3ce51ea7bf733c3baf83ffaa82badb8de60c2d860dd01c33a7305caee2eb",
+        "2cbea6b5b5d98f0f",
+    );
+
+    //Ed25519 signatures are deterministic, so the envelope of a fixed certificate and key is
+    //fixed as well. Other COSE implementations must read the same bytes
+    #[test]
+    fn certificate_envelope_matches_test_vector() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let envelope = sign_certificate(&certificate(), &key).unwrap();
+        assert_eq!(hex::encode(&envelope), CERTIFICATE_ENVELOPE);
+        let claims =
+            open_certificate(&hex::decode(CERTIFICATE_ENVELOPE).unwrap(), &public_key(&key)).unwrap();
+        assert_eq!(claims, CertificateClaims::try_from(&certificate()).unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use aws_lc_rs::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use pkcs8::{der::pem, ObjectIdentifier, PrivateKeyInfo};
+use spki::SubjectPublicKeyInfoRef;
+use std::{fs::File, io::Read, path::Path};
+
+///Algorithm identifier of Ed25519 keys (RFC 8410).
+pub const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+const ED25519_KEY_LEN: usize = 32;
+const PUBLIC_KEY_LABEL: &str = "PUBLIC KEY";
+const PRIVATE_KEY_LABEL: &str = "PRIVATE KEY";
+
+#[derive(Debug)]
+pub enum KeyError {
+    IoError(std::io::Error),
+    PemError(pem::Error),
+    //File is PEM, but not of the expected kind (e.g. a private key given as a public key)
+    UnexpectedPemLabel(String),
+    SpkiError(spki::Error),
+    Pkcs8Error(pkcs8::Error),
+    UnsupportedAlgorithm(ObjectIdentifier),
+    //Structure parsed, but the key bytes are not a valid Ed25519 key
+    InvalidKeyMaterial,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::IoError(_) => write!(f, "could not read key file"),
+            KeyError::PemError(e) => write!(f, "malformed PEM: {}", e),
+            KeyError::UnexpectedPemLabel(label) => write!(f, "unexpected PEM label {}", label),
+            KeyError::SpkiError(_) => write!(f, "malformed SubjectPublicKeyInfo"),
+            KeyError::Pkcs8Error(_) => write!(f, "malformed PKCS#8 document"),
+            KeyError::UnsupportedAlgorithm(oid) => write!(f, "unsupported key algorithm {}", oid),
+            KeyError::InvalidKeyMaterial => write!(f, "invalid Ed25519 key material"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::IoError(e) => Some(e),
+            KeyError::SpkiError(e) => Some(e),
+            KeyError::Pkcs8Error(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, KeyError> {
+    let mut file = File::open(path).map_err(KeyError::IoError)?;
+    let mut contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(KeyError::IoError)?;
+    Ok(contents)
+}
+
+//Returns DER bytes, decoding them first if the input is PEM with the expected label.
+fn to_der(bytes: &[u8], expected_label: &str) -> Result<Vec<u8>, KeyError> {
+    if !bytes.starts_with(b"-----BEGIN") {
+        return Ok(bytes.to_vec());
+    }
+    let (label, der) = pem::decode_vec(bytes).map_err(KeyError::PemError)?;
+    if label != expected_label {
+        return Err(KeyError::UnexpectedPemLabel(label.to_string()));
+    }
+    Ok(der)
+}
+
+///Extracts raw Ed25519 public key material from a SubjectPublicKeyInfo, in DER or PEM.
+pub fn parse_public_key(bytes: &[u8]) -> Result<Vec<u8>, KeyError> {
+    let der = to_der(bytes, PUBLIC_KEY_LABEL)?;
+    let spki = SubjectPublicKeyInfoRef::try_from(der.as_slice()).map_err(KeyError::SpkiError)?;
+    if spki.algorithm.oid != ED25519_OID {
+        return Err(KeyError::UnsupportedAlgorithm(spki.algorithm.oid));
+    }
+    let key_material = spki
+        .subject_public_key
+        .as_bytes()
+        .ok_or(KeyError::InvalidKeyMaterial)?;
+    if key_material.len() != ED25519_KEY_LEN {
+        return Err(KeyError::InvalidKeyMaterial);
+    }
+    Ok(key_material.to_vec())
+}
+
+///Parses an Ed25519 signing key from a PKCS#8 document, in DER or PEM.
+pub fn parse_signing_key(bytes: &[u8]) -> Result<Ed25519KeyPair, KeyError> {
+    let der = to_der(bytes, PRIVATE_KEY_LABEL)?;
+    let info = PrivateKeyInfo::try_from(der.as_slice()).map_err(KeyError::Pkcs8Error)?;
+    if info.algorithm.oid != ED25519_OID {
+        return Err(KeyError::UnsupportedAlgorithm(info.algorithm.oid));
+    }
+    Ed25519KeyPair::from_pkcs8(&der).map_err(|_| KeyError::InvalidKeyMaterial)
+}
+
+///Reads raw Ed25519 public key material from a SubjectPublicKeyInfo file.
+pub fn load_public_key_material(path: &Path) -> Result<Vec<u8>, KeyError> {
+    parse_public_key(&read_file(path)?)
+}
+
+///Reads an Ed25519 verification key from a SubjectPublicKeyInfo file.
+pub fn load_public_key(path: &Path) -> Result<UnparsedPublicKey<Vec<u8>>, KeyError> {
+    Ok(UnparsedPublicKey::new(
+        &ED25519,
+        load_public_key_material(path)?,
+    ))
+}
+
+///Reads an Ed25519 signing key from a PKCS#8 file.
+pub fn load_signing_key(path: &Path) -> Result<Ed25519KeyPair, KeyError> {
+    parse_signing_key(&read_file(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lc_rs::signature::KeyPair;
+    use std::path::PathBuf;
+
+    //Generated with openssl genpkey, except the v2 document, which adds the public key of the
+    //same key pair to the v1 one
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/keys").join(file)
+    }
+
+    fn public_key() -> Vec<u8> {
+        load_public_key_material(&testdata("ed25519_public.pem")).unwrap()
+    }
+
+    #[test]
+    fn public_keys_are_read_from_pem_and_der() {
+        assert_eq!(public_key().len(), ED25519_KEY_LEN);
+        assert_eq!(load_public_key_material(&testdata("ed25519_public.der")).unwrap(), public_key());
+    }
+
+    #[test]
+    fn signing_keys_are_read_from_pkcs8_v1_and_v2() {
+        for file in ["ed25519_pkcs8_v1.pem", "ed25519_pkcs8_v1.der", "ed25519_pkcs8_v2.der"] {
+            let key = load_signing_key(&testdata(file)).unwrap();
+            assert_eq!(key.public_key().as_ref(), public_key().as_slice(), "{}", file);
+            let signature = key.sign(b"message");
+            assert!(load_public_key(&testdata("ed25519_public.der"))
+                .unwrap()
+                .verify(b"message", signature.as_ref())
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn other_algorithms_are_rejected() {
+        let ec_public_key = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+        assert!(matches!(
+            load_public_key_material(&testdata("p256_public.pem")),
+            Err(KeyError::UnsupportedAlgorithm(oid)) if oid == ec_public_key
+        ));
+        assert!(matches!(
+            load_signing_key(&testdata("p256_private.pem")),
+            Err(KeyError::UnsupportedAlgorithm(oid)) if oid == ec_public_key
+        ));
+    }
+
+    #[test]
+    fn keys_of_the_wrong_kind_are_rejected() {
+        assert!(matches!(
+            load_public_key_material(&testdata("ed25519_pkcs8_v1.pem")),
+            Err(KeyError::UnexpectedPemLabel(label)) if label == PRIVATE_KEY_LABEL
+        ));
+        assert!(matches!(
+            load_signing_key(&testdata("ed25519_public.pem")),
+            Err(KeyError::UnexpectedPemLabel(label)) if label == PUBLIC_KEY_LABEL
+        ));
+        assert!(matches!(
+            parse_public_key(&std::fs::read(testdata("ed25519_pkcs8_v1.der")).unwrap()),
+            Err(KeyError::SpkiError(_))
+        ));
+        assert!(matches!(
+            load_signing_key(&testdata("missing.pem")),
+            Err(KeyError::IoError(_))
+        ));
+    }
+}
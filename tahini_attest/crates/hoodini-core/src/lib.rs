@@ -1,7 +1,49 @@
+#![cfg_attr(not(feature="std"), no_std)]
+
+extern crate alloc;
+
 pub mod types;
 
+pub mod canonical;
+
+#[cfg(feature="std")]
+pub mod keys;
+
+pub mod revocation;
+
+pub mod authority;
+
+pub mod transparency;
+
+pub mod cose;
+
+pub mod manifest;
+
+pub mod appraisal;
+
+pub mod replay;
+
+pub mod session;
+
+#[cfg(feature="std")]
+pub mod record;
+
+pub mod verify;
+
 #[cfg(feature="attest")]
 pub mod service;
 
+#[cfg(feature="attest")]
+pub mod codec;
+
 #[cfg(feature="attest")]
 pub mod certificate;
+
+#[cfg(feature="attest")]
+pub mod reload;
+
+#[cfg(feature="attest")]
+pub mod transport;
+
+#[cfg(feature="tls")]
+pub mod tls;
@@ -0,0 +1,138 @@
+//!Per-policy hashes of a binary, as dumped by `policy_signing`.
+//!
+//!The aggregate policy hash of a certificate only tells whether the whole policy set changed.
+//!Certificates from v3 onwards can also carry the manifest it was computed from, so that a
+//!client can check that a given `Policy` implementation is present, with the hash it expects.
+
+use alloc::{collections::BTreeMap, string::String};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
+
+///Same layout as the JSON `policy_signing` writes after the aggregate hash line. Maps are kept
+///sorted so that the manifest encodes the same way wherever it is read.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PolicyManifest {
+    //Crate name to the aggregate policy hash of that crate
+    pub dependency_hashes: BTreeMap<String, String>,
+    //Hash of the local implementations
+    pub local_summary_hash: String,
+    //Path of each local `impl Policy` block to the stable hash of its methods
+    pub local_impls_hashes: BTreeMap<String, String>,
+}
+
+impl PolicyManifest {
+    ///Hash of the implementation of `policy`, looked up by its exact impl path first, then by
+    ///the type the impl is for, e.g. `ConsentPolicy` for `<impl Policy for ConsentPolicy>`.
+    ///A type matching several impls, e.g. `ConsentPolicy` for both `a::ConsentPolicy` and
+    ///`b::ConsentPolicy`, is ambiguous and has no hash: it must be given by its impl path.
+    pub fn policy_hash(&self, policy: &str) -> Option<&str> {
+        if let Some(hash) = self.local_impls_hashes.get(policy) {
+            return Some(hash);
+        }
+        let mut impls = self
+            .local_impls_hashes
+            .iter()
+            .filter(|(path, _)| implements_for(path, policy));
+        match (impls.next(), impls.next()) {
+            (Some((_, hash)), None) => Some(hash.as_str()),
+            _ => None,
+        }
+    }
+
+    ///SHA-256 of the canonical encoding, which certificates sign.
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(self.signing_payload()).into()
+    }
+}
+
+//Whether the impl path is for the given type, written either as in the path or by its bare
+//name: `<impl Policy for a::ConsentPolicy<T>>` is for `ConsentPolicy`
+fn implements_for(path: &str, policy: &str) -> bool {
+    let Some((_, ty)) = path.rsplit_once(" for ") else {
+        return false;
+    };
+    let ty = ty.strip_suffix('>').unwrap_or(ty);
+    let without_generics = &ty[..ty.find('<').unwrap_or(ty.len())];
+    ty == policy || without_generics.rsplit("::").next() == Some(policy)
+}
+
+impl SigningPayload for PolicyManifest {
+    const DOMAIN: &'static [u8] = b"tahini-policy-manifest-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(&self.local_summary_hash)
+            .u32(self.local_impls_hashes.len() as u32);
+        for (path, hash) in self.local_impls_hashes.iter() {
+            encoder.str(path).str(hash);
+        }
+        encoder.u32(self.dependency_hashes.len() as u32);
+        for (name, hash) in self.dependency_hashes.iter() {
+            encoder.str(name).str(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn manifest(impls: &[(&str, &str)]) -> PolicyManifest {
+        PolicyManifest {
+            dependency_hashes: BTreeMap::new(),
+            local_summary_hash: "bb".repeat(32),
+            local_impls_hashes: impls
+                .iter()
+                .map(|(path, hash)| (path.to_string(), hash.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn policies_are_found_by_path_or_type() {
+        let manifest = manifest(&[
+            ("<impl Policy for ConsentPolicy>", "aa"),
+            ("<impl Policy for policies::RetentionPolicy<T>>", "cc"),
+        ]);
+        assert_eq!(manifest.policy_hash("<impl Policy for ConsentPolicy>"), Some("aa"));
+        assert_eq!(manifest.policy_hash("ConsentPolicy"), Some("aa"));
+        assert_eq!(manifest.policy_hash("RetentionPolicy"), Some("cc"));
+        assert_eq!(manifest.policy_hash("policies::RetentionPolicy<T>"), Some("cc"));
+        assert_eq!(manifest.policy_hash("Consent"), None);
+        assert_eq!(manifest.policy_hash("AccessPolicy"), None);
+    }
+
+    #[test]
+    fn ambiguous_types_have_no_hash() {
+        let manifest = manifest(&[
+            ("<impl Policy for a::ConsentPolicy>", "aa"),
+            ("<impl Policy for b::ConsentPolicy>", "cc"),
+        ]);
+        assert_eq!(manifest.policy_hash("ConsentPolicy"), None);
+        assert_eq!(manifest.policy_hash("a::ConsentPolicy"), Some("aa"));
+        assert_eq!(manifest.policy_hash("<impl Policy for b::ConsentPolicy>"), Some("cc"));
+    }
+
+    #[test]
+    fn digest_covers_every_hash() {
+        let manifest = manifest(&[("<impl Policy for ConsentPolicy>", "aa")]);
+        let mut changed = manifest.clone();
+        changed.local_summary_hash = "cc".repeat(32);
+        assert_ne!(changed.digest(), manifest.digest());
+
+        let mut changed = manifest.clone();
+        changed
+            .local_impls_hashes
+            .insert("<impl Policy for ConsentPolicy>".to_string(), "cc".to_string());
+        assert_ne!(changed.digest(), manifest.digest());
+
+        let mut changed = manifest.clone();
+        changed
+            .dependency_hashes
+            .insert("tahini_policies".to_string(), "aa".to_string());
+        assert_ne!(changed.digest(), manifest.digest());
+    }
+}
@@ -0,0 +1,443 @@
+//!Record layer sealing application messages with the session keys.
+//!
+//!Each direction of a connection has its own key and sequence number. The keys are those of the
+//!connection, see `SessionKeys::connection_keys`, and are consumed by the sealer and opener. A record is the big-endian
+//!sequence number followed by the AES-256-GCM ciphertext. The nonce is derived from the sequence
+//!number, and the additional data binds the direction, the client id and the sequence number,
+//!so a record is only accepted once, in order, on the session it was sealed for.
+
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use core::fmt;
+
+use crate::canonical::CanonicalEncoder;
+use crate::session::ConnectionKeys;
+use crate::types::ClientId;
+
+const RECORD_DOMAIN: &[u8] = b"tahini-record-v1";
+
+//Bytes of the sequence number heading each record
+const SEQUENCE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    fn tag(&self) -> u8 {
+        match self {
+            Direction::ClientToServer => 0,
+            Direction::ServerToClient => 1,
+        }
+    }
+}
+
+///Amount of data a key may protect before the session has to be rekeyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLimits {
+    pub max_records: u64,
+    //Plaintext bytes
+    pub max_bytes: u64,
+}
+
+impl Default for RecordLimits {
+    //Well within the AES-GCM usage limits for a single key
+    fn default() -> Self {
+        Self {
+            max_records: 1 << 32,
+            max_bytes: 1 << 36,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    //Record is too short to hold a sequence number and a tag
+    MalformedRecordError,
+    //Record was already opened
+    ReplayedRecordError { expected: u64, received: u64 },
+    //Records before this one were dropped or are yet to arrive
+    ReorderedRecordError { expected: u64, received: u64 },
+    //Record was tampered with, or sealed for another session or direction
+    RecordAuthenticationError,
+    //Key reached its record limits, the session has to be rekeyed
+    RekeyRequiredError,
+    CryptoError,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::MalformedRecordError => write!(f, "malformed record"),
+            RecordError::ReplayedRecordError { expected, received } => write!(
+                f,
+                "record {} was replayed, expected record {}",
+                received, expected
+            ),
+            RecordError::ReorderedRecordError { expected, received } => write!(
+                f,
+                "record {} arrived out of order, expected record {}",
+                received, expected
+            ),
+            RecordError::RecordAuthenticationError => write!(f, "record authentication failed"),
+            RecordError::RekeyRequiredError => {
+                write!(f, "session key reached its usage limits and must be rekeyed")
+            }
+            RecordError::CryptoError => write!(f, "cryptographic operation failed"),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl From<RecordError> for std::io::Error {
+    fn from(value: RecordError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, value)
+    }
+}
+
+pub type RecordResult<T> = Result<T, RecordError>;
+
+//State shared by both ends of a direction
+struct RecordState {
+    key: LessSafeKey,
+    direction: Direction,
+    client_id: ClientId,
+    limits: RecordLimits,
+    //Sequence number of the next record
+    sequence: u64,
+    bytes: u64,
+}
+
+impl RecordState {
+    fn new(
+        material: &[u8],
+        direction: Direction,
+        client_id: &ClientId,
+        limits: RecordLimits,
+    ) -> RecordResult<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, material).map_err(|_| RecordError::CryptoError)?;
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            direction,
+            client_id: client_id.clone(),
+            limits,
+            sequence: 0,
+            bytes: 0,
+        })
+    }
+
+    fn nonce(&self, sequence: u64) -> Nonce {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[0] = self.direction.tag();
+        nonce[NONCE_LEN - SEQUENCE_LEN..].copy_from_slice(&sequence.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn aad(&self, sequence: u64) -> Vec<u8> {
+        let mut encoder = CanonicalEncoder::new(RECORD_DOMAIN);
+        encoder
+            .u8(self.direction.tag())
+            .u64(usize::from(self.client_id.clone()) as u64)
+            .u64(sequence);
+        encoder.finish()
+    }
+
+    //Bytes protected by the key once a record of `len` plaintext bytes is added, failing if that
+    //exceeds the limits
+    fn reserve(&self, len: usize) -> RecordResult<u64> {
+        let bytes = self.bytes.saturating_add(len as u64);
+        if self.sequence >= self.limits.max_records || bytes > self.limits.max_bytes {
+            return Err(RecordError::RekeyRequiredError);
+        }
+        Ok(bytes)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.sequence >= self.limits.max_records || self.bytes >= self.limits.max_bytes
+    }
+}
+
+///Seals the records one end sends.
+pub struct RecordSealer {
+    state: RecordState,
+}
+
+impl RecordSealer {
+    pub fn new(
+        material: &[u8],
+        direction: Direction,
+        client_id: &ClientId,
+        limits: RecordLimits,
+    ) -> RecordResult<Self> {
+        RecordState::new(material, direction, client_id, limits).map(|state| Self { state })
+    }
+
+    pub fn seal(&mut self, message: &[u8]) -> RecordResult<Vec<u8>> {
+        let bytes = self.state.reserve(message.len())?;
+        let sequence = self.state.sequence;
+        let mut record = sequence.to_be_bytes().to_vec();
+        let mut in_out = message.to_vec();
+        self.state
+            .key
+            .seal_in_place_append_tag(
+                self.state.nonce(sequence),
+                Aad::from(self.state.aad(sequence)),
+                &mut in_out,
+            )
+            .map_err(|_| RecordError::CryptoError)?;
+        record.extend(in_out);
+        self.state.sequence += 1;
+        self.state.bytes = bytes;
+        Ok(record)
+    }
+
+    ///Sequence number of the next record.
+    pub fn sequence(&self) -> u64 {
+        self.state.sequence
+    }
+
+    ///Whether the key reached its limits. Sealing fails from then on.
+    pub fn is_exhausted(&self) -> bool {
+        self.state.is_exhausted()
+    }
+}
+
+///Opens the records one end receives, in the order they were sealed.
+pub struct RecordOpener {
+    state: RecordState,
+}
+
+impl RecordOpener {
+    pub fn new(
+        material: &[u8],
+        direction: Direction,
+        client_id: &ClientId,
+        limits: RecordLimits,
+    ) -> RecordResult<Self> {
+        RecordState::new(material, direction, client_id, limits).map(|state| Self { state })
+    }
+
+    pub fn open(&mut self, record: &[u8]) -> RecordResult<Vec<u8>> {
+        let tag_len = AES_256_GCM.tag_len();
+        if record.len() < SEQUENCE_LEN + tag_len {
+            return Err(RecordError::MalformedRecordError);
+        }
+        let (sequence, ciphertext) = record.split_at(SEQUENCE_LEN);
+        let mut sequence_bytes = [0u8; SEQUENCE_LEN];
+        sequence_bytes.copy_from_slice(sequence);
+        let received = u64::from_be_bytes(sequence_bytes);
+        let expected = self.state.sequence;
+        if received < expected {
+            return Err(RecordError::ReplayedRecordError { expected, received });
+        }
+        if received > expected {
+            return Err(RecordError::ReorderedRecordError { expected, received });
+        }
+        let bytes = self.state.reserve(ciphertext.len() - tag_len)?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext_len = self
+            .state
+            .key
+            .open_in_place(
+                self.state.nonce(received),
+                Aad::from(self.state.aad(received)),
+                &mut in_out,
+            )
+            .map_err(|_| RecordError::RecordAuthenticationError)?
+            .len();
+        in_out.truncate(plaintext_len);
+        self.state.sequence += 1;
+        self.state.bytes = bytes;
+        Ok(in_out)
+    }
+
+    ///Sequence number of the next expected record.
+    pub fn sequence(&self) -> u64 {
+        self.state.sequence
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.state.is_exhausted()
+    }
+}
+
+///Sealer for the requests and opener for the responses of a client.
+pub fn client_records(
+    keys: ConnectionKeys,
+    client_id: &ClientId,
+    limits: RecordLimits,
+) -> RecordResult<(RecordSealer, RecordOpener)> {
+    Ok((
+        RecordSealer::new(&keys.client_to_server, Direction::ClientToServer, client_id, limits)?,
+        RecordOpener::new(&keys.server_to_client, Direction::ServerToClient, client_id, limits)?,
+    ))
+}
+
+///Sealer for the responses and opener for the requests of a server.
+pub fn server_records(
+    keys: ConnectionKeys,
+    client_id: &ClientId,
+    limits: RecordLimits,
+) -> RecordResult<(RecordSealer, RecordOpener)> {
+    Ok((
+        RecordSealer::new(&keys.server_to_client, Direction::ServerToClient, client_id, limits)?,
+        RecordOpener::new(&keys.client_to_server, Direction::ClientToServer, client_id, limits)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{ClientSession, SessionKeys};
+
+    fn keys() -> SessionKeys {
+        SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        }
+    }
+
+    fn client(client_id: usize, limits: RecordLimits) -> (RecordSealer, RecordOpener) {
+        client_records(keys().connection_keys(0).unwrap(), &ClientId::from(client_id), limits)
+            .unwrap()
+    }
+
+    fn server(client_id: usize, limits: RecordLimits) -> (RecordSealer, RecordOpener) {
+        server_records(keys().connection_keys(0).unwrap(), &ClientId::from(client_id), limits)
+            .unwrap()
+    }
+
+    #[test]
+    fn connections_have_their_own_keys() {
+        let mut session = ClientSession::new(ClientId::from(3), keys());
+        let (first, first_keys) = session.next_connection().unwrap();
+        let (second, second_keys) = session.next_connection().unwrap();
+        assert_eq!((first, second), (0, 1));
+        let (mut first_sealer, _) =
+            client_records(first_keys, session.client_id(), RecordLimits::default()).unwrap();
+        let (mut second_sealer, _) =
+            client_records(second_keys, session.client_id(), RecordLimits::default()).unwrap();
+        //Same sequence number, so the same nonce, under different keys
+        let first_record = first_sealer.seal(b"request").unwrap();
+        let second_record = second_sealer.seal(b"request").unwrap();
+        assert_ne!(first_record, second_record);
+
+        let (_, mut opener) =
+            server_records(keys().connection_keys(1).unwrap(), &ClientId::from(3), RecordLimits::default())
+                .unwrap();
+        assert_eq!(opener.open(&first_record), Err(RecordError::RecordAuthenticationError));
+        assert_eq!(opener.open(&second_record).unwrap(), b"request");
+    }
+
+    #[test]
+    fn records_round_trip_both_ways() {
+        let (mut client_sealer, mut client_opener) = client(3, RecordLimits::default());
+        let (mut server_sealer, mut server_opener) = server(3, RecordLimits::default());
+
+        for message in [&b"request"[..], b"", b"second request"] {
+            let record = client_sealer.seal(message).unwrap();
+            assert_eq!(server_opener.open(&record).unwrap(), message);
+        }
+        let record = server_sealer.seal(b"response").unwrap();
+        assert_eq!(client_opener.open(&record).unwrap(), b"response");
+
+        assert_eq!(client_sealer.sequence(), 3);
+        assert_eq!(server_opener.sequence(), 3);
+        assert_eq!(server_sealer.sequence(), 1);
+    }
+
+    #[test]
+    fn replayed_record_is_rejected() {
+        let (mut sealer, _) = client(3, RecordLimits::default());
+        let (_, mut opener) = server(3, RecordLimits::default());
+        let record = sealer.seal(b"request").unwrap();
+        opener.open(&record).unwrap();
+        assert_eq!(
+            opener.open(&record),
+            Err(RecordError::ReplayedRecordError {
+                expected: 1,
+                received: 0
+            })
+        );
+    }
+
+    #[test]
+    fn reordered_record_is_rejected() {
+        let (mut sealer, _) = client(3, RecordLimits::default());
+        let (_, mut opener) = server(3, RecordLimits::default());
+        let first = sealer.seal(b"first").unwrap();
+        let second = sealer.seal(b"second").unwrap();
+        assert_eq!(
+            opener.open(&second),
+            Err(RecordError::ReorderedRecordError {
+                expected: 0,
+                received: 1
+            })
+        );
+        //Rejected records leave the opener where it was
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+        assert_eq!(opener.open(&second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn record_of_another_client_is_rejected() {
+        let (mut sealer, _) = client(3, RecordLimits::default());
+        let (_, mut opener) = server(4, RecordLimits::default());
+        let record = sealer.seal(b"request").unwrap();
+        assert_eq!(opener.open(&record), Err(RecordError::RecordAuthenticationError));
+    }
+
+    #[test]
+    fn reflected_record_is_rejected() {
+        let (mut sealer, mut opener) = client(3, RecordLimits::default());
+        let record = sealer.seal(b"request").unwrap();
+        assert_eq!(opener.open(&record), Err(RecordError::RecordAuthenticationError));
+    }
+
+    #[test]
+    fn tampered_or_truncated_record_is_rejected() {
+        let (mut sealer, _) = client(3, RecordLimits::default());
+        let (_, mut opener) = server(3, RecordLimits::default());
+        let mut record = sealer.seal(b"request").unwrap();
+        assert_eq!(
+            opener.open(&record[..SEQUENCE_LEN + 4]),
+            Err(RecordError::MalformedRecordError)
+        );
+        record[SEQUENCE_LEN] ^= 1;
+        assert_eq!(opener.open(&record), Err(RecordError::RecordAuthenticationError));
+    }
+
+    #[test]
+    fn record_limit_requires_rekey() {
+        let limits = RecordLimits {
+            max_records: 2,
+            max_bytes: 1 << 20,
+        };
+        let (mut sealer, _) = client(3, limits);
+        let (_, mut opener) = server(3, RecordLimits { max_records: 1, ..limits });
+        let first = sealer.seal(b"first").unwrap();
+        let second = sealer.seal(b"second").unwrap();
+        assert!(sealer.is_exhausted());
+        assert_eq!(sealer.seal(b"third"), Err(RecordError::RekeyRequiredError));
+
+        opener.open(&first).unwrap();
+        assert!(opener.is_exhausted());
+        assert_eq!(opener.open(&second), Err(RecordError::RekeyRequiredError));
+    }
+
+    #[test]
+    fn byte_limit_requires_rekey() {
+        let limits = RecordLimits {
+            max_records: 1 << 20,
+            max_bytes: 10,
+        };
+        let (mut sealer, _) = client(3, limits);
+        sealer.seal(&[0; 8]).unwrap();
+        assert_eq!(sealer.seal(&[0; 3]), Err(RecordError::RekeyRequiredError));
+        sealer.seal(&[0; 2]).unwrap();
+        assert!(sealer.is_exhausted());
+    }
+}
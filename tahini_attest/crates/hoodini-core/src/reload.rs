@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::RwLock;
+
+use crate::certificate::CertificateLoader;
+use crate::types::AttestResult;
+
+//Last modification time and length of a file, None if it could not be read
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+///Polls a set of files for changes.
+pub struct FileWatcher {
+    stamps: HashMap<PathBuf, FileStamp>,
+}
+
+impl FileWatcher {
+    pub fn new(files: &[PathBuf]) -> Self {
+        Self {
+            stamps: files.iter().map(|path| (path.clone(), stamp(path))).collect(),
+        }
+    }
+
+    ///Whether any watched file changed since the previous call, or since the watcher was created.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in self.stamps.iter_mut() {
+            let current = stamp(path);
+            if current != *last {
+                *last = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    ///Replaces the set of watched files, e.g. after the config started referring to new ones.
+    pub fn watch(&mut self, files: &[PathBuf]) {
+        *self = Self::new(files);
+    }
+}
+
+///Rebuilds the loader from its config. The new loader is swapped in only once it was fully built
+///and validated, so a failed reload keeps the last good state.
+pub async fn reload_config(
+    config_path: &Path,
+    loader: &RwLock<CertificateLoader>,
+) -> AttestResult<()> {
+    let reloaded = CertificateLoader::from_config(config_path)?;
+    *loader.write().await = reloaded;
+    Ok(())
+}
+
+///Reloads the loader whenever the config, or a file it refers to, changes, and passes the outcome
+///of each reload to `report`.
+pub async fn watch_config(
+    config_path: PathBuf,
+    loader: Arc<RwLock<CertificateLoader>>,
+    interval: Duration,
+    mut report: impl FnMut(AttestResult<()>) + Send,
+) {
+    let mut watcher = FileWatcher::new(loader.read().await.sources());
+    loop {
+        tokio::time::sleep(interval).await;
+        if !watcher.changed() {
+            continue;
+        }
+        let reloaded = reload_config(&config_path, &loader).await;
+        if reloaded.is_ok() {
+            watcher.watch(loader.read().await.sources());
+        }
+        report(reloaded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys;
+    use crate::types::{BinHash, BinaryName, PolicyHash, ServiceName, Signature, TahiniCertificate};
+
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/keys").join(file)
+    }
+
+    //v1 certificate of hoodini-server, signed with the testdata key
+    fn write_certificate(path: &Path) {
+        let key = keys::load_signing_key(&testdata("ed25519_pkcs8_v1.pem")).unwrap();
+        let mut certificate = TahiniCertificate {
+            version: crate::types::CertificateVersion::V1,
+            service_name: ServiceName("hoodini-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: None,
+            policy_manifest: None,
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        };
+        certificate.signature = key.sign(&certificate.signed_data().unwrap()).into();
+        fs::write(path, serde_json::to_vec(&certificate).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_reloads_keep_the_previous_certificates() {
+        let dir = std::env::temp_dir().join(format!("hoodini-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (config_path, certificate_path) = (dir.join("config.toml"), dir.join("server.json"));
+        write_certificate(&certificate_path);
+        let config = format!(
+            "allow_legacy_certificates = true\nkeys = {:?}\n[certificates]\nhoodini-server = {:?}\n",
+            testdata("ed25519_public.pem"),
+            certificate_path,
+        );
+        fs::write(&config_path, &config).unwrap();
+        let loader = RwLock::new(CertificateLoader::from_config(&config_path).unwrap());
+        let server = BinaryName::from("hoodini-server".to_string());
+        assert!(loader.read().await.get_binary_certificate(&server).is_some());
+
+        //Certificate no longer parses
+        fs::write(&certificate_path, "{}").unwrap();
+        assert!(reload_config(&config_path, &loader).await.is_err());
+        assert!(loader.read().await.get_binary_certificate(&server).is_some());
+
+        //Config no longer parses
+        fs::write(&config_path, "certificates = ").unwrap();
+        assert!(reload_config(&config_path, &loader).await.is_err());
+        assert!(loader.read().await.get_binary_certificate(&server).is_some());
+
+        //Both fixed, and the certificate dropped from the config
+        fs::write(&config_path, config.replace("hoodini-server = ", "#")).unwrap();
+        assert!(reload_config(&config_path, &loader).await.is_ok());
+        assert!(loader.read().await.get_binary_certificate(&server).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//!Bounded memory of the attestation reports a client already accepted.
+//!
+//!Reports answer a fresh nonce, so a replayed one already fails the nonce check. Remembering
+//!report signatures also catches a sidecar, or anything relaying its reports, handing the same
+//!report out twice.
+
+use alloc::{collections::{BTreeSet, VecDeque}, vec::Vec};
+
+pub const DEFAULT_REPLAY_CACHE_SIZE: usize = 1024;
+
+///Remembers the signatures of the last `capacity` reports, forgetting the oldest first.
+#[derive(Debug, Clone)]
+pub struct ReplayCache {
+    capacity: usize,
+    //Insertion order, to know which signature to forget
+    order: VecDeque<Vec<u8>>,
+    seen: BTreeSet<Vec<u8>>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    ///Records the signature of an accepted report. Returns false if it was already recorded,
+    ///i.e. the report is a replay.
+    ///Only call this once the signature verified, so that forged reports can't evict real ones.
+    pub fn insert(&mut self, signature: &[u8]) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.seen.contains(signature) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(signature.to_vec());
+        self.seen.insert(signature.to_vec());
+        true
+    }
+
+    pub fn contains(&self, signature: &[u8]) -> bool {
+        self.seen.contains(signature)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REPLAY_CACHE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_are_detected() {
+        let mut cache = ReplayCache::new(4);
+        assert!(cache.insert(b"first"));
+        assert!(cache.insert(b"second"));
+        assert!(!cache.insert(b"first"));
+        assert!(cache.contains(b"second"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn oldest_signatures_are_forgotten_first() {
+        let mut cache = ReplayCache::new(2);
+        assert!(cache.insert(b"first"));
+        assert!(cache.insert(b"second"));
+        assert!(cache.insert(b"third"));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(b"first"));
+        assert!(!cache.insert(b"second"));
+        assert!(!cache.insert(b"third"));
+        //Forgotten, so no longer detected
+        assert!(cache.insert(b"first"));
+        assert!(!cache.contains(b"second"));
+    }
+
+    #[test]
+    fn empty_cache_remembers_nothing() {
+        let mut cache = ReplayCache::new(0);
+        assert!(cache.insert(b"first"));
+        assert!(cache.insert(b"first"));
+        assert!(!cache.contains(b"first"));
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+use alloc::vec::Vec;
+#[cfg(feature="std")]
+use aws_lc_rs::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
+use crate::types::{BinHash, CertificateId, KeyId, PolicyHash, Signature, TahiniCertificate};
+
+///Something a revocation list withdraws trust from.
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum RevokedEntry {
+    //Every certificate for this binary
+    BinaryHash(BinHash),
+    //Every certificate attesting to this policy set
+    PolicyHash(PolicyHash),
+    //A single certificate
+    CertificateId(CertificateId),
+}
+
+impl RevokedEntry {
+    fn tag(&self) -> u8 {
+        match self {
+            RevokedEntry::BinaryHash(_) => 0,
+            RevokedEntry::PolicyHash(_) => 1,
+            RevokedEntry::CertificateId(_) => 2,
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            RevokedEntry::BinaryHash(hash) => &hash.0,
+            RevokedEntry::PolicyHash(hash) => &hash.0,
+            RevokedEntry::CertificateId(id) => &id.0,
+        }
+    }
+
+    pub fn matches(&self, certificate: &TahiniCertificate) -> bool {
+        match self {
+            RevokedEntry::BinaryHash(hash) => &certificate.binary_hash == hash,
+            RevokedEntry::PolicyHash(hash) => &certificate.policy_hash == hash,
+            RevokedEntry::CertificateId(id) => certificate.id().is_ok_and(|cert_id| &cert_id == id),
+        }
+    }
+}
+
+///List of revoked certificates, signed by one of the root certificate issuer keys.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RevocationList {
+    pub issuer: KeyId,
+    //Seconds since UNIX epoch
+    pub issued_at: u64,
+    pub entries: Vec<RevokedEntry>,
+    pub signature: Signature,
+}
+
+impl RevocationList {
+    #[cfg(feature="std")]
+    ///Builds and signs a revocation list.
+    pub fn new(
+        issuer: KeyId,
+        issued_at: u64,
+        entries: Vec<RevokedEntry>,
+        key: &Ed25519KeyPair,
+    ) -> Self {
+        let mut list = Self {
+            issuer,
+            issued_at,
+            entries,
+            signature: Signature(String::new()),
+        };
+        list.signature = key.sign(&list.signing_payload()).into();
+        list
+    }
+}
+
+impl SigningPayload for RevocationList {
+    const DOMAIN: &'static [u8] = b"tahini-revocation-list-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(&self.issuer.0)
+            .u64(self.issued_at)
+            .u32(self.entries.len() as u32);
+        for entry in self.entries.iter() {
+            encoder.u8(entry.tag()).str(entry.value());
+        }
+    }
+}
@@ -1,14 +1,28 @@
-use crate::types::ServiceName;
-use aws_lc_rs::{
-    agreement::{self, EphemeralPrivateKey, PublicKey, UnparsedPublicKey, agree_ephemeral},
-    error::Unspecified,
-    kdf::{get_sskdf_hmac_algorithm, sskdf_hmac},
-};
+use crate::session::{CallerProof, RekeyRequest};
+use crate::types::{AttestationFailure, DynamicAttestationReport, ServiceName, TahiniCertificate};
+use aws_lc_rs::agreement::{self, EphemeralPrivateKey, PublicKey};
+
+//Byte strings in compact codecs, instead of arrays of integers
+pub use serde_bytes::ByteBuf;
 
 #[tarpc::service]
 pub trait AttestationService {
-    //FIXME: Add sidecar keyshare + client_id to the attestation report
-    async fn attest_binary(service_name: ServiceName, nonce: u128, key_share: Vec<u8>) -> crate::types::DynamicAttestationReport;
+    ///The report is signed over the client key share, the sidecar key share and the client id.
+    ///Fails with a stable code instead of a report when the service can't be attested.
+    ///A caller launched by the sidecar may prove who it is, for the server to learn it along with
+    ///the session keys.
+    async fn attest_binary(service_name: ServiceName, nonce: u128, key_share: ByteBuf, caller: Option<CallerProof>) -> Result<DynamicAttestationReport, AttestationFailure>;
+    ///Same attestation as attest_binary, returned as a COSE_Sign1 envelope (see crate::cose)
+    ///embedding the issuer-signed certificate envelope.
+    async fn attest_binary_cose(service_name: ServiceName, nonce: u128, key_share: ByteBuf, caller: Option<CallerProof>) -> Result<ByteBuf, AttestationFailure>;
+    ///Attests the service again for a live session, replacing its keys and extending it.
+    ///Fails with RekeyRefusedError if the session is unknown or expired, or the proof of the
+    ///current keys is wrong.
+    async fn rekey(request: RekeyRequest) -> Result<DynamicAttestationReport, AttestationFailure>;
+    ///Certificate the sidecar holds for a service, if any.
+    async fn get_certificate(service_name: ServiceName) -> Option<TahiniCertificate>;
+    ///Services the sidecar holds certificates for.
+    async fn list_services() -> Vec<ServiceName>;
 }
 
 pub fn compute_local_share() -> (EphemeralPrivateKey, PublicKey) {
@@ -17,28 +31,3 @@ pub fn compute_local_share() -> (EphemeralPrivateKey, PublicKey) {
     let pkey = skey.compute_public_key().unwrap();
     (skey, pkey)
 }
-
-//TODO: Add service name to key derivation
-pub fn derive_key_from_shares(local_skey: EphemeralPrivateKey, remote_share: Vec<u8>) -> Vec<u8> {
-    let pkey_peer = UnparsedPublicKey::new(&agreement::X25519, remote_share);
-    let a = [0u8; 32];
-    let info = "Sidecar_session".as_bytes();
-    let mut end_derived_key = [0u8; 32];
-    let alg_id = get_sskdf_hmac_algorithm(aws_lc_rs::kdf::SskdfHmacAlgorithmId::Sha256)
-        .ok_or(Unspecified)
-        .unwrap();
-    let usable_kdf =
-        |key_material: &[u8]| sskdf_hmac(alg_id, key_material, &info, &a, &mut end_derived_key);
-
-    let _ = agree_ephemeral(
-        local_skey,
-        &pkey_peer,
-        aws_lc_rs::error::Unspecified,
-        usable_kdf,
-    )
-    .expect("Key exchange failed");
-    end_derived_key.to_vec()
-
-    // let aes_key = RandomizedNonceKey::new(&AES_128_GCM, &end_derived_key).unwrap();
-    // aes_key
-}
@@ -0,0 +1,605 @@
+//!Session keys agreed during attestation.
+//!
+//!Both key shares go through an X25519 agreement, and the shared secret through a KDF salted with
+//!the hash of the attestation transcript. A session key is then only valid for the certificate,
+//!service, nonce and client id it was attested for. Each direction gets its own key.
+//!
+//!Records are not sealed with the session keys directly: each connection made with a session gets
+//!its own keys, derived from the session keys and the connection number, as record sequence
+//!numbers restart on every connection.
+//!
+//!Sessions expire, and may only be handed to the server a limited number of times. A client
+//!extends its session by rekeying through the sidecar, proving it holds the current keys.
+//!
+//!A client that is itself a binary launched by the sidecar may present the credential the sidecar
+//!handed it at launch. The server then learns which attested binary is calling.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+#[cfg(feature="std")]
+use aws_lc_rs::{
+    aead::{AES_256_GCM, RandomizedNonceKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, agree_ephemeral},
+    error::Unspecified,
+    hmac,
+    kdf::{get_sskdf_hmac_algorithm, sskdf_hmac, SskdfHmacAlgorithmId},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
+use crate::types::{
+    BinaryName, CertificateId, ClientId, DynamicAttestationData, ServiceName, TahiniCertificate,
+};
+
+///Version of the session key derivation. Part of the KDF info, so that keys derived by different
+///versions never collide.
+pub const SESSION_KDF_VERSION: &str = "tahini-session-kdf-v1";
+
+pub const SESSION_KEY_LEN: usize = 32;
+
+///Everything the sidecar attested to when handing out a session key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionTranscript {
+    pub certificate_id: CertificateId,
+    pub service_name: ServiceName,
+    pub nonce: u128,
+    pub client_id: ClientId,
+    pub client_key_share: Vec<u8>,
+    pub server_key_share: Vec<u8>,
+}
+
+impl SessionTranscript {
+    ///Transcript of the exchange a report attests to. Fails on certificates with non-hex hashes.
+    pub fn from_data(data: &DynamicAttestationData) -> Result<Self, hex::FromHexError> {
+        Ok(Self {
+            certificate_id: data.cert.id()?,
+            service_name: data.service_name.clone(),
+            nonce: data.nonce,
+            client_id: data.client_id.clone(),
+            client_key_share: data.client_key_share.clone(),
+            server_key_share: data.server_key_share.clone(),
+        })
+    }
+
+    ///SHA-256 of the canonical encoding, used as the KDF salt.
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(self.signing_payload()).into()
+    }
+}
+
+impl SigningPayload for SessionTranscript {
+    const DOMAIN: &'static [u8] = b"tahini-session-transcript-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(&self.certificate_id.0)
+            .str(&self.service_name.0)
+            .u128(self.nonce)
+            .u64(self.client_id.0 as u64)
+            .bytes(&self.client_key_share)
+            .bytes(&self.server_key_share);
+    }
+}
+
+///Key material of a session, one key per direction.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub client_to_server: [u8; SESSION_KEY_LEN],
+    pub server_to_client: [u8; SESSION_KEY_LEN],
+}
+
+impl SessionKeys {
+    ///Client-to-server key followed by the server-to-client key, as passed to the server.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.client_to_server.to_vec();
+        bytes.extend(self.server_to_client);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 2 * SESSION_KEY_LEN {
+            return None;
+        }
+        let (client_to_server, server_to_client) = bytes.split_at(SESSION_KEY_LEN);
+        Some(Self {
+            client_to_server: client_to_server.try_into().ok()?,
+            server_to_client: server_to_client.try_into().ok()?,
+        })
+    }
+
+    #[cfg(feature="std")]
+    //Key authenticating rekey requests, distinct from both record keys
+    fn rekey_key(&self) -> Result<hmac::Key, Unspecified> {
+        let mut material = [0u8; SESSION_KEY_LEN];
+        derive_direction(&self.to_bytes(), &[], "rekey", &mut material)?;
+        Ok(hmac::Key::new(hmac::HMAC_SHA256, &material))
+    }
+
+    #[cfg(feature="std")]
+    ///Proof that the client holds these keys, for a rekey request.
+    pub fn rekey_proof(&self, request: &RekeyRequest) -> Result<Vec<u8>, Unspecified> {
+        let tag = hmac::sign(&self.rekey_key()?, &request.signing_payload());
+        Ok(tag.as_ref().to_vec())
+    }
+
+    #[cfg(feature="std")]
+    pub fn check_rekey_proof(&self, request: &RekeyRequest) -> bool {
+        self.rekey_key()
+            .and_then(|key| hmac::verify(&key, &request.signing_payload(), &request.proof))
+            .is_ok()
+    }
+
+    #[cfg(feature="std")]
+    ///Record keys of one connection of the session. Records of every connection start at
+    ///sequence 0, so a connection number must only be used once per session: `ClientSession`
+    ///numbers the connections of a client, and servers refuse a number they already served.
+    pub fn connection_keys(&self, connection: u32) -> Result<ConnectionKeys, Unspecified> {
+        let session_keys = self.to_bytes();
+        let salt = connection.to_be_bytes();
+        let mut keys = ConnectionKeys {
+            client_to_server: [0u8; SESSION_KEY_LEN],
+            server_to_client: [0u8; SESSION_KEY_LEN],
+        };
+        derive_direction(&session_keys, &salt, "connection c2s", &mut keys.client_to_server)?;
+        derive_direction(&session_keys, &salt, "connection s2c", &mut keys.server_to_client)?;
+        Ok(keys)
+    }
+
+    #[cfg(feature="std")]
+    pub fn client_to_server_key(&self) -> Result<RandomizedNonceKey, Unspecified> {
+        RandomizedNonceKey::new(&AES_256_GCM, &self.client_to_server)
+    }
+
+    #[cfg(feature="std")]
+    pub fn server_to_client_key(&self) -> Result<RandomizedNonceKey, Unspecified> {
+        RandomizedNonceKey::new(&AES_256_GCM, &self.server_to_client)
+    }
+}
+
+//Key material is kept out of logs
+impl core::fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SessionKeys").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature="std")]
+///Record keys of a single connection, see SessionKeys::connection_keys. Not cloneable, and
+///consumed by the record layer, so that each set seals one sequence of records.
+pub struct ConnectionKeys {
+    pub(crate) client_to_server: [u8; SESSION_KEY_LEN],
+    pub(crate) server_to_client: [u8; SESSION_KEY_LEN],
+}
+
+#[cfg(feature="std")]
+impl core::fmt::Debug for ConnectionKeys {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ConnectionKeys").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature="std")]
+///Client end of a session, numbering the connections made with its keys.
+pub struct ClientSession {
+    client_id: ClientId,
+    keys: SessionKeys,
+    //Number of the next connection
+    next_connection: u32,
+}
+
+#[cfg(feature="std")]
+impl ClientSession {
+    ///Session as returned by `verify_binary`, or by a rekey, before any connection was made.
+    pub fn new(client_id: ClientId, keys: SessionKeys) -> Self {
+        Self {
+            client_id,
+            keys,
+            next_connection: 0,
+        }
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    ///Keys of the session, e.g. to prove them when rekeying.
+    pub fn keys(&self) -> &SessionKeys {
+        &self.keys
+    }
+
+    ///Number and record keys of a new connection. The server fetches the same keys from the
+    ///client id and this number, so both have to be sent to it before the first record.
+    pub fn next_connection(&mut self) -> Result<(u32, ConnectionKeys), Unspecified> {
+        let connection = self.next_connection;
+        self.next_connection = connection.checked_add(1).ok_or(Unspecified)?;
+        Ok((connection, self.keys.connection_keys(connection)?))
+    }
+}
+
+#[cfg(feature="std")]
+fn derive_direction(
+    shared_secret: &[u8],
+    transcript_digest: &[u8],
+    direction: &str,
+    out: &mut [u8; SESSION_KEY_LEN],
+) -> Result<(), Unspecified> {
+    let alg_id = get_sskdf_hmac_algorithm(SskdfHmacAlgorithmId::Sha256).ok_or(Unspecified)?;
+    let info = [SESSION_KDF_VERSION.as_bytes(), b" ", direction.as_bytes()].concat();
+    sskdf_hmac(alg_id, shared_secret, &info, transcript_digest, out)
+}
+
+#[cfg(feature="std")]
+///Completes the key agreement with the peer share, and derives both session keys bound to the
+///transcript. Client and sidecar derive the same keys from their own secret and the other share.
+pub fn derive_session_keys(
+    local_skey: EphemeralPrivateKey,
+    remote_share: &[u8],
+    transcript: &SessionTranscript,
+) -> Result<SessionKeys, Unspecified> {
+    let pkey_peer = UnparsedPublicKey::new(&agreement::X25519, remote_share.to_vec());
+    agree_ephemeral(local_skey, &pkey_peer, Unspecified, |shared_secret| {
+        keys_from_secret(shared_secret, transcript)
+    })
+}
+
+#[cfg(feature="std")]
+fn keys_from_secret(
+    shared_secret: &[u8],
+    transcript: &SessionTranscript,
+) -> Result<SessionKeys, Unspecified> {
+    let transcript_digest = transcript.digest();
+    let mut keys = SessionKeys {
+        client_to_server: [0u8; SESSION_KEY_LEN],
+        server_to_client: [0u8; SESSION_KEY_LEN],
+    };
+    derive_direction(shared_secret, &transcript_digest, "c2s", &mut keys.client_to_server)?;
+    derive_direction(shared_secret, &transcript_digest, "s2c", &mut keys.server_to_client)?;
+    Ok(keys)
+}
+
+//Session keys are only serialized inside the KEK-encrypted messages of the sidecar channel
+fn serialize_keys<S: Serializer>(keys: &SessionKeys, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(keys.to_bytes()))
+}
+
+fn deserialize_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SessionKeys, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    hex::decode(encoded)
+        .ok()
+        .and_then(|bytes| SessionKeys::from_bytes(&bytes))
+        .ok_or_else(|| serde::de::Error::custom("malformed session keys"))
+}
+
+///How long, and how many times, the server may use a session before the client has to rekey.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimits {
+    //Seconds
+    pub ttl: u64,
+    //Number of times the server may fetch the session keys
+    pub budget: u32,
+}
+
+impl Default for SessionLimits {
+    //A session is fetched once, as before sessions could be reused
+    fn default() -> Self {
+        Self {
+            ttl: 3600,
+            budget: 1,
+        }
+    }
+}
+
+///Why the sidecar ended sessions early.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    //Certificate the sessions were attested under is revoked
+    CertificateRevoked,
+    //Client may no longer reach the service
+    ClientBanned,
+}
+
+///Secret the sidecar hands a binary it launched, for the binary to prove who it is when it calls
+///other services. Only valid with the sidecar that issued it, for as long as the binary runs.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CallerCredential {
+    pub bin_name: BinaryName,
+    #[serde(with = "hex::serde")]
+    pub secret: [u8; SESSION_KEY_LEN],
+}
+
+impl CallerCredential {
+    #[cfg(feature="std")]
+    ///Fresh credential for a binary about to be launched.
+    pub fn generate(bin_name: BinaryName) -> Result<Self, Unspecified> {
+        use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+        let mut secret = [0u8; SESSION_KEY_LEN];
+        SystemRandom::new().fill(&mut secret)?;
+        Ok(Self { bin_name, secret })
+    }
+
+    #[cfg(feature="std")]
+    ///Proof of identity for an attestation request. Bound to the request, so that it is useless
+    ///to anyone not holding the secret of `key_share`.
+    pub fn prove(&self, service_name: &ServiceName, nonce: u128, key_share: &[u8]) -> CallerProof {
+        let mut proof = CallerProof {
+            bin_name: self.bin_name.clone(),
+            service_name: service_name.clone(),
+            nonce,
+            key_share: key_share.to_vec(),
+            tag: Vec::new(),
+        };
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.secret);
+        proof.tag = hmac::sign(&key, &proof.signing_payload()).as_ref().to_vec();
+        proof
+    }
+
+    #[cfg(feature="std")]
+    ///Whether the proof was made with this credential, for this request.
+    pub fn check(
+        &self,
+        proof: &CallerProof,
+        service_name: &ServiceName,
+        nonce: u128,
+        key_share: &[u8],
+    ) -> bool {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.secret);
+        proof.bin_name == self.bin_name
+            && proof.service_name == *service_name
+            && proof.nonce == nonce
+            && proof.key_share == key_share
+            && hmac::verify(&key, &proof.signing_payload(), &proof.tag).is_ok()
+    }
+}
+
+//The secret is kept out of logs
+impl core::fmt::Debug for CallerCredential {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CallerCredential")
+            .field("bin_name", &self.bin_name)
+            .finish_non_exhaustive()
+    }
+}
+
+///Identity a caller presents with its attestation request, see CallerCredential::prove.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CallerProof {
+    pub bin_name: BinaryName,
+    //Request the proof was made for
+    pub service_name: ServiceName,
+    pub nonce: u128,
+    #[serde(with = "serde_bytes")]
+    pub key_share: Vec<u8>,
+    //HMAC-SHA256 with the credential secret. Not covered by the signing payload
+    #[serde(with = "serde_bytes")]
+    pub tag: Vec<u8>,
+}
+
+impl SigningPayload for CallerProof {
+    const DOMAIN: &'static [u8] = b"tahini-caller-proof-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(self.bin_name.as_str())
+            .str(&self.service_name.0)
+            .u128(self.nonce)
+            .bytes(&self.key_share);
+    }
+}
+
+///Attested binary on the calling end of a session, as the sidecar vouches for it to the server.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CallerIdentity {
+    pub bin_name: BinaryName,
+    //Services the caller's certificate lists
+    pub service_names: Vec<ServiceName>,
+    pub certificate: TahiniCertificate,
+}
+
+impl CallerIdentity {
+    pub fn new(bin_name: BinaryName, certificate: TahiniCertificate) -> Self {
+        Self {
+            bin_name,
+            service_names: certificate.hosted_services().to_vec(),
+            certificate,
+        }
+    }
+}
+
+///Message from the sidecar to a server, over the channel set up at launch.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SessionMessage {
+    //New session, or new keys for a rekeyed one
+    Open {
+        client_id: ClientId,
+        #[serde(serialize_with = "serialize_keys", deserialize_with = "deserialize_keys")]
+        keys: SessionKeys,
+        //Seconds since UNIX epoch
+        expires_at: u64,
+        budget: u32,
+        //Only for clients that presented a caller credential. Boxed for the certificate size
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        caller: Option<Box<CallerIdentity>>,
+    },
+    //Credential of the server, sent once right after launch
+    Credential { credential: CallerCredential },
+    //Sessions to evict right away
+    Revoke {
+        client_ids: Vec<ClientId>,
+        reason: RevocationReason,
+    },
+}
+
+///Request to replace the keys of a live session. The sidecar attests again with a fresh report,
+///for the same client id.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RekeyRequest {
+    pub client_id: ClientId,
+    pub service_name: ServiceName,
+    pub nonce: u128,
+    //New client key share
+    #[serde(with = "serde_bytes")]
+    pub key_share: Vec<u8>,
+    //See SessionKeys::rekey_proof. Not covered by the signing payload
+    #[serde(with = "serde_bytes")]
+    pub proof: Vec<u8>,
+}
+
+impl SigningPayload for RekeyRequest {
+    const DOMAIN: &'static [u8] = b"tahini-rekey-request-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .u64(self.client_id.0 as u64)
+            .str(&self.service_name.0)
+            .u128(self.nonce)
+            .bytes(&self.key_share);
+    }
+}
+
+#[cfg(feature="std")]
+///Seconds since UNIX epoch.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use aws_lc_rs::rand::SystemRandom;
+
+    use super::*;
+    use crate::types::CertificateId;
+
+    fn transcript() -> SessionTranscript {
+        SessionTranscript {
+            certificate_id: CertificateId("ab".repeat(32)),
+            service_name: ServiceName("hoodini-server".to_string()),
+            nonce: 42,
+            client_id: ClientId::from(7),
+            client_key_share: vec![1; 32],
+            server_key_share: vec![2; 32],
+        }
+    }
+
+    fn keys() -> SessionKeys {
+        keys_from_secret(&[9; 32], &transcript()).unwrap()
+    }
+
+    fn share() -> (EphemeralPrivateKey, Vec<u8>) {
+        let skey = EphemeralPrivateKey::generate(&agreement::X25519, &SystemRandom::new()).unwrap();
+        let pkey = skey.compute_public_key().unwrap().as_ref().to_vec();
+        (skey, pkey)
+    }
+
+    #[test]
+    fn both_ends_derive_the_same_keys() {
+        let (client_skey, client_share) = share();
+        let (server_skey, server_share) = share();
+        let transcript = SessionTranscript {
+            client_key_share: client_share.clone(),
+            server_key_share: server_share.clone(),
+            ..transcript()
+        };
+        let client_keys = derive_session_keys(client_skey, &server_share, &transcript).unwrap();
+        let server_keys = derive_session_keys(server_skey, &client_share, &transcript).unwrap();
+        assert!(client_keys == server_keys);
+        assert_ne!(client_keys.client_to_server, client_keys.server_to_client);
+    }
+
+    #[test]
+    fn keys_are_bound_to_the_transcript() {
+        let changes: [fn(&mut SessionTranscript); 6] = [
+            |t| t.certificate_id = CertificateId("cd".repeat(32)),
+            |t| t.service_name = ServiceName("hoodini-admin".to_string()),
+            |t| t.nonce += 1,
+            |t| t.client_id = ClientId::from(8),
+            |t| t.client_key_share[0] ^= 1,
+            |t| t.server_key_share[0] ^= 1,
+        ];
+        for change in changes {
+            let mut changed = transcript();
+            change(&mut changed);
+            let changed_keys = keys_from_secret(&[9; 32], &changed).unwrap();
+            assert_ne!(changed_keys.client_to_server, keys().client_to_server);
+            assert_ne!(changed_keys.server_to_client, keys().server_to_client);
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_through_bytes() {
+        assert!(SessionKeys::from_bytes(&keys().to_bytes()).unwrap() == keys());
+        assert!(SessionKeys::from_bytes(&keys().to_bytes()[1..]).is_none());
+    }
+
+    fn rekey_request() -> RekeyRequest {
+        RekeyRequest {
+            client_id: ClientId::from(7),
+            service_name: ServiceName("hoodini-server".to_string()),
+            nonce: 43,
+            key_share: vec![3; 32],
+            proof: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rekey_proofs_are_checked() {
+        let mut request = rekey_request();
+        request.proof = keys().rekey_proof(&request).unwrap();
+        assert!(keys().check_rekey_proof(&request));
+
+        let mut other_keys = keys();
+        other_keys.client_to_server[0] ^= 1;
+        assert!(!other_keys.check_rekey_proof(&request));
+
+        let mut other_request = request.clone();
+        other_request.nonce += 1;
+        assert!(!keys().check_rekey_proof(&other_request));
+
+        let mut truncated = request.clone();
+        truncated.proof.pop();
+        assert!(!keys().check_rekey_proof(&truncated));
+        truncated.proof.clear();
+        assert!(!keys().check_rekey_proof(&truncated));
+
+        let mut flipped = request;
+        flipped.proof[0] ^= 1;
+        assert!(!keys().check_rekey_proof(&flipped));
+    }
+
+    #[test]
+    fn caller_proofs_are_checked() {
+        let service_name = ServiceName("hoodini-server".to_string());
+        let credential = CallerCredential::generate(BinaryName::from("caller".to_string())).unwrap();
+        let proof = credential.prove(&service_name, 42, &[1; 32]);
+        assert!(credential.check(&proof, &service_name, 42, &[1; 32]));
+
+        //Other request
+        assert!(!credential.check(&proof, &ServiceName("hoodini-admin".to_string()), 42, &[1; 32]));
+        assert!(!credential.check(&proof, &service_name, 43, &[1; 32]));
+        assert!(!credential.check(&proof, &service_name, 42, &[2; 32]));
+
+        //Other credential, even for the same binary
+        let other = CallerCredential::generate(BinaryName::from("caller".to_string())).unwrap();
+        assert!(!other.check(&proof, &service_name, 42, &[1; 32]));
+
+        let mut renamed = proof.clone();
+        renamed.bin_name = BinaryName::from("other".to_string());
+        assert!(!credential.check(&renamed, &service_name, 42, &[1; 32]));
+
+        let mut truncated = proof.clone();
+        truncated.tag.pop();
+        assert!(!credential.check(&truncated, &service_name, 42, &[1; 32]));
+        truncated.tag.clear();
+        assert!(!credential.check(&truncated, &service_name, 42, &[1; 32]));
+
+        let mut flipped = proof;
+        flipped.tag[0] ^= 1;
+        assert!(!credential.check(&flipped, &service_name, 42, &[1; 32]));
+    }
+}
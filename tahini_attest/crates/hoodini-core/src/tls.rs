@@ -0,0 +1,222 @@
+//!TLS for the connection between clients and a remote sidecar.
+//!
+//!Clients don't rely on certificate authorities: they pin the certificate of the sidecar they
+//!talk to, and refuse any other. Attestation reports are signed regardless, TLS keeps the session
+//!setup and the services asked for private.
+
+use std::{fmt, path::Path, sync::Arc};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+
+pub use rustls::pki_types::ServerName;
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Debug)]
+pub enum TlsError {
+    //Certificate or key file is missing, or not PEM of the expected kind
+    PemError(rustls::pki_types::pem::Error),
+    //PEM file holds no certificate
+    MissingCertificateError,
+    //Host can't be used as a TLS server name
+    InvalidServerNameError(String),
+    ConfigError(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::PemError(e) => write!(f, "could not read PEM file: {}", e),
+            TlsError::MissingCertificateError => write!(f, "PEM file holds no certificate"),
+            TlsError::InvalidServerNameError(name) => {
+                write!(f, "{} is not a valid TLS server name", name)
+            }
+            TlsError::ConfigError(e) => write!(f, "invalid TLS configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::PemError(e) => Some(e),
+            TlsError::ConfigError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(aws_lc_rs::default_provider())
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(TlsError::PemError)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::PemError)?;
+    if certificates.is_empty() {
+        return Err(TlsError::MissingCertificateError);
+    }
+    Ok(certificates)
+}
+
+///Sidecar end: serves the certificate chain at `certificate_path`, leaf first, with the key at
+///`key_path`. Both PEM.
+pub fn server_config(certificate_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certificates = load_certificates(certificate_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(TlsError::PemError)?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::ConfigError)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(TlsError::ConfigError)?;
+    Ok(Arc::new(config))
+}
+
+///Client end: only accepts a sidecar presenting the leaf certificate of the PEM file at
+///`pinned_certificate_path`.
+pub fn pinned_client_config(pinned_certificate_path: &Path) -> Result<Arc<ClientConfig>, TlsError> {
+    let pinned = load_certificates(pinned_certificate_path)?.swap_remove(0);
+    let provider = provider();
+    let verifier = PinnedServerVerifier {
+        pinned,
+        provider: provider.clone(),
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::ConfigError)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+///Name the client sends and expects the sidecar under. Hostnames and IP addresses are accepted.
+pub fn server_name(host: &str) -> Result<ServerName<'static>, TlsError> {
+    ServerName::try_from(host.to_string()).map_err(|_| TlsError::InvalidServerNameError(host.to_string()))
+}
+
+//Accepts exactly the pinned certificate, in place of a chain to a trusted root. Handshake
+//signatures are still checked against it
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    pinned: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.pinned.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    //Self-signed certificates for localhost, made as in `just generate_sidecar_tls_certificate`
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls").join(file)
+    }
+
+    //Handshake between a sidecar serving its certificate and a client pinning `pinned`
+    async fn handshake(pinned: &str) -> Result<(), std::io::Error> {
+        let acceptor = TlsAcceptor::from(
+            server_config(
+                &testdata("sidecar_certificate.pem"),
+                &testdata("sidecar_key.pem"),
+            )
+            .unwrap(),
+        );
+        let connector = TlsConnector::from(pinned_client_config(&testdata(pinned)).unwrap());
+        let (client, sidecar) = duplex(16 * 1024);
+        let sidecar = async move {
+            let mut stream = acceptor.accept(sidecar).await?;
+            stream.write_all(b"ok").await?;
+            stream.flush().await
+        };
+        let client = async move {
+            let mut stream = connector
+                .connect(server_name("localhost").unwrap(), client)
+                .await?;
+            let mut answer = [0u8; 2];
+            stream.read_exact(&mut answer).await?;
+            assert_eq!(&answer, b"ok");
+            Ok(())
+        };
+        let (client, _) = tokio::join!(client, sidecar);
+        client
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_is_accepted() {
+        handshake("sidecar_certificate.pem").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_certificate_is_rejected() {
+        let error = handshake("other_certificate.pem").await.unwrap_err();
+        let error = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+            .expect("handshake should fail on the certificate");
+        assert_eq!(
+            error,
+            &rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+        );
+    }
+
+    #[test]
+    fn pem_without_certificate_is_refused() {
+        assert!(matches!(
+            pinned_client_config(&testdata("sidecar_key.pem")),
+            Err(TlsError::MissingCertificateError)
+        ));
+    }
+}
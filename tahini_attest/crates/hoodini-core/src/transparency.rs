@@ -0,0 +1,568 @@
+//!Append-only Merkle log of issued certificates, following the tree structure of RFC 9162.
+//!
+//!Every certificate produced by the certificate generator is appended to the log, and carries
+//!an inclusion proof against a signed tree head. Auditors can enumerate the log entries and check
+//!that successive tree heads are consistent, i.e. that the log was only ever appended to.
+
+use alloc::{string::String, vec::Vec};
+#[cfg(feature="std")]
+use aws_lc_rs::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(feature="std")]
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
+use crate::types::{KeyId, Signature, TahiniCertificate};
+
+pub type MerkleHash = [u8; 32];
+
+#[cfg(feature="std")]
+const ENTRIES_FILE: &str = "entries.jsonl";
+#[cfg(feature="std")]
+const TREE_HEADS_FILE: &str = "tree_heads.jsonl";
+const LOG_ENTRY_DOMAIN: &[u8] = b"tahini-log-entry-v1";
+
+pub fn leaf_hash(entry: &[u8]) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(entry);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &MerkleHash, right: &MerkleHash) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+///Leaf hash of a certificate: its signed data and signature. The transparency proof it carries
+///is not part of the entry.
+pub fn certificate_leaf(certificate: &TahiniCertificate) -> Result<MerkleHash, hex::FromHexError> {
+    let mut encoder = CanonicalEncoder::new(LOG_ENTRY_DOMAIN);
+    encoder
+        .bytes(&certificate.signed_data()?)
+        .str(&certificate.signature.0);
+    Ok(leaf_hash(&encoder.finish()))
+}
+
+//Largest power of two strictly smaller than n, for n > 1
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+///In-memory Merkle tree over leaf hashes.
+#[derive(Default, Debug, Clone)]
+pub struct MerkleTree {
+    leaves: Vec<MerkleHash>,
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self { leaves: Vec::new() }
+    }
+
+    ///Appends a leaf hash, returning its index.
+    pub fn push(&mut self, leaf: MerkleHash) -> u64 {
+        self.leaves.push(leaf);
+        (self.leaves.len() - 1) as u64
+    }
+
+    pub fn size(&self) -> u64 {
+        self.leaves.len() as u64
+    }
+
+    ///Root of the tree made of the first `size` leaves.
+    pub fn root(&self, size: u64) -> Option<MerkleHash> {
+        let leaves = self.leaves.get(..size as usize)?;
+        Some(subtree_root(leaves))
+    }
+
+    ///Audit path of a leaf in the tree made of the first `size` leaves.
+    pub fn inclusion_proof(&self, index: u64, size: u64) -> Option<Vec<MerkleHash>> {
+        let leaves = self.leaves.get(..size as usize)?;
+        if index >= size {
+            return None;
+        }
+        let mut proof = Vec::new();
+        inclusion_path(index as usize, leaves, &mut proof);
+        Some(proof)
+    }
+
+    ///Proof that the tree of size `old_size` is a prefix of the tree of size `new_size`.
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Option<Vec<MerkleHash>> {
+        let leaves = self.leaves.get(..new_size as usize)?;
+        if old_size > new_size {
+            return None;
+        }
+        let mut proof = Vec::new();
+        if old_size > 0 && old_size < new_size {
+            consistency_path(old_size as usize, leaves, true, &mut proof);
+        }
+        Some(proof)
+    }
+}
+
+fn subtree_root(leaves: &[MerkleHash]) -> MerkleHash {
+    match leaves.len() {
+        0 => Sha256::digest([]).into(),
+        1 => leaves[0],
+        n => {
+            let k = split_point(n);
+            node_hash(&subtree_root(&leaves[..k]), &subtree_root(&leaves[k..]))
+        }
+    }
+}
+
+fn inclusion_path(index: usize, leaves: &[MerkleHash], proof: &mut Vec<MerkleHash>) {
+    let n = leaves.len();
+    if n <= 1 {
+        return;
+    }
+    let k = split_point(n);
+    if index < k {
+        inclusion_path(index, &leaves[..k], proof);
+        proof.push(subtree_root(&leaves[k..]));
+    } else {
+        inclusion_path(index - k, &leaves[k..], proof);
+        proof.push(subtree_root(&leaves[..k]));
+    }
+}
+
+fn consistency_path(old_size: usize, leaves: &[MerkleHash], complete: bool, proof: &mut Vec<MerkleHash>) {
+    let n = leaves.len();
+    if old_size == n {
+        if !complete {
+            proof.push(subtree_root(leaves));
+        }
+        return;
+    }
+    let k = split_point(n);
+    if old_size <= k {
+        consistency_path(old_size, &leaves[..k], complete, proof);
+        proof.push(subtree_root(&leaves[k..]));
+    } else {
+        consistency_path(old_size - k, &leaves[k..], false, proof);
+        proof.push(subtree_root(&leaves[..k]));
+    }
+}
+
+///Recomputes the root of a tree of `size` leaves from a leaf and its audit path.
+pub fn root_from_inclusion_proof(
+    index: u64,
+    size: u64,
+    leaf: MerkleHash,
+    proof: &[MerkleHash],
+) -> Option<MerkleHash> {
+    if index >= size {
+        return None;
+    }
+    let (mut fnode, mut snode) = (index, size - 1);
+    let mut root = leaf;
+    for sibling in proof {
+        if snode == 0 {
+            return None;
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            root = node_hash(sibling, &root);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            root = node_hash(&root, sibling);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+    (snode == 0).then_some(root)
+}
+
+///Checks that the tree of `old_size` leaves with `old_root` is a prefix of the tree of
+///`new_size` leaves with `new_root`.
+pub fn verify_consistency(
+    old_size: u64,
+    new_size: u64,
+    old_root: &MerkleHash,
+    new_root: &MerkleHash,
+    proof: &[MerkleHash],
+) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+    if old_size == 0 {
+        return proof.is_empty();
+    }
+    let mut path = Vec::with_capacity(proof.len() + 1);
+    if old_size.is_power_of_two() {
+        path.push(*old_root);
+    }
+    path.extend_from_slice(proof);
+    let Some((first, rest)) = path.split_first() else {
+        return false;
+    };
+    let (mut fnode, mut snode) = (old_size - 1, new_size - 1);
+    while fnode & 1 == 1 {
+        fnode >>= 1;
+        snode >>= 1;
+    }
+    let (mut old_computed, mut new_computed) = (*first, *first);
+    for node in rest {
+        if snode == 0 {
+            return false;
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            old_computed = node_hash(node, &old_computed);
+            new_computed = node_hash(node, &new_computed);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            new_computed = node_hash(&new_computed, node);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+    snode == 0 && &old_computed == old_root && &new_computed == new_root
+}
+
+#[cfg(feature="std")]
+fn encode_hashes(hashes: &[MerkleHash]) -> Vec<String> {
+    hashes.iter().map(hex::encode).collect()
+}
+
+///Decodes hex-encoded tree hashes, failing on any malformed entry.
+pub fn decode_hashes(hashes: &[String]) -> Option<Vec<MerkleHash>> {
+    hashes.iter().map(|hash| decode_hash(hash)).collect()
+}
+
+fn decode_hash(hash: &str) -> Option<MerkleHash> {
+    hex::decode(hash).ok()?.try_into().ok()
+}
+
+///Log root at a given size, signed by the log key.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedTreeHead {
+    pub log_id: KeyId,
+    pub tree_size: u64,
+    //Hex-encoded
+    pub root_hash: String,
+    //Seconds since UNIX epoch
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+
+impl SignedTreeHead {
+    #[cfg(feature="std")]
+    pub fn new(
+        log_id: KeyId,
+        tree_size: u64,
+        root: &MerkleHash,
+        timestamp: u64,
+        key: &Ed25519KeyPair,
+    ) -> Self {
+        let mut tree_head = Self {
+            log_id,
+            tree_size,
+            root_hash: hex::encode(root),
+            timestamp,
+            signature: Signature(String::new()),
+        };
+        tree_head.signature = key.sign(&tree_head.signing_payload()).into();
+        tree_head
+    }
+
+    pub fn root(&self) -> Option<MerkleHash> {
+        decode_hash(&self.root_hash)
+    }
+}
+
+impl SigningPayload for SignedTreeHead {
+    const DOMAIN: &'static [u8] = b"tahini-tree-head-v1";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .str(&self.log_id.0)
+            .u64(self.tree_size)
+            .str(&self.root_hash)
+            .u64(self.timestamp);
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    //Hex-encoded audit path
+    pub hashes: Vec<String>,
+}
+
+///Proof that a certificate was appended to the transparency log.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransparencyProof {
+    pub inclusion: InclusionProof,
+    pub tree_head: SignedTreeHead,
+}
+
+impl TransparencyProof {
+    ///Checks the inclusion proof of the certificate against the tree head root.
+    ///Does not check the tree head signature.
+    pub fn verify_inclusion(&self, certificate: &TahiniCertificate) -> bool {
+        if self.inclusion.tree_size != self.tree_head.tree_size {
+            return false;
+        }
+        let (Ok(leaf), Some(proof), Some(root)) = (
+            certificate_leaf(certificate),
+            decode_hashes(&self.inclusion.hashes),
+            self.tree_head.root(),
+        ) else {
+            return false;
+        };
+        root_from_inclusion_proof(self.inclusion.leaf_index, self.inclusion.tree_size, leaf, &proof)
+            .is_some_and(|computed| computed == root)
+    }
+}
+
+///File-backed transparency log. Entries and signed tree heads are stored as JSON lines, and
+///only ever appended to.
+#[cfg(feature="std")]
+pub struct TransparencyLog {
+    dir: PathBuf,
+    tree: MerkleTree,
+    entries: Vec<TahiniCertificate>,
+    tree_heads: Vec<SignedTreeHead>,
+}
+
+#[cfg(feature="std")]
+fn read_lines<T: for<'de> Deserialize<'de>>(path: &Path) -> io::Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut values = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        values.push(serde_json::from_str(&line)?);
+    }
+    Ok(values)
+}
+
+#[cfg(feature="std")]
+fn append_line<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(value)?)
+}
+
+#[cfg(feature="std")]
+impl TransparencyLog {
+    ///Opens the log stored in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let entries: Vec<TahiniCertificate> = read_lines(&dir.join(ENTRIES_FILE))?;
+        let tree_heads = read_lines(&dir.join(TREE_HEADS_FILE))?;
+        let mut tree = MerkleTree::new();
+        for entry in entries.iter() {
+            let leaf = certificate_leaf(entry)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            tree.push(leaf);
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            tree,
+            entries,
+            tree_heads,
+        })
+    }
+
+    ///Appends a certificate, returning its leaf index.
+    pub fn append(&mut self, certificate: &TahiniCertificate) -> io::Result<u64> {
+        let mut entry = certificate.clone();
+        entry.transparency = None;
+        let leaf = certificate_leaf(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        append_line(&self.dir.join(ENTRIES_FILE), &entry)?;
+        self.entries.push(entry);
+        Ok(self.tree.push(leaf))
+    }
+
+    ///Signs and records the head of the log at its current size.
+    pub fn sign_tree_head(
+        &mut self,
+        log_id: KeyId,
+        timestamp: u64,
+        key: &Ed25519KeyPair,
+    ) -> io::Result<SignedTreeHead> {
+        let size = self.tree.size();
+        let root = self.tree.root(size).expect("Current size is within the tree");
+        let tree_head = SignedTreeHead::new(log_id, size, &root, timestamp, key);
+        append_line(&self.dir.join(TREE_HEADS_FILE), &tree_head)?;
+        self.tree_heads.push(tree_head.clone());
+        Ok(tree_head)
+    }
+
+    ///Inclusion proof of an entry against the given tree head.
+    pub fn prove_inclusion(&self, index: u64, tree_head: &SignedTreeHead) -> Option<TransparencyProof> {
+        let hashes = self.tree.inclusion_proof(index, tree_head.tree_size)?;
+        Some(TransparencyProof {
+            inclusion: InclusionProof {
+                leaf_index: index,
+                tree_size: tree_head.tree_size,
+                hashes: encode_hashes(&hashes),
+            },
+            tree_head: tree_head.clone(),
+        })
+    }
+
+    pub fn tree(&self) -> &MerkleTree {
+        &self.tree
+    }
+
+    pub fn entries(&self) -> &[TahiniCertificate] {
+        &self.entries
+    }
+
+    pub fn tree_heads(&self) -> &[SignedTreeHead] {
+        &self.tree_heads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [u64; 5] = [1, 2, 3, 7, 8];
+
+    fn tree(size: u64) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for i in 0..size {
+            tree.push(leaf_hash(&i.to_be_bytes()));
+        }
+        tree
+    }
+
+    //Copies of the proof with one hash changed, one per hash
+    fn tampered(proof: &[MerkleHash]) -> Vec<Vec<MerkleHash>> {
+        (0..proof.len())
+            .map(|i| {
+                let mut proof = proof.to_vec();
+                proof[i][0] ^= 1;
+                proof
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_point_is_largest_smaller_power_of_two() {
+        for (n, k) in [(2, 1), (3, 2), (4, 2), (5, 4), (7, 4), (8, 4), (9, 8)] {
+            assert_eq!(split_point(n), k, "split point of {}", n);
+        }
+    }
+
+    #[test]
+    fn roots_follow_rfc_9162() {
+        let leaves: Vec<_> = (0u64..3).map(|i| leaf_hash(&i.to_be_bytes())).collect();
+        let tree = tree(3);
+        assert_eq!(tree.root(1), Some(leaves[0]));
+        assert_eq!(tree.root(2), Some(node_hash(&leaves[0], &leaves[1])));
+        assert_eq!(
+            tree.root(3),
+            Some(node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]))
+        );
+        assert_eq!(tree.root(4), None);
+    }
+
+    #[test]
+    fn inclusion_proofs_verify() {
+        for size in SIZES {
+            let tree = tree(size);
+            let root = tree.root(size).unwrap();
+            for index in 0..size {
+                let leaf = leaf_hash(&index.to_be_bytes());
+                let proof = tree.inclusion_proof(index, size).unwrap();
+                assert_eq!(
+                    root_from_inclusion_proof(index, size, leaf, &proof),
+                    Some(root),
+                    "leaf {} of {}",
+                    index,
+                    size
+                );
+                for proof in tampered(&proof) {
+                    assert_ne!(root_from_inclusion_proof(index, size, leaf, &proof), Some(root));
+                }
+                let other_leaf = leaf_hash(b"other");
+                assert_ne!(root_from_inclusion_proof(index, size, other_leaf, &proof), Some(root));
+                if size > 1 {
+                    let other_index = (index + 1) % size;
+                    assert_ne!(
+                        root_from_inclusion_proof(other_index, size, leaf, &proof),
+                        Some(root)
+                    );
+                }
+            }
+            assert_eq!(tree.inclusion_proof(size, size), None);
+            assert_eq!(root_from_inclusion_proof(size, size, root, &[]), None);
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_verify() {
+        for size in SIZES {
+            let tree = tree(size);
+            let new_root = tree.root(size).unwrap();
+            for old_size in 0..=size {
+                let old_root = tree.root(old_size).unwrap();
+                let proof = tree.consistency_proof(old_size, size).unwrap();
+                assert!(
+                    verify_consistency(old_size, size, &old_root, &new_root, &proof),
+                    "from {} to {}",
+                    old_size,
+                    size
+                );
+                for proof in tampered(&proof) {
+                    assert!(!verify_consistency(old_size, size, &old_root, &new_root, &proof));
+                }
+                if old_size > 0 && old_size < size {
+                    let other_root = leaf_hash(b"other");
+                    assert!(!verify_consistency(old_size, size, &other_root, &new_root, &proof));
+                    assert!(!verify_consistency(old_size, size, &old_root, &other_root, &proof));
+                }
+            }
+            assert_eq!(tree.consistency_proof(size + 1, size), None);
+            assert!(!verify_consistency(size, size - 1, &new_root, &new_root, &[]));
+        }
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = tree(0);
+        let empty_root: MerkleHash = Sha256::digest([]).into();
+        assert_eq!(tree.root(0), Some(empty_root));
+        assert_eq!(tree.inclusion_proof(0, 0), None);
+        assert_eq!(root_from_inclusion_proof(0, 0, empty_root, &[]), None);
+        assert_eq!(tree.consistency_proof(0, 0), Some(Vec::new()));
+        assert!(verify_consistency(0, 0, &empty_root, &empty_root, &[]));
+        assert!(!verify_consistency(0, 0, &empty_root, &leaf_hash(b"other"), &[]));
+
+        //Every tree extends the empty one, with an empty proof
+        let tree = self::tree(3);
+        assert_eq!(tree.consistency_proof(0, 3), Some(Vec::new()));
+        assert!(verify_consistency(0, 3, &empty_root, &tree.root(3).unwrap(), &[]));
+        assert!(!verify_consistency(0, 3, &empty_root, &tree.root(3).unwrap(), &[empty_root]));
+    }
+}
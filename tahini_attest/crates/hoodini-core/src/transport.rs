@@ -0,0 +1,255 @@
+//!Tarpc transport carrying messages as sealed records.
+
+use std::{
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, Stream};
+use tarpc::tokio_serde::{Deserializer, Serializer};
+
+use crate::codec::Codec;
+use crate::record::{
+    client_records, server_records, RecordLimits, RecordOpener, RecordResult, RecordSealer,
+};
+use crate::session::ConnectionKeys;
+use crate::types::ClientId;
+
+///Serializes messages with a codec such as `crate::codec::Codec` and seals each one as a record,
+///over a transport of frames such as `Framed<TcpStream, LengthDelimitedCodec>`. Implements
+///`tarpc::Transport`, so it can be handed to `tarpc::client::new` or
+///`tarpc::server::BaseChannel::with_defaults`.
+///The session can't recover from a record that fails to open or seal: the error is returned once,
+///then the stream ends and sending fails, on both halves.
+pub struct SealedTransport<T, Item, SinkItem, C = Codec> {
+    inner: T,
+    sealer: RecordSealer,
+    opener: RecordOpener,
+    codec: C,
+    failed: bool,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<T, Item, SinkItem, C> SealedTransport<T, Item, SinkItem, C> {
+    pub fn new(inner: T, sealer: RecordSealer, opener: RecordOpener, codec: C) -> Self {
+        Self {
+            inner,
+            sealer,
+            opener,
+            codec,
+            failed: false,
+            ghost: PhantomData,
+        }
+    }
+
+    ///Client end of a connection, with keys from `ClientSession::next_connection` over a session
+    ///attested by `DynamicAttestationVerifier::verify_binary`.
+    pub fn client(inner: T, keys: ConnectionKeys, client_id: &ClientId, codec: C) -> RecordResult<Self> {
+        let (sealer, opener) = client_records(keys, client_id, RecordLimits::default())?;
+        Ok(Self::new(inner, sealer, opener, codec))
+    }
+
+    ///Server end of a connection, with the keys from `get_key_for_client`.
+    pub fn server(inner: T, keys: ConnectionKeys, client_id: &ClientId, codec: C) -> RecordResult<Self> {
+        let (sealer, opener) = server_records(keys, client_id, RecordLimits::default())?;
+        Ok(Self::new(inner, sealer, opener, codec))
+    }
+
+    ///Whether a record failed to open or seal, which ended the session.
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    //Marks the session failed when `result` is an error
+    fn check<V>(&mut self, result: io::Result<V>) -> io::Result<V> {
+        self.failed |= result.is_err();
+        result
+    }
+}
+
+fn failed_session() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "sealed session failed on an earlier record",
+    )
+}
+
+impl<T, Item, SinkItem, C> Stream for SealedTransport<T, Item, SinkItem, C>
+where
+    T: Stream<Item = io::Result<BytesMut>> + Unpin,
+    C: Deserializer<Item> + Unpin,
+    C::Error: Into<io::Error>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.failed {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(record))) => {
+                let message = this
+                    .opener
+                    .open(&record)
+                    .map_err(io::Error::from)
+                    .and_then(|message| {
+                        Pin::new(&mut this.codec)
+                            .deserialize(&BytesMut::from(message.as_slice()))
+                            .map_err(Into::into)
+                    });
+                Poll::Ready(Some(this.check(message)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(this.check(Err(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T, Item, SinkItem, C> Sink<SinkItem> for SealedTransport<T, Item, SinkItem, C>
+where
+    T: Sink<Bytes, Error = io::Error> + Unpin,
+    C: Serializer<SinkItem> + Unpin,
+    C::Error: Into<io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.failed {
+            return Poll::Ready(Err(failed_session()));
+        }
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let this = &mut *self;
+        if this.failed {
+            return Err(failed_session());
+        }
+        let record = Pin::new(&mut this.codec)
+            .serialize(&item)
+            .map_err(Into::into)
+            .and_then(|message| this.sealer.seal(&message).map_err(io::Error::from));
+        let record = this.check(record)?;
+        Pin::new(&mut this.inner).start_send(Bytes::from(record))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionKeys;
+    use futures::{executor::block_on, StreamExt};
+    use std::collections::VecDeque;
+
+    //Frames to read, and frames sent
+    #[derive(Default)]
+    struct Frames {
+        incoming: VecDeque<io::Result<BytesMut>>,
+        sent: Vec<Bytes>,
+    }
+
+    impl Stream for Frames {
+        type Item = io::Result<BytesMut>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.incoming.pop_front())
+        }
+    }
+
+    impl Sink<Bytes> for Frames {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> io::Result<()> {
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn keys() -> ConnectionKeys {
+        SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        }
+        .connection_keys(0)
+        .unwrap()
+    }
+
+    type Transport = SealedTransport<Frames, String, String>;
+
+    fn client() -> Transport {
+        SealedTransport::client(Frames::default(), keys(), &ClientId::from(3), Codec::Cbor).unwrap()
+    }
+
+    fn server(incoming: Vec<Bytes>) -> Transport {
+        let frames = Frames {
+            incoming: incoming
+                .into_iter()
+                .map(|frame| Ok(BytesMut::from(frame.as_ref())))
+                .collect(),
+            sent: Vec::new(),
+        };
+        SealedTransport::server(frames, keys(), &ClientId::from(3), Codec::Cbor).unwrap()
+    }
+
+    //Records the client seals for these messages
+    fn sealed(messages: &[&str]) -> Vec<Bytes> {
+        let mut client = client();
+        for message in messages {
+            Pin::new(&mut client).start_send(message.to_string()).unwrap();
+        }
+        client.into_inner().sent
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut server = server(sealed(&["first", "second"]));
+        assert_eq!(block_on(server.next()).unwrap().unwrap(), "first");
+        assert_eq!(block_on(server.next()).unwrap().unwrap(), "second");
+        assert!(block_on(server.next()).is_none());
+    }
+
+    #[test]
+    fn tampered_record_ends_the_session() {
+        let mut records = sealed(&["first", "second"]);
+        let mut tampered = records[0].to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        records.insert(0, Bytes::from(tampered));
+        let mut server = server(records);
+
+        assert!(block_on(server.next()).unwrap().is_err());
+        assert!(server.has_failed());
+        //The genuine records that follow are not opened anymore
+        assert!(block_on(server.next()).is_none());
+        assert!(Pin::new(&mut server).start_send("reply".to_string()).is_err());
+        assert!(server.into_inner().sent.is_empty());
+    }
+}
@@ -1,16 +1,201 @@
+use alloc::{string::String, vec::Vec};
+#[cfg(feature="std")]
 use aws_lc_rs::signature::Signature as awsSig;
+use core::fmt::Display;
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use sha2::{Digest, Sha256};
+
+use crate::canonical::{CanonicalEncoder, SigningPayload};
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct TahiniCertificate {
+    //Certificates generated before versioning was introduced do not carry this field.
+    #[serde(default = "CertificateVersion::legacy")]
+    pub version: CertificateVersion,
+    //Name of the certified binary, see binary_name. Predates binaries hosting several services
     pub service_name: ServiceName,
     pub policy_hash: PolicyHash,
     pub binary_hash: BinHash,
+    //Only present from v2 onwards.
+    #[serde(flatten)]
+    pub issuance: Option<IssuanceInfo>,
+    //Present in v3, optional from v4 onwards. Signed through its digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_manifest: Option<crate::manifest::PolicyManifest>,
+    //Services hosted by the binary. Only present from v4 onwards
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceName>,
     pub signature: Signature,
+    //Inclusion in the transparency log, added after signing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transparency: Option<crate::transparency::TransparencyProof>,
+}
+
+impl TahiniCertificate {
+    ///A certificate is well-formed if the fields it carries match its declared version.
+    pub fn is_well_formed(&self) -> bool {
+        match self.version {
+            CertificateVersion::V1 => {
+                self.issuance.is_none() && self.policy_manifest.is_none() && self.services.is_empty()
+            }
+            CertificateVersion::V2 => {
+                self.issuance.is_some() && self.policy_manifest.is_none() && self.services.is_empty()
+            }
+            CertificateVersion::V3 => {
+                self.issuance.is_some() && self.policy_manifest.is_some() && self.services.is_empty()
+            }
+            CertificateVersion::V4 => self.issuance.is_some() && !self.services.is_empty(),
+            _ => false,
+        }
+    }
+
+    ///Name of the certified binary. Certificates are issued per binary.
+    pub fn binary_name(&self) -> BinaryName {
+        BinaryName(self.service_name.0.clone())
+    }
+
+    ///Services hosted by the certified binary. Certificates before v4 name a single service,
+    ///the binary itself.
+    pub fn hosted_services(&self) -> &[ServiceName] {
+        if self.services.is_empty() {
+            core::slice::from_ref(&self.service_name)
+        } else {
+            &self.services
+        }
+    }
+
+    pub fn hosts(&self, service_name: &ServiceName) -> bool {
+        self.hosted_services().contains(service_name)
+    }
+
+    ///Bytes covered by the certificate signature.
+    ///v1 certificates only sign `policy_hash || binary_hash`.
+    ///v2 and later certificates sign their canonical payload.
+    pub fn signed_data(&self) -> Result<Vec<u8>, hex::FromHexError> {
+        match &self.issuance {
+            None => {
+                let mut data = hex::decode(&self.policy_hash.0)?;
+                data.extend(hex::decode(&self.binary_hash.0)?);
+                Ok(data)
+            }
+            Some(_) => Ok(self.signing_payload()),
+        }
+    }
+
+    ///Identifier of the certificate: SHA-256 of the signed data, in hex.
+    pub fn id(&self) -> Result<CertificateId, hex::FromHexError> {
+        let digest = Sha256::digest(self.signed_data()?);
+        Ok(CertificateId(hex::encode(digest)))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct CertificateId(pub String);
+
+impl Display for CertificateId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl SigningPayload for TahiniCertificate {
+    const DOMAIN: &'static [u8] = b"tahini-certificate-v2";
+
+    //The version decides which fields follow, so the fields of a version are a prefix of the
+    //fields of the next one.
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .u8(self.version.0)
+            .str(&self.service_name.0)
+            .str(&self.policy_hash.0)
+            .str(&self.binary_hash.0);
+        if let Some(issuance) = &self.issuance {
+            encoder
+                .str(&issuance.issuer.0)
+                .u64(issuance.issued_at)
+                .u64(issuance.expires_at)
+                .str(&issuance.toolchain.generator)
+                .str(&issuance.toolchain.rustc);
+        }
+        if self.version.0 < CertificateVersion::V4.0 {
+            if let Some(manifest) = &self.policy_manifest {
+                encoder.bytes(&manifest.digest());
+            }
+            return;
+        }
+        //From v4, the manifest is optional and flagged
+        match &self.policy_manifest {
+            None => {
+                encoder.u8(0);
+            }
+            Some(manifest) => {
+                encoder.u8(1).bytes(&manifest.digest());
+            }
+        }
+        encoder.u32(self.services.len() as u32);
+        for service in self.services.iter() {
+            encoder.str(&service.0);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertificateVersion(pub u8);
+
+impl CertificateVersion {
+    pub const V1: CertificateVersion = CertificateVersion(1);
+    pub const V2: CertificateVersion = CertificateVersion(2);
+    pub const V3: CertificateVersion = CertificateVersion(3);
+    pub const V4: CertificateVersion = CertificateVersion(4);
+
+    fn legacy() -> Self {
+        Self::V1
+    }
 }
 
+impl Display for CertificateVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+///Issuer metadata carried by v2 and later certificates.
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuanceInfo {
+    pub issuer: KeyId,
+    //Seconds since UNIX epoch
+    pub issued_at: u64,
+    //Seconds since UNIX epoch
+    pub expires_at: u64,
+    pub toolchain: ToolchainInfo,
+}
+
+///Tools that produced the certificate.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolchainInfo {
+    pub generator: String,
+    pub rustc: String,
+}
+
+///Identifier of a certificate signing key.
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct KeyId(pub String);
+
+impl KeyId {
+    ///Default key identifier: first 8 bytes of the SHA-256 of the raw public key, in hex.
+    pub fn from_public_key(key_material: &[u8]) -> Self {
+        let digest = Sha256::digest(key_material);
+        KeyId(hex::encode(&digest[..8]))
+    }
+}
+
+impl Display for KeyId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[allow(unused)]
 pub struct BinHash(pub String);
 
@@ -18,15 +203,22 @@ pub struct BinHash(pub String);
 #[allow(unused)]
 pub struct Signature(pub String);
 
+#[cfg(feature="std")]
 impl From<awsSig> for Signature {
     fn from(value: awsSig) -> Self {
         Signature(hex::encode(value.as_ref()))
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[allow(unused)]
-pub struct PolicyHash(String);
+pub struct PolicyHash(pub String);
+
+impl From<String> for PolicyHash {
+    fn from(value: String) -> Self {
+        PolicyHash(value)
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DynamicAttestationReport {
@@ -34,21 +226,175 @@ pub struct DynamicAttestationReport {
     pub nonce: u128,
     pub service_name: ServiceName,
     pub current_bin_hash: BinHash,
+    //Key share the client sent with its request
+    #[serde(with = "serde_bytes")]
+    pub client_key_share: Vec<u8>,
+    #[serde(with = "serde_bytes")]
     pub server_key_share: Vec<u8>,
     pub client_id: ClientId,
     pub signature: Signature,
 }
 
-#[derive(Serialize, Debug)]
+impl DynamicAttestationReport {
+    ///Data the report signature covers.
+    pub fn attestation_data(&self) -> DynamicAttestationData<'_> {
+        DynamicAttestationData {
+            cert: &self.certificate,
+            nonce: self.nonce,
+            service_name: self.service_name.clone(),
+            current_bin_hash: self.current_bin_hash.clone(),
+            client_key_share: self.client_key_share.clone(),
+            server_key_share: self.server_key_share.clone(),
+            client_id: self.client_id.clone(),
+        }
+    }
+}
+
+///Data signed by the sidecar in a `DynamicAttestationReport`.
+#[derive(Debug)]
 pub struct DynamicAttestationData<'a> {
     pub cert: &'a TahiniCertificate,
     pub nonce: u128,
     pub service_name: ServiceName,
     pub current_bin_hash: BinHash,
+    pub client_key_share: Vec<u8>,
     pub server_key_share: Vec<u8>,
     pub client_id: ClientId,
 }
 
+impl SigningPayload for DynamicAttestationData<'_> {
+    //v1 did not cover the client key share
+    const DOMAIN: &'static [u8] = b"tahini-attestation-report-v2";
+
+    fn encode(&self, encoder: &mut CanonicalEncoder) {
+        encoder
+            .bytes(&self.cert.signing_payload())
+            .str(&self.cert.signature.0)
+            .u128(self.nonce)
+            .str(&self.service_name.0)
+            .str(&self.current_bin_hash.0)
+            .bytes(&self.client_key_share)
+            .bytes(&self.server_key_share)
+            .u64(self.client_id.0 as u64);
+    }
+}
+
+///Why the sidecar could not attest a service, sent back to the client instead of a report.
+///Goes over the wire as its code, which never changes meaning once assigned, so that clients and
+///sidecars of different versions agree on it. Codes a client doesn't know yet are kept as
+///UnknownFailureError.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u16", into = "u16")]
+pub enum AttestationFailure {
+    //No certified binary hosts the service
+    UnknownServiceError,
+    //Binary hosting the service has no certificate loaded
+    MissingCertificateError,
+    //Binary hosting the service is not running under the sidecar
+    BinaryNotRunningError,
+    //Certificate of the binary appears in a loaded revocation list
+    CertificateRevokedError,
+    //Session keys could not be handed to the binary
+    ServiceUnavailableError,
+    //No COSE certificate envelope is registered for the service
+    MissingCoseCertificateError,
+    //Client key share is not a valid X25519 public key
+    MalformedKeyShareError,
+    //Session is unknown or expired, or the proof of its current keys is wrong
+    RekeyRefusedError,
+    InternalError,
+    //Caller credential is unknown to the sidecar, its proof is wrong, or the caller's
+    //certificate is missing or revoked
+    CallerIdentityError,
+    //Sidecar access policy does not let the client attest
+    AccessDeniedError,
+    UnknownFailureError(u16),
+}
+
+impl AttestationFailure {
+    pub fn code(&self) -> u16 {
+        match self {
+            AttestationFailure::UnknownServiceError => 1,
+            AttestationFailure::MissingCertificateError => 2,
+            AttestationFailure::BinaryNotRunningError => 3,
+            AttestationFailure::CertificateRevokedError => 4,
+            AttestationFailure::ServiceUnavailableError => 5,
+            AttestationFailure::MissingCoseCertificateError => 6,
+            AttestationFailure::MalformedKeyShareError => 7,
+            AttestationFailure::RekeyRefusedError => 8,
+            AttestationFailure::InternalError => 9,
+            AttestationFailure::CallerIdentityError => 10,
+            AttestationFailure::AccessDeniedError => 11,
+            AttestationFailure::UnknownFailureError(code) => *code,
+        }
+    }
+
+    ///Whether asking again later may succeed, e.g. once the binary is up.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            AttestationFailure::BinaryNotRunningError | AttestationFailure::ServiceUnavailableError
+        )
+    }
+}
+
+impl From<u16> for AttestationFailure {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => AttestationFailure::UnknownServiceError,
+            2 => AttestationFailure::MissingCertificateError,
+            3 => AttestationFailure::BinaryNotRunningError,
+            4 => AttestationFailure::CertificateRevokedError,
+            5 => AttestationFailure::ServiceUnavailableError,
+            6 => AttestationFailure::MissingCoseCertificateError,
+            7 => AttestationFailure::MalformedKeyShareError,
+            8 => AttestationFailure::RekeyRefusedError,
+            9 => AttestationFailure::InternalError,
+            10 => AttestationFailure::CallerIdentityError,
+            11 => AttestationFailure::AccessDeniedError,
+            code => AttestationFailure::UnknownFailureError(code),
+        }
+    }
+}
+
+impl From<AttestationFailure> for u16 {
+    fn from(value: AttestationFailure) -> Self {
+        value.code()
+    }
+}
+
+impl Display for AttestationFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AttestationFailure::UnknownServiceError => write!(f, "no certified binary hosts the service"),
+            AttestationFailure::MissingCertificateError => {
+                write!(f, "no certificate is loaded for the binary")
+            }
+            AttestationFailure::BinaryNotRunningError => write!(f, "binary is not running"),
+            AttestationFailure::CertificateRevokedError => write!(f, "certificate is revoked"),
+            AttestationFailure::ServiceUnavailableError => {
+                write!(f, "session keys could not be handed to the binary")
+            }
+            AttestationFailure::MissingCoseCertificateError => {
+                write!(f, "no COSE certificate is registered for the service")
+            }
+            AttestationFailure::MalformedKeyShareError => write!(f, "malformed client key share"),
+            AttestationFailure::RekeyRefusedError => write!(f, "sidecar refused to rekey the session"),
+            AttestationFailure::InternalError => write!(f, "internal sidecar error"),
+            AttestationFailure::CallerIdentityError => {
+                write!(f, "sidecar could not establish the caller identity")
+            }
+            AttestationFailure::AccessDeniedError => write!(f, "access denied by the sidecar"),
+            AttestationFailure::UnknownFailureError(code) => {
+                write!(f, "sidecar failed with unknown code {}", code)
+            }
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl std::error::Error for AttestationFailure {}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ServiceName(pub String);
 
@@ -59,7 +405,7 @@ impl ServiceName {
 }
 
 impl Display for ServiceName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
@@ -70,20 +416,272 @@ impl From<String> for ServiceName {
     }
 }
 
-#[derive(Clone, Hash, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
 pub struct BinaryName(pub(crate) String);
 
+impl BinaryName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for BinaryName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for BinaryName {
+    fn from(value: String) -> Self {
+        BinaryName(value)
+    }
+}
+
 
 #[cfg(feature="attest")]
 #[derive(Debug)]
 pub enum AttestErrors {
     IoError(std::io::Error),
+    KeyError(crate::keys::KeyError),
     ServiceMismatchError,
     NetworkError(tarpc::client::RpcError),
     AttestDataMalformedError(serde_json::Error),
     ConfigError(String),
     CryptoError,
-    InvalidAttestation,
+    //Could not reach the sidecar
+    TransportError(std::io::Error),
+    //Sidecar and client don't agree on a codec for the attestation RPC
+    CodecError(crate::codec::CodecError),
+    //No binary hosts the requested service
+    UnknownServiceError(ServiceName),
+    //Attestation report is not signed by the sidecar attestation key
+    AttestationSignatureError,
+    //Sidecar runs another binary than the certified one
+    BinaryHashMismatchError { expected: BinHash, reported: BinHash },
+    //Attestation report does not answer the nonce of the request
+    NonceMismatchError,
+    //Attestation report was made for another key share than the one sent by the client
+    KeyShareMismatchError,
+    //Attestation report was already accepted once
+    ReplayedReportError,
+    //Sidecar could not attest the service. Failures with a dedicated variant are mapped to it
+    AttestationFailedError(AttestationFailure),
+    //Rekeyed report was made for another client id than the session's
+    ClientIdMismatchError,
+    //Certificate is v1 and the loader was not configured to accept legacy certificates
+    LegacyCertificateError,
+    UnsupportedCertificateVersion(CertificateVersion),
+    //Certificate fields do not match its declared version
+    MalformedCertificateError,
+    //No issuer key was loaded to verify certificates against
+    MissingCertificateKey,
+    CertificateSignatureError,
+    CertificateExpiredError,
+    //No certificate is held locally for the requested service
+    UnknownCertificateError,
+    //Remote certificate verifies but differs from the locally held one
+    CertificateMismatchError,
+    //Certificate names an issuer key that is not trusted
+    UnknownIssuerError,
+    //Issuer key is outside its validity window, now or at certificate issuance
+    IssuerKeyExpiredError,
+    RevocationSignatureError,
+    //Revocation list is signed by an intermediate key rather than a root key
+    RevocationIssuerError,
+    //Certificate, or its binary or policy hash, appears in a loaded revocation list
+    CertificateRevokedError,
+    AuthoritySignatureError,
+    //Authority certificate is not signed by a root key
+    AuthorityChainError,
+    //Certificate names a service outside of its issuing authority scope
+    AuthorityScopeError,
+    CoseError(crate::cose::CoseEnvelopeError),
+    //Loader requires inclusion proofs and the certificate carries none
+    MissingInclusionProofError,
+    //Tree head is signed by a key that is not a trusted log key
+    UnknownLogKeyError,
+    TreeHeadSignatureError,
+    InclusionProofError,
+    //No policy hash is pinned for a certificate fetched from the sidecar
+    UnpinnedServiceError,
+    //Certificate verifies, but attests to another policy set than the pinned one
+    PolicyPinMismatchError,
+    //Certificate carries no per-policy hashes
+    MissingPolicyManifestError,
+    //Policy manifest has no implementation of the policy
+    UnknownPolicyError,
+    PolicyImplMismatchError,
+    //Report verifies, but the appraisal policy rejected it. Holds the outcome of every rule
+    AppraisalRejectedError(crate::appraisal::Appraisal),
+    //Certificates of two binaries list the same service
+    DuplicateServiceError(ServiceName),
+}
+
+#[cfg(feature="attest")]
+impl AttestErrors {
+    ///Whether the failure comes from reaching the sidecar rather than from the attestation
+    ///itself, so that retrying may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            AttestErrors::TransportError(_) | AttestErrors::NetworkError(_)
+        ) || matches!(self, AttestErrors::AttestationFailedError(failure) if failure.is_transient())
+    }
+
+    ///Client error for a failure reported by the sidecar, on a request for `service_name`.
+    pub fn from_failure(failure: AttestationFailure, service_name: ServiceName) -> Self {
+        match failure {
+            AttestationFailure::UnknownServiceError => AttestErrors::UnknownServiceError(service_name),
+            AttestationFailure::CertificateRevokedError => AttestErrors::CertificateRevokedError,
+            failure => AttestErrors::AttestationFailedError(failure),
+        }
+    }
+}
+
+#[cfg(feature="attest")]
+impl Display for AttestErrors {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AttestErrors::IoError(_) => write!(f, "I/O error"),
+            AttestErrors::KeyError(_) => write!(f, "could not load key"),
+            AttestErrors::ServiceMismatchError => {
+                write!(f, "service name does not match the certificate or report")
+            }
+            AttestErrors::NetworkError(_) => write!(f, "attestation RPC failed"),
+            AttestErrors::AttestDataMalformedError(_) => write!(f, "malformed attestation data"),
+            AttestErrors::ConfigError(reason) => write!(f, "invalid configuration: {}", reason),
+            AttestErrors::CryptoError => write!(f, "cryptographic operation failed"),
+            AttestErrors::TransportError(_) => write!(f, "could not connect to the sidecar"),
+            AttestErrors::CodecError(e) => write!(f, "{}", e),
+            AttestErrors::UnknownServiceError(service) => {
+                write!(f, "no binary is known to host service {}", service)
+            }
+            AttestErrors::AttestationSignatureError => {
+                write!(f, "attestation report signature is invalid")
+            }
+            AttestErrors::BinaryHashMismatchError { expected, reported } => write!(
+                f,
+                "running binary hash {} does not match certified hash {}",
+                reported.0, expected.0
+            ),
+            AttestErrors::NonceMismatchError => {
+                write!(f, "attestation report does not answer the request nonce")
+            }
+            AttestErrors::KeyShareMismatchError => {
+                write!(f, "attestation report does not cover the client key share")
+            }
+            AttestErrors::ReplayedReportError => write!(f, "attestation report was replayed"),
+            AttestErrors::AttestationFailedError(failure) => {
+                write!(f, "sidecar could not attest the service: {}", failure)
+            }
+            AttestErrors::ClientIdMismatchError => {
+                write!(f, "rekeyed report was made for another client id")
+            }
+            AttestErrors::LegacyCertificateError => write!(f, "legacy v1 certificates are not accepted"),
+            AttestErrors::UnsupportedCertificateVersion(version) => {
+                write!(f, "unsupported certificate version {}", version)
+            }
+            AttestErrors::MalformedCertificateError => write!(f, "malformed certificate"),
+            AttestErrors::MissingCertificateKey => write!(f, "no certificate issuer key is loaded"),
+            AttestErrors::CertificateSignatureError => write!(f, "certificate signature is invalid"),
+            AttestErrors::CertificateExpiredError => {
+                write!(f, "certificate is outside of its validity window")
+            }
+            AttestErrors::UnknownCertificateError => write!(f, "no certificate is held for the service"),
+            AttestErrors::CertificateMismatchError => {
+                write!(f, "certificate differs from the locally held one")
+            }
+            AttestErrors::UnknownIssuerError => write!(f, "certificate issuer key is not trusted"),
+            AttestErrors::IssuerKeyExpiredError => {
+                write!(f, "issuer key is outside of its validity window")
+            }
+            AttestErrors::RevocationSignatureError => {
+                write!(f, "revocation list signature is invalid")
+            }
+            AttestErrors::RevocationIssuerError => {
+                write!(f, "revocation list is not signed by a root key")
+            }
+            AttestErrors::CertificateRevokedError => write!(f, "certificate is revoked"),
+            AttestErrors::AuthoritySignatureError => {
+                write!(f, "authority certificate signature is invalid")
+            }
+            AttestErrors::AuthorityChainError => {
+                write!(f, "authority certificate is not signed by a root key")
+            }
+            AttestErrors::AuthorityScopeError => {
+                write!(f, "service is outside of the issuing authority scope")
+            }
+            AttestErrors::CoseError(_) => write!(f, "invalid COSE envelope"),
+            AttestErrors::MissingInclusionProofError => {
+                write!(f, "certificate carries no transparency log inclusion proof")
+            }
+            AttestErrors::UnknownLogKeyError => write!(f, "tree head log key is not trusted"),
+            AttestErrors::TreeHeadSignatureError => write!(f, "tree head signature is invalid"),
+            AttestErrors::InclusionProofError => write!(f, "transparency log inclusion proof is invalid"),
+            AttestErrors::UnpinnedServiceError => write!(f, "no policy hash is pinned for the service"),
+            AttestErrors::PolicyPinMismatchError => {
+                write!(f, "certificate policy hash does not match the pinned one")
+            }
+            AttestErrors::MissingPolicyManifestError => {
+                write!(f, "certificate carries no policy manifest")
+            }
+            AttestErrors::UnknownPolicyError => write!(f, "policy is not implemented by the binary"),
+            AttestErrors::PolicyImplMismatchError => {
+                write!(f, "policy implementation hash does not match the expected one")
+            }
+            AttestErrors::AppraisalRejectedError(appraisal) => {
+                write!(f, "appraisal policy rejected the report: {}", appraisal)
+            }
+            AttestErrors::DuplicateServiceError(service) => {
+                write!(f, "service {} is hosted by several certified binaries", service)
+            }
+        }
+    }
+}
+
+#[cfg(feature="attest")]
+impl std::error::Error for AttestErrors {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AttestErrors::IoError(e) | AttestErrors::TransportError(e) => Some(e),
+            AttestErrors::KeyError(e) => Some(e),
+            AttestErrors::NetworkError(e) => Some(e),
+            AttestErrors::AttestDataMalformedError(e) => Some(e),
+            AttestErrors::CoseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature="attest")]
+impl From<crate::verify::VerifyError> for AttestErrors {
+    fn from(value: crate::verify::VerifyError) -> Self {
+        use crate::verify::VerifyError;
+        match value {
+            VerifyError::LegacyCertificateError => AttestErrors::LegacyCertificateError,
+            VerifyError::UnsupportedCertificateVersion(version) => {
+                AttestErrors::UnsupportedCertificateVersion(version)
+            }
+            VerifyError::MalformedCertificateError => AttestErrors::MalformedCertificateError,
+            VerifyError::CertificateSignatureError => AttestErrors::CertificateSignatureError,
+            VerifyError::CertificateExpiredError => AttestErrors::CertificateExpiredError,
+            VerifyError::MissingInclusionProofError => AttestErrors::MissingInclusionProofError,
+            VerifyError::TreeHeadSignatureError => AttestErrors::TreeHeadSignatureError,
+            VerifyError::InclusionProofError => AttestErrors::InclusionProofError,
+            VerifyError::RevocationSignatureError => AttestErrors::RevocationSignatureError,
+            VerifyError::AuthoritySignatureError => AttestErrors::AuthoritySignatureError,
+            VerifyError::ServiceMismatchError => AttestErrors::ServiceMismatchError,
+            VerifyError::NonceMismatchError => AttestErrors::NonceMismatchError,
+            VerifyError::KeyShareMismatchError => AttestErrors::KeyShareMismatchError,
+            VerifyError::BinaryHashMismatchError { expected, reported } => {
+                AttestErrors::BinaryHashMismatchError { expected, reported }
+            }
+            VerifyError::AttestationSignatureError => AttestErrors::AttestationSignatureError,
+            VerifyError::MissingPolicyManifestError => AttestErrors::MissingPolicyManifestError,
+            VerifyError::UnknownPolicyError => AttestErrors::UnknownPolicyError,
+            VerifyError::PolicyImplMismatchError => AttestErrors::PolicyImplMismatchError,
+        }
+    }
 }
 
 #[cfg(feature="attest")]
@@ -93,7 +691,7 @@ pub type AttestResult<T> = Result<T, AttestErrors>;
 pub struct ClientId(pub(crate) usize);
 
 impl Display for ClientId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
@@ -109,3 +707,38 @@ impl From<ClientId> for usize {
         value.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_codes_are_stable() {
+        let assigned = [
+            (1, AttestationFailure::UnknownServiceError),
+            (2, AttestationFailure::MissingCertificateError),
+            (3, AttestationFailure::BinaryNotRunningError),
+            (4, AttestationFailure::CertificateRevokedError),
+            (5, AttestationFailure::ServiceUnavailableError),
+            (6, AttestationFailure::MissingCoseCertificateError),
+            (7, AttestationFailure::MalformedKeyShareError),
+            (8, AttestationFailure::RekeyRefusedError),
+            (9, AttestationFailure::InternalError),
+            (10, AttestationFailure::CallerIdentityError),
+            (11, AttestationFailure::AccessDeniedError),
+        ];
+        for (code, failure) in assigned {
+            assert_eq!(failure.code(), code);
+            assert_eq!(AttestationFailure::from(code), failure);
+            assert_eq!(serde_json::to_string(&failure).unwrap(), code.to_string());
+        }
+    }
+
+    #[test]
+    fn unknown_failure_codes_are_kept() {
+        let failure: AttestationFailure = serde_json::from_str("42").unwrap();
+        assert_eq!(failure, AttestationFailure::UnknownFailureError(42));
+        assert_eq!(u16::from(failure), 42);
+        assert!(!failure.is_transient());
+    }
+}
@@ -0,0 +1,535 @@
+//!Verification of certificates, attestation reports and the structures that vouch for them,
+//!without filesystem, configuration or network access.
+//!
+//!Everything here only depends on `alloc`, so that constrained components can check Tahini
+//!artifacts they received by other means. Signature checks go through `SignatureVerifier`,
+//!which lets such components bring their own Ed25519 implementation. Picking the trusted key,
+//!and the current time, is left to the caller.
+
+use core::fmt;
+
+use crate::authority::AuthorityCertificate;
+use crate::canonical::SigningPayload;
+use crate::revocation::RevocationList;
+use crate::transparency::SignedTreeHead;
+use crate::types::{
+    BinHash, CertificateVersion, DynamicAttestationReport, ServiceName, TahiniCertificate,
+};
+
+///Public key able to check Ed25519 signatures.
+pub trait SignatureVerifier {
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+impl<T: SignatureVerifier + ?Sized> SignatureVerifier for &T {
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> bool {
+        (**self).verify_signature(message, signature)
+    }
+}
+
+#[cfg(feature="std")]
+impl<B: AsRef<[u8]>> SignatureVerifier for aws_lc_rs::signature::UnparsedPublicKey<B> {
+    fn verify_signature(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.verify(message, signature).is_ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    //Certificate is v1 and legacy certificates are not accepted
+    LegacyCertificateError,
+    UnsupportedCertificateVersion(CertificateVersion),
+    //Certificate fields do not match its declared version, or are not valid hex
+    MalformedCertificateError,
+    CertificateSignatureError,
+    CertificateExpiredError,
+    MissingInclusionProofError,
+    TreeHeadSignatureError,
+    InclusionProofError,
+    RevocationSignatureError,
+    AuthoritySignatureError,
+    //Report is for another service than the requested one
+    ServiceMismatchError,
+    NonceMismatchError,
+    //Report was made for another key share than the one the client sent
+    KeyShareMismatchError,
+    BinaryHashMismatchError { expected: BinHash, reported: BinHash },
+    AttestationSignatureError,
+    //Certificate carries no per-policy hashes
+    MissingPolicyManifestError,
+    //Policy manifest has no implementation of the policy
+    UnknownPolicyError,
+    PolicyImplMismatchError,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::LegacyCertificateError => write!(f, "legacy v1 certificates are not accepted"),
+            VerifyError::UnsupportedCertificateVersion(version) => {
+                write!(f, "unsupported certificate version {}", version)
+            }
+            VerifyError::MalformedCertificateError => write!(f, "malformed certificate"),
+            VerifyError::CertificateSignatureError => write!(f, "certificate signature is invalid"),
+            VerifyError::CertificateExpiredError => {
+                write!(f, "certificate is outside of its validity window")
+            }
+            VerifyError::MissingInclusionProofError => {
+                write!(f, "certificate carries no transparency log inclusion proof")
+            }
+            VerifyError::TreeHeadSignatureError => write!(f, "tree head signature is invalid"),
+            VerifyError::InclusionProofError => write!(f, "transparency log inclusion proof is invalid"),
+            VerifyError::RevocationSignatureError => write!(f, "revocation list signature is invalid"),
+            VerifyError::AuthoritySignatureError => {
+                write!(f, "authority certificate signature is invalid")
+            }
+            VerifyError::ServiceMismatchError => {
+                write!(f, "service name does not match the certificate or report")
+            }
+            VerifyError::NonceMismatchError => {
+                write!(f, "attestation report does not answer the request nonce")
+            }
+            VerifyError::KeyShareMismatchError => {
+                write!(f, "attestation report does not cover the client key share")
+            }
+            VerifyError::BinaryHashMismatchError { expected, reported } => write!(
+                f,
+                "running binary hash {} does not match certified hash {}",
+                reported.0, expected.0
+            ),
+            VerifyError::AttestationSignatureError => {
+                write!(f, "attestation report signature is invalid")
+            }
+            VerifyError::MissingPolicyManifestError => {
+                write!(f, "certificate carries no policy manifest")
+            }
+            VerifyError::UnknownPolicyError => write!(f, "policy is not implemented by the binary"),
+            VerifyError::PolicyImplMismatchError => {
+                write!(f, "policy implementation hash does not match the expected one")
+            }
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl std::error::Error for VerifyError {}
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+///Checks that the certificate format is a supported one, and that its fields match it.
+pub fn check_version(certificate: &TahiniCertificate, allow_legacy: bool) -> VerifyResult<()> {
+    match certificate.version {
+        CertificateVersion::V1 if !allow_legacy => return Err(VerifyError::LegacyCertificateError),
+        CertificateVersion::V1
+        | CertificateVersion::V2
+        | CertificateVersion::V3
+        | CertificateVersion::V4 => {}
+        version => return Err(VerifyError::UnsupportedCertificateVersion(version)),
+    }
+    if !certificate.is_well_formed() {
+        return Err(VerifyError::MalformedCertificateError);
+    }
+    Ok(())
+}
+
+///Checks that `now` (seconds since UNIX epoch) is within the certificate validity window.
+///v1 certificates carry no window and are always valid.
+pub fn check_validity(certificate: &TahiniCertificate, now: u64) -> VerifyResult<()> {
+    match &certificate.issuance {
+        Some(issuance) if now < issuance.issued_at || now >= issuance.expires_at => {
+            Err(VerifyError::CertificateExpiredError)
+        }
+        _ => Ok(()),
+    }
+}
+
+///Checks the certificate signature against the given issuer key.
+pub fn verify_certificate_signature(
+    certificate: &TahiniCertificate,
+    key: &impl SignatureVerifier,
+) -> VerifyResult<()> {
+    let signed_data = certificate
+        .signed_data()
+        .map_err(|_| VerifyError::MalformedCertificateError)?;
+    let signature = hex::decode(&certificate.signature.0)
+        .map_err(|_| VerifyError::MalformedCertificateError)?;
+    if !key.verify_signature(&signed_data, &signature) {
+        return Err(VerifyError::CertificateSignatureError);
+    }
+    Ok(())
+}
+
+///Checks the certificate format, validity window and signature against the given issuer key.
+///Does not check the key against the issuer named by the certificate, nor revocation.
+pub fn verify_certificate(
+    certificate: &TahiniCertificate,
+    key: &impl SignatureVerifier,
+    now: u64,
+    allow_legacy: bool,
+) -> VerifyResult<()> {
+    check_version(certificate, allow_legacy)?;
+    check_validity(certificate, now)?;
+    verify_certificate_signature(certificate, key)
+}
+
+pub fn verify_tree_head(tree_head: &SignedTreeHead, key: &impl SignatureVerifier) -> VerifyResult<()> {
+    let signature =
+        hex::decode(&tree_head.signature.0).map_err(|_| VerifyError::TreeHeadSignatureError)?;
+    if !key.verify_signature(&tree_head.signing_payload(), &signature) {
+        return Err(VerifyError::TreeHeadSignatureError);
+    }
+    Ok(())
+}
+
+///Checks that the certificate carries an inclusion proof against a tree head signed by the
+///given log key.
+pub fn verify_inclusion(
+    certificate: &TahiniCertificate,
+    log_key: &impl SignatureVerifier,
+) -> VerifyResult<()> {
+    let proof = certificate
+        .transparency
+        .as_ref()
+        .ok_or(VerifyError::MissingInclusionProofError)?;
+    verify_tree_head(&proof.tree_head, log_key)?;
+    if !proof.verify_inclusion(certificate) {
+        return Err(VerifyError::InclusionProofError);
+    }
+    Ok(())
+}
+
+pub fn verify_revocation_list(list: &RevocationList, key: &impl SignatureVerifier) -> VerifyResult<()> {
+    let signature =
+        hex::decode(&list.signature.0).map_err(|_| VerifyError::RevocationSignatureError)?;
+    if !key.verify_signature(&list.signing_payload(), &signature) {
+        return Err(VerifyError::RevocationSignatureError);
+    }
+    Ok(())
+}
+
+pub fn verify_authority(
+    certificate: &AuthorityCertificate,
+    root_key: &impl SignatureVerifier,
+) -> VerifyResult<()> {
+    let signature =
+        hex::decode(&certificate.signature.0).map_err(|_| VerifyError::AuthoritySignatureError)?;
+    if !root_key.verify_signature(&certificate.signing_payload(), &signature) {
+        return Err(VerifyError::AuthoritySignatureError);
+    }
+    Ok(())
+}
+
+///Checks that the certificate attests to an implementation of `policy` with the expected hash.
+///The certificate itself has to be verified first, as its signature covers the manifest.
+pub fn verify_policy(
+    certificate: &TahiniCertificate,
+    policy: &str,
+    expected_hash: &str,
+) -> VerifyResult<()> {
+    let manifest = certificate
+        .policy_manifest
+        .as_ref()
+        .ok_or(VerifyError::MissingPolicyManifestError)?;
+    let hash = manifest
+        .policy_hash(policy)
+        .ok_or(VerifyError::UnknownPolicyError)?;
+    if hash != expected_hash {
+        return Err(VerifyError::PolicyImplMismatchError);
+    }
+    Ok(())
+}
+
+///Checks that the report answers a request for `service_name` with `nonce` and
+///`client_key_share`, that the certified binary hosts that service and is the running one, and
+///that the report, client id included, is signed by the sidecar key.
+///The certificate embedded in the report has to be verified separately.
+pub fn verify_report(
+    report: &DynamicAttestationReport,
+    service_name: &ServiceName,
+    nonce: u128,
+    client_key_share: &[u8],
+    sidecar_key: &impl SignatureVerifier,
+) -> VerifyResult<()> {
+    if &report.service_name != service_name || !report.certificate.hosts(service_name) {
+        return Err(VerifyError::ServiceMismatchError);
+    }
+    if report.nonce != nonce {
+        return Err(VerifyError::NonceMismatchError);
+    }
+    if report.client_key_share != client_key_share {
+        return Err(VerifyError::KeyShareMismatchError);
+    }
+    if report.current_bin_hash != report.certificate.binary_hash {
+        return Err(VerifyError::BinaryHashMismatchError {
+            expected: report.certificate.binary_hash.clone(),
+            reported: report.current_bin_hash.clone(),
+        });
+    }
+    let data = report.attestation_data();
+    let signature =
+        hex::decode(&report.signature.0).map_err(|_| VerifyError::AttestationSignatureError)?;
+    if !sidecar_key.verify_signature(&data.signing_payload(), &signature) {
+        return Err(VerifyError::AttestationSignatureError);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, string::ToString, vec};
+
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+    use super::*;
+    use crate::manifest::PolicyManifest;
+    use crate::types::{ClientId, IssuanceInfo, KeyId, PolicyHash, Signature, ToolchainInfo};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn manifest() -> PolicyManifest {
+        PolicyManifest {
+            dependency_hashes: BTreeMap::from([("tahini_policies".to_string(), "aa".repeat(32))]),
+            local_summary_hash: "bb".repeat(32),
+            local_impls_hashes: BTreeMap::from([(
+                "<impl Policy for ConsentPolicy>".to_string(),
+                "cc".repeat(32),
+            )]),
+        }
+    }
+
+    //Unsigned certificate with the fields of `version`
+    fn certificate(version: CertificateVersion) -> TahiniCertificate {
+        let issuance = IssuanceInfo {
+            issuer: KeyId("0011223344556677".to_string()),
+            issued_at: 1_700_000_000,
+            expires_at: 1_800_000_000,
+            toolchain: ToolchainInfo {
+                generator: "certificate_generation 0.1.0".to_string(),
+                rustc: "rustc 1.87.0".to_string(),
+            },
+        };
+        TahiniCertificate {
+            version,
+            service_name: ServiceName("hoodini-server".to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: (version != CertificateVersion::V1).then_some(issuance),
+            policy_manifest: (version.0 >= CertificateVersion::V3.0).then(manifest),
+            services: if version == CertificateVersion::V4 {
+                vec![
+                    ServiceName("hoodini-server".to_string()),
+                    ServiceName("hoodini-admin".to_string()),
+                ]
+            } else {
+                Vec::new()
+            },
+            signature: Signature(String::new()),
+            transparency: None,
+        }
+    }
+
+    fn sign(certificate: &mut TahiniCertificate, key: &Ed25519KeyPair) {
+        certificate.signature = key.sign(&certificate.signed_data().unwrap()).into();
+    }
+
+    const VERSIONS: [CertificateVersion; 4] = [
+        CertificateVersion::V1,
+        CertificateVersion::V2,
+        CertificateVersion::V3,
+        CertificateVersion::V4,
+    ];
+
+    #[test]
+    fn signed_certificates_verify() {
+        let key = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, key.public_key().as_ref().to_vec());
+        for version in VERSIONS {
+            let mut certificate = certificate(version);
+            assert!(certificate.is_well_formed(), "{} certificate", version);
+            sign(&mut certificate, &key);
+            assert_eq!(
+                verify_certificate_signature(&certificate, &public_key),
+                Ok(()),
+                "{} certificate",
+                version
+            );
+            if version != CertificateVersion::V1 {
+                assert_eq!(certificate.signed_data().unwrap(), certificate.signing_payload());
+            }
+        }
+    }
+
+    #[test]
+    fn other_key_is_rejected() {
+        let key = key_pair();
+        let other = key_pair();
+        let other_key = UnparsedPublicKey::new(&ED25519, other.public_key().as_ref().to_vec());
+        for version in VERSIONS {
+            let mut certificate = certificate(version);
+            sign(&mut certificate, &key);
+            assert_eq!(
+                verify_certificate_signature(&certificate, &other_key),
+                Err(VerifyError::CertificateSignatureError)
+            );
+        }
+    }
+
+    #[test]
+    fn changed_fields_are_rejected() {
+        let key = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, key.public_key().as_ref().to_vec());
+        type Change = fn(&mut TahiniCertificate);
+        let changes: [(&str, CertificateVersion, Change); 12] = [
+            ("policy hash", CertificateVersion::V1, |c| c.policy_hash.0 = "03".repeat(32)),
+            ("binary hash", CertificateVersion::V1, |c| c.binary_hash.0 = "03".repeat(32)),
+            ("service name", CertificateVersion::V2, |c| {
+                c.service_name.0 = "other-server".to_string()
+            }),
+            ("version", CertificateVersion::V2, |c| c.version = CertificateVersion::V3),
+            ("issuer", CertificateVersion::V2, |c| {
+                c.issuance.as_mut().unwrap().issuer.0 = "7766554433221100".to_string()
+            }),
+            ("issuance", CertificateVersion::V2, |c| {
+                c.issuance.as_mut().unwrap().issued_at -= 1
+            }),
+            ("expiry", CertificateVersion::V2, |c| {
+                c.issuance.as_mut().unwrap().expires_at += 1
+            }),
+            ("toolchain", CertificateVersion::V2, |c| {
+                c.issuance.as_mut().unwrap().toolchain.rustc = "rustc 1.88.0".to_string()
+            }),
+            ("manifest", CertificateVersion::V3, |c| {
+                c.policy_manifest.as_mut().unwrap().local_summary_hash = "dd".repeat(32)
+            }),
+            ("manifest entry", CertificateVersion::V4, |c| {
+                c.policy_manifest
+                    .as_mut()
+                    .unwrap()
+                    .local_impls_hashes
+                    .insert("<impl Policy for OtherPolicy>".to_string(), "ee".repeat(32));
+            }),
+            ("dropped manifest", CertificateVersion::V4, |c| c.policy_manifest = None),
+            ("services", CertificateVersion::V4, |c| {
+                c.services.push(ServiceName("hoodini-extra".to_string()))
+            }),
+        ];
+        for (field, first_version, change) in changes {
+            for version in VERSIONS.into_iter().filter(|v| v.0 >= first_version.0) {
+                //The version can't be changed to itself
+                if field == "version" && version != first_version {
+                    continue;
+                }
+                let mut certificate = certificate(version);
+                sign(&mut certificate, &key);
+                change(&mut certificate);
+                assert_eq!(
+                    verify_certificate_signature(&certificate, &public_key),
+                    Err(VerifyError::CertificateSignatureError),
+                    "{} changed in {} certificate",
+                    field,
+                    version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_signature_is_rejected() {
+        let key = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, key.public_key().as_ref().to_vec());
+        let mut certificate = certificate(CertificateVersion::V4);
+        certificate.signature = Signature("not hex".to_string());
+        assert_eq!(
+            verify_certificate_signature(&certificate, &public_key),
+            Err(VerifyError::MalformedCertificateError)
+        );
+    }
+
+    //Report for hoodini-server, from the v4 certificate that also hosts hoodini-admin
+    fn report(sidecar: &Ed25519KeyPair) -> DynamicAttestationReport {
+        let mut report = DynamicAttestationReport {
+            certificate: certificate(CertificateVersion::V4),
+            nonce: 42,
+            service_name: ServiceName("hoodini-server".to_string()),
+            current_bin_hash: BinHash("02".repeat(32)),
+            client_key_share: vec![3; 32],
+            server_key_share: vec![4; 32],
+            client_id: ClientId::from(7),
+            signature: Signature(String::new()),
+        };
+        report.signature = sidecar.sign(&report.attestation_data().signing_payload()).into();
+        report
+    }
+
+    #[test]
+    fn reports_answer_their_request() {
+        let sidecar = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, sidecar.public_key().as_ref().to_vec());
+        let report = report(&sidecar);
+        let server = ServiceName("hoodini-server".to_string());
+        assert_eq!(verify_report(&report, &server, 42, &[3; 32], &public_key), Ok(()));
+
+        //Hosted by the same binary, but not the service the report is for
+        let admin = ServiceName("hoodini-admin".to_string());
+        assert_eq!(
+            verify_report(&report, &admin, 42, &[3; 32], &public_key),
+            Err(VerifyError::ServiceMismatchError)
+        );
+        assert_eq!(
+            verify_report(&report, &server, 43, &[3; 32], &public_key),
+            Err(VerifyError::NonceMismatchError)
+        );
+        assert_eq!(
+            verify_report(&report, &server, 42, &[5; 32], &public_key),
+            Err(VerifyError::KeyShareMismatchError)
+        );
+    }
+
+    #[test]
+    fn reports_for_unhosted_services_are_rejected() {
+        let sidecar = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, sidecar.public_key().as_ref().to_vec());
+        let mut report = report(&sidecar);
+        let other = ServiceName("hoodini-other".to_string());
+        report.service_name = other.clone();
+        report.signature = sidecar.sign(&report.attestation_data().signing_payload()).into();
+        assert_eq!(
+            verify_report(&report, &other, 42, &[3; 32], &public_key),
+            Err(VerifyError::ServiceMismatchError)
+        );
+    }
+
+    #[test]
+    fn reports_of_other_binaries_or_signers_are_rejected() {
+        let sidecar = key_pair();
+        let public_key = UnparsedPublicKey::new(&ED25519, sidecar.public_key().as_ref().to_vec());
+        let server = ServiceName("hoodini-server".to_string());
+
+        let mut other_binary = report(&sidecar);
+        other_binary.current_bin_hash = BinHash("03".repeat(32));
+        other_binary.signature =
+            sidecar.sign(&other_binary.attestation_data().signing_payload()).into();
+        assert_eq!(
+            verify_report(&other_binary, &server, 42, &[3; 32], &public_key),
+            Err(VerifyError::BinaryHashMismatchError {
+                expected: BinHash("02".repeat(32)),
+                reported: BinHash("03".repeat(32)),
+            })
+        );
+
+        let mut other_client = report(&sidecar);
+        other_client.client_id = ClientId::from(8);
+        assert_eq!(
+            verify_report(&other_client, &server, 42, &[3; 32], &public_key),
+            Err(VerifyError::AttestationSignatureError)
+        );
+        assert_eq!(
+            verify_report(&report(&key_pair()), &server, 42, &[3; 32], &public_key),
+            Err(VerifyError::AttestationSignatureError)
+        );
+    }
+}
@@ -1,28 +1,60 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
-    usize,
 };
 
 use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
 use lazy_static::lazy_static;
 
+pub use hoodini_core::record::{
+    self, RecordError, RecordLimits, RecordOpener, RecordResult, RecordSealer, server_records,
+};
+pub use hoodini_core::session::{
+    CallerCredential, CallerIdentity, ConnectionKeys, RevocationReason, SessionKeys, SessionMessage,
+};
+use hoodini_core::session::unix_now;
 pub use hoodini_core::types::ClientId;
 use clap::Parser;
 use std::thread;
 
+///Live session of a client, as handed over by the sidecar.
+pub struct Session {
+    pub keys: SessionKeys,
+    //Seconds since UNIX epoch
+    pub expires_at: u64,
+    //Remaining number of connections the keys may be fetched for
+    pub budget: u32,
+    //Connections already served, whose record keys must not be handed out again
+    pub connections: HashSet<u32>,
+    //Attested binary on the calling end, if the client presented its credential
+    pub caller: Option<CallerIdentity>,
+}
+
+impl Session {
+    fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
 lazy_static! {
-    pub static ref CLIENT_MAP: Arc<RwLock<HashMap<ClientId, RandomizedNonceKey>>> =
+    pub static ref CLIENT_MAP: Arc<RwLock<HashMap<ClientId, Session>>> =
         Arc::new(RwLock::new(HashMap::new()));
+    //Credential the sidecar issued this binary at launch
+    static ref CALLER_CREDENTIAL: RwLock<Option<CallerCredential>> = RwLock::new(None);
 }
 
-//Pre-main server pipe construction and background thread handling
+//Pre-main server pipe construction and background thread handling. Without the sidecar
+//arguments, e.g. under cargo test, no session can be received and nothing is set up
 #[ctor::ctor]
 pub unsafe fn client_map_state_constructor() {
-    let args = SidecarCliArgs::parse();
+    let Ok(args) = SidecarCliArgs::try_parse() else {
+        println!("Not launched by the sidecar, no sessions will be received");
+        return;
+    };
     let fifo_read = File::options()
         .read(true)
         .open(&args.fifo_path)
@@ -30,15 +62,26 @@ pub unsafe fn client_map_state_constructor() {
     thread::spawn(move || {
         let kek_hex = args.kek_hex;
         let fifo_path = args.fifo_path;
-        let read_handler = FifoReadHandle::new(fifo_path, kek_hex);
+        let mut read_handler = FifoReadHandle::new(fifo_path, kek_hex);
         loop {
-            //read_session_key is blocking on actually reading a key
-            let (client_id, session_key) = read_handler.read_session_key();
-            //We only acquire write lock if we have a key to write
-            CLIENT_MAP
-                .write()
-                .expect("Couldn't get a write lock on the client map")
-                .insert(client_id, session_key);
+            //read_message is blocking on actually reading a message
+            match read_handler.read_message() {
+                //We only acquire write lock if we have a message to apply
+                Ok(message) => apply_message(
+                    &mut CLIENT_MAP
+                        .write()
+                        .expect("Couldn't get a write lock on the client map"),
+                    message,
+                ),
+                Err(e @ (FifoReadError::ReadError(_) | FifoReadError::ClosedError)) => {
+                    println!(
+                        "Couldn't read from the sidecar FIFO, no more sessions will be received: {}",
+                        e
+                    );
+                    break;
+                }
+                Err(e) => println!("Skipping session message: {}", e),
+            }
         }
     });
 }
@@ -51,11 +94,41 @@ struct SidecarCliArgs {
     kek_hex: String,
 }
 
+///Failure to read a message of the sidecar.
+#[derive(Debug)]
+pub enum FifoReadError {
+    //The FIFO can't be read from anymore
+    ReadError(io::Error),
+    //The sidecar closed its end of the FIFO
+    ClosedError,
+    //Line is not a hex (`Nonce`, `Cipher`) pair
+    MalformedLineError,
+    //Cipher was not sealed with the KEK
+    DecryptionError,
+    MalformedMessageError(serde_json::Error),
+}
+
+impl fmt::Display for FifoReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FifoReadError::ReadError(e) => write!(f, "could not read from the FIFO: {}", e),
+            FifoReadError::ClosedError => write!(f, "the sidecar closed the FIFO"),
+            FifoReadError::MalformedLineError => write!(f, "malformed line"),
+            FifoReadError::DecryptionError => write!(f, "could not decrypt the line"),
+            FifoReadError::MalformedMessageError(e) => write!(f, "malformed session message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FifoReadError {}
+
 ///Pipe handler on the server (read) side.
 ///The channel is protected by some key that is passed via command-line to the server.
-struct FifoReadHandle {
+struct FifoReadHandle<R = File> {
     kek: RandomizedNonceKey,
-    handle: File,
+    //Kept across reads: the sidecar may write several lines at once, and the ones read ahead of
+    //the current message are buffered here
+    reader: BufReader<R>,
 }
 
 impl FifoReadHandle {
@@ -64,80 +137,352 @@ impl FifoReadHandle {
             .read(true)
             .open(fifo_path.as_ref())
             .expect("Couldn't open FIFO as read");
+        Self::from_reader(fifo_read, &hex_kek)
+    }
+}
+
+impl<R: Read> FifoReadHandle<R> {
+    fn from_reader(reader: R, hex_kek: &str) -> Self {
         let kek_bytes = hex::decode(hex_kek).expect("Malformed kek received from command line");
         Self {
             kek: RandomizedNonceKey::new(&AES_256_GCM, &kek_bytes)
                 .expect("Couldn't generate AES KEK key from material"),
-            handle: fifo_read,
+            reader: BufReader::new(reader),
         }
     }
 
-    ///Reads a session information from the pipe.
-    ///A line contains a (`Nonce`, `Cipher`, `ClientId`) triplet.
-    ///The `Nonce` and the `Cipher` are under hex representations.
-    ///We only acquire map lock on a successful line parsing.
-    fn read_session_key(&self) -> (ClientId, RandomizedNonceKey) {
-        let mut reader = BufReader::new(&self.handle);
+    ///Reads a message from the pipe.
+    ///A line contains a (`Nonce`, `Cipher`) pair, under hex representations.
+    ///The `Cipher` is a JSON `SessionMessage`.
+    ///A malformed line is consumed, so that the next call reads the following one.
+    ///Reading blocks while the sidecar holds the FIFO open, and fails once it closed it.
+    fn read_message(&mut self) -> Result<SessionMessage, FifoReadError> {
         let mut buf = String::new();
-        loop {
-            match reader.read_line(&mut buf) {
-                Ok(n) => {
-                    if n > 0 {
-                        std::io::stdout().flush();
-                        let splitted_line: Vec<_> = buf.split(",").collect();
-                        if splitted_line.len() != 3 {
-                            panic!("Line received from FIFO is malformed")
-                        }
-                        let (nonce_hex, cipher_hex, client_id) =
-                            (splitted_line[0], splitted_line[1], splitted_line[2]);
-                        //Decode to slice handles string size mismatch, so we can ensure the nonce is
-                        //well-formed and full after decoding
-                        let mut nonce: [u8; 12] = [0u8; 12];
-                        hex::decode_to_slice(nonce_hex, &mut nonce).expect("Malformed nonce");
-
-                        let nonce = Nonce::assume_unique_for_key(nonce);
-                        let mut cipher_vec = hex::decode(cipher_hex).expect("Cipher hex malformed");
-                        let key_material = self
-                            .kek
-                            .open_in_place(nonce, Aad::empty(), &mut cipher_vec)
-                            .expect("Couldn't decrypt cipher");
-
-
-                        let key = RandomizedNonceKey::new(&AES_256_GCM, &key_material)
-                            .expect("Couldn't generate session key from derived key material");
-
-                        let client_id = ClientId::from(
-                            usize::from_str_radix(client_id.trim_end_matches("\n"), 10)
-                                .expect("Client ID malformed"),
-                        );
-                        buf.clear();
-
-                        return (client_id, key);
-                    }
+        let n = self
+            .reader
+            .read_line(&mut buf)
+            .map_err(FifoReadError::ReadError)?;
+        if n == 0 {
+            return Err(FifoReadError::ClosedError);
+        }
+        self.open_line(buf.trim_end_matches("\n"))
+    }
+
+    fn open_line(&self, line: &str) -> Result<SessionMessage, FifoReadError> {
+        let (nonce_hex, cipher_hex) = line
+            .split_once(",")
+            .ok_or(FifoReadError::MalformedLineError)?;
+        //Decode to slice handles string size mismatch, so we can ensure the nonce is
+        //well-formed and full after decoding
+        let mut nonce: [u8; 12] = [0u8; 12];
+        hex::decode_to_slice(nonce_hex, &mut nonce).map_err(|_| FifoReadError::MalformedLineError)?;
+
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        let mut cipher_vec =
+            hex::decode(cipher_hex).map_err(|_| FifoReadError::MalformedLineError)?;
+        let plaintext = self
+            .kek
+            .open_in_place(nonce, Aad::empty(), &mut cipher_vec)
+            .map_err(|_| FifoReadError::DecryptionError)?;
+
+        serde_json::from_slice(plaintext).map_err(FifoReadError::MalformedMessageError)
+    }
+}
+
+
+//Applies a message of the sidecar. Expired sessions are dropped along the way
+fn apply_message(sessions: &mut HashMap<ClientId, Session>, message: SessionMessage) {
+    let now = unix_now();
+    sessions.retain(|_, session| !session.is_expired(now));
+    match message {
+        SessionMessage::Open {
+            client_id,
+            keys,
+            expires_at,
+            budget,
+            caller,
+        } => {
+            sessions.insert(
+                client_id,
+                Session {
+                    keys,
+                    expires_at,
+                    budget,
+                    connections: HashSet::new(),
+                    caller: caller.map(|caller| *caller),
+                },
+            );
+        }
+        SessionMessage::Credential { credential } => {
+            *CALLER_CREDENTIAL
+                .write()
+                .expect("Couldn't get a write lock on the caller credential") = Some(credential);
+        }
+        SessionMessage::Revoke { client_ids, reason } => {
+            for client_id in client_ids.iter() {
+                if sessions.remove(client_id).is_some() {
+                    println!("Evicted session of client {} ({:?})", client_id, reason);
                 }
-                Err(_) => panic!("Couldn't read from the FIFO"),
             }
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    //No session for the client: never opened, revoked, or its budget is spent
+    UnknownClientError,
+    SessionExpiredError,
+    //The connection number was already served, its records would reuse nonces
+    ConnectionReusedError,
+    CryptoError,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::UnknownClientError => write!(f, "no live session for the client"),
+            SessionError::SessionExpiredError => write!(f, "session expired, the client must rekey"),
+            SessionError::ConnectionReusedError => {
+                write!(f, "connection number already used in this session")
+            }
+            SessionError::CryptoError => write!(f, "cryptographic operation failed"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
 
-///Reads the session for a given `ClientId`. 
-///In order to manage the session map size,
-///server handlers get a write lock on the map and delete their entry
-///from the map.
-///We will have to evaluate at some point if read locks are not better under stress.
-pub fn get_key_for_client(client_id: &ClientId) -> RandomizedNonceKey {
+///Reads the keys of connection `connection` of the session of a given `ClientId`, with the
+///identity of the calling binary when the client presented one. The server opens requests with
+///the client-to-server key, and seals responses with the server-to-client key.
+///Each connection number is served once, as its records restart at sequence 0, and spends one use
+///of the session budget. The session is removed from the map once its budget is spent or it
+///expired, and the client then has to rekey through the sidecar.
+pub fn get_key_for_client(
+    client_id: &ClientId,
+    connection: u32,
+) -> Result<(ConnectionKeys, Option<CallerIdentity>), SessionError> {
     let mut engine_lock = CLIENT_MAP
         .write()
-        .expect("Couldn't get a read lock on the client map");
-    //FIXME: Add error handling for non present client. Also note that the write lock poisons the
-    //entire map currently which is iffy.
-    //Currently, if a client is missing from the map (which could be either by race condition between
-    //sidecar->server and client->server comm, or by
-    //malicious client), the server is crashing.
-    let val = engine_lock
-        .remove_entry(client_id)
-        .expect("Client_id not found in the map");
-    val.1
+        .expect("Couldn't get a write lock on the client map");
+    let session = engine_lock
+        .get_mut(client_id)
+        .ok_or(SessionError::UnknownClientError)?;
+    if session.is_expired(unix_now()) {
+        engine_lock.remove(client_id);
+        return Err(SessionError::SessionExpiredError);
+    }
+    if session.connections.contains(&connection) {
+        return Err(SessionError::ConnectionReusedError);
+    }
+    let keys = session
+        .keys
+        .connection_keys(connection)
+        .map_err(|_| SessionError::CryptoError)?;
+    session.connections.insert(connection);
+    session.budget = session.budget.saturating_sub(1);
+    let grant = (keys, session.caller.clone());
+    if session.budget == 0 {
+        engine_lock.remove(client_id);
+    }
+    Ok(grant)
+}
+
+///Same as get_key_for_client, as a sealer for the responses to the client and an opener for its
+///requests on connection `connection`, the number the client got from
+///`ClientSession::next_connection`.
+pub fn get_records_for_client(
+    client_id: &ClientId,
+    connection: u32,
+) -> Result<(RecordSealer, RecordOpener, Option<CallerIdentity>), SessionError> {
+    let (keys, caller) = get_key_for_client(client_id, connection)?;
+    let (sealer, opener) = server_records(keys, client_id, RecordLimits::default())
+        .map_err(|_| SessionError::CryptoError)?;
+    Ok((sealer, opener, caller))
+}
+
+///Credential to present when this binary calls other services, see
+///`DynamicAttestationVerifier::with_caller_credential`. None until the sidecar sent it, right
+///after launch.
+pub fn caller_credential() -> Option<CallerCredential> {
+    CALLER_CREDENTIAL
+        .read()
+        .expect("Couldn't get a read lock on the caller credential")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hoodini_core::types::BinaryName;
+    use std::io::Cursor;
+
+    const KEK_HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    //Line as the sidecar writes it, see hoodini_sidecar::FifoWriterHandle::write_message
+    fn sealed_line(kek_hex: &str, message: &SessionMessage) -> String {
+        let kek = RandomizedNonceKey::new(&AES_256_GCM, &hex::decode(kek_hex).unwrap()).unwrap();
+        let mut cipher = serde_json::to_vec(message).unwrap();
+        let nonce = kek.seal_in_place_append_tag(Aad::empty(), &mut cipher).unwrap();
+        format!("{},{}\n", hex::encode(nonce.as_ref()), hex::encode(cipher))
+    }
+
+    fn revoke(client_id: usize) -> SessionMessage {
+        SessionMessage::Revoke {
+            client_ids: vec![ClientId::from(client_id)],
+            reason: RevocationReason::ClientBanned,
+        }
+    }
+
+    #[test]
+    fn lines_written_at_once_are_all_read() {
+        let lines = sealed_line(KEK_HEX, &revoke(1)) + &sealed_line(KEK_HEX, &revoke(2));
+        let mut handle = FifoReadHandle::from_reader(Cursor::new(lines), KEK_HEX);
+        assert_eq!(handle.read_message().unwrap(), revoke(1));
+        assert_eq!(handle.read_message().unwrap(), revoke(2));
+        assert!(matches!(handle.read_message(), Err(FifoReadError::ClosedError)));
+    }
+
+    //The sidecar sends the credential right after the first session opens
+    #[test]
+    fn open_then_credential_are_both_applied() {
+        let credential = CallerCredential {
+            bin_name: BinaryName::from("caller".to_string()),
+            secret: [7; 32],
+        };
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        let open = SessionMessage::Open {
+            client_id: ClientId::from(5),
+            keys: keys.clone(),
+            expires_at: unix_now() + 60,
+            budget: 3,
+            caller: None,
+        };
+        let lines = sealed_line(KEK_HEX, &open)
+            + &sealed_line(KEK_HEX, &SessionMessage::Credential {
+                credential: credential.clone(),
+            });
+        let mut handle = FifoReadHandle::from_reader(Cursor::new(lines), KEK_HEX);
+        let mut sessions = HashMap::new();
+        apply_message(&mut sessions, handle.read_message().unwrap());
+        apply_message(&mut sessions, handle.read_message().unwrap());
+
+        let session = &sessions[&ClientId::from(5)];
+        assert!(session.keys == keys);
+        assert_eq!(session.budget, 3);
+        assert_eq!(caller_credential(), Some(credential));
+    }
+
+    fn open_session(client_id: usize, keys: SessionKeys, budget: u32) {
+        CLIENT_MAP.write().unwrap().insert(
+            ClientId::from(client_id),
+            Session {
+                keys,
+                expires_at: unix_now() + 60,
+                budget,
+                connections: HashSet::new(),
+                caller: None,
+            },
+        );
+    }
+
+    #[test]
+    fn connections_never_share_record_keys() {
+        let keys = SessionKeys {
+            client_to_server: [3; 32],
+            server_to_client: [4; 32],
+        };
+        open_session(100, keys.clone(), 3);
+        let client_id = ClientId::from(100);
+        let (mut first, _, _) = get_records_for_client(&client_id, 0).unwrap();
+        let (mut second, mut second_opener, _) = get_records_for_client(&client_id, 1).unwrap();
+        //Both first records have sequence 0, so the same nonce
+        assert_ne!(first.seal(b"response").unwrap(), second.seal(b"response").unwrap());
+        assert!(matches!(
+            get_records_for_client(&client_id, 1),
+            Err(SessionError::ConnectionReusedError)
+        ));
+
+        //The client numbers its connections the same way
+        let mut session = hoodini_core::session::ClientSession::new(client_id.clone(), keys);
+        session.next_connection().unwrap();
+        let (connection, client_keys) = session.next_connection().unwrap();
+        assert_eq!(connection, 1);
+        let (mut sealer, _) =
+            hoodini_core::record::client_records(client_keys, &client_id, RecordLimits::default())
+                .unwrap();
+        assert_eq!(second_opener.open(&sealer.seal(b"request").unwrap()).unwrap(), b"request");
+    }
+
+    #[test]
+    fn revoked_sessions_are_evicted() {
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        let open = |client_id: usize| SessionMessage::Open {
+            client_id: ClientId::from(client_id),
+            keys: keys.clone(),
+            expires_at: unix_now() + 60,
+            budget: 3,
+            caller: None,
+        };
+        let mut sessions = HashMap::new();
+        apply_message(&mut sessions, open(1));
+        apply_message(&mut sessions, open(2));
+        apply_message(&mut sessions, revoke(1));
+        assert!(!sessions.contains_key(&ClientId::from(1)));
+        assert!(sessions.contains_key(&ClientId::from(2)));
+        //Unknown clients are ignored
+        apply_message(&mut sessions, revoke(3));
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[test]
+    fn expired_or_spent_sessions_are_refused() {
+        let keys = SessionKeys {
+            client_to_server: [1; 32],
+            server_to_client: [2; 32],
+        };
+        open_session(200, keys.clone(), 3);
+        CLIENT_MAP
+            .write()
+            .unwrap()
+            .get_mut(&ClientId::from(200))
+            .unwrap()
+            .expires_at = unix_now() - 1;
+        assert!(matches!(
+            get_key_for_client(&ClientId::from(200), 0),
+            Err(SessionError::SessionExpiredError)
+        ));
+        //Removed along the way
+        assert!(matches!(
+            get_key_for_client(&ClientId::from(200), 0),
+            Err(SessionError::UnknownClientError)
+        ));
+
+        open_session(201, keys, 2);
+        assert!(get_key_for_client(&ClientId::from(201), 0).is_ok());
+        assert!(get_key_for_client(&ClientId::from(201), 1).is_ok());
+        assert!(matches!(
+            get_key_for_client(&ClientId::from(201), 2),
+            Err(SessionError::UnknownClientError)
+        ));
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let other_kek = "ff".repeat(32);
+        let lines = "not a sealed line\n".to_string()
+            + &sealed_line(&other_kek, &revoke(1))
+            + &sealed_line(KEK_HEX, &revoke(2));
+        let mut handle = FifoReadHandle::from_reader(Cursor::new(lines), KEK_HEX);
+        assert!(matches!(handle.read_message(), Err(FifoReadError::MalformedLineError)));
+        assert!(matches!(handle.read_message(), Err(FifoReadError::DecryptionError)));
+        assert_eq!(handle.read_message().unwrap(), revoke(2));
+    }
 }
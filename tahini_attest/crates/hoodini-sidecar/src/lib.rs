@@ -2,10 +2,11 @@ use std::{
     cell::OnceCell,
     collections::HashMap,
     ffi::CString,
+    fmt,
     fs::File,
     io::{self, BufReader, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command},
 };
 
 use aws_lc_rs::{
@@ -16,27 +17,64 @@ use aws_lc_rs::{
 };
 use sha2::{Digest, Sha256};
 
+use hoodini_core::session::{
+    CallerCredential, CallerIdentity, RevocationReason, SessionKeys, SessionLimits, SessionMessage,
+};
 use hoodini_core::types::{
-    BinHash, ClientId,
-    ServiceName,
+    BinHash, BinaryName, ClientId,
+    ServiceName, TahiniCertificate,
 };
 
+#[derive(Debug)]
+pub enum FifoError {
+    //Binary couldn't be started
+    SpawnError(io::Error),
+    //FIFO couldn't be opened or written to
+    IoError(io::Error),
+    EncodingError(serde_json::Error),
+    CryptoError,
+}
+
+impl fmt::Display for FifoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FifoError::SpawnError(e) => write!(f, "could not start binary: {}", e),
+            FifoError::IoError(e) => write!(f, "could not write to the binary FIFO: {}", e),
+            FifoError::EncodingError(e) => write!(f, "could not encode session message: {}", e),
+            FifoError::CryptoError => write!(f, "could not encrypt session message"),
+        }
+    }
+}
 
-pub fn launch_binary<P: AsRef<Path>>(bin_path: P, dir_to_run: P) -> Result<FifoWriterHandle, ()> {
+impl std::error::Error for FifoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FifoError::SpawnError(e) | FifoError::IoError(e) => Some(e),
+            FifoError::EncodingError(e) => Some(e),
+            FifoError::CryptoError => None,
+        }
+    }
+}
+
+///Starts the binary with the FIFO it receives its sessions on. The binary is stopped when the
+///returned handle is dropped.
+pub fn launch_binary<P: AsRef<Path>>(bin_path: P, dir_to_run: P) -> Result<FifoWriterHandle, FifoError> {
     let fifo_path = format_fifo_path(&dir_to_run);
     create_fifo(&fifo_path);
     let (mut fifo_handle, kek_hex) = FifoWriterHandle::new(&fifo_path);
 
-    Command::new(bin_path.as_ref())
+    let child = Command::new(bin_path.as_ref())
         .current_dir(dir_to_run)
         .arg("--fifo_path")
-        .arg(format!("{}", fifo_path.to_str().unwrap()))
+        .arg(fifo_path.to_str().unwrap())
         .arg("--kek_hex")
-        .arg(format!("{}", kek_hex))
+        .arg(&kek_hex)
         .spawn()
-        .expect("Couldn't start process");
+        .map_err(FifoError::SpawnError)?;
+    //Set first, so that the child is reaped even if the FIFO can't be opened
+    fifo_handle.child = Some(child);
 
-    fifo_handle.enable_fifo();
+    fifo_handle.enable_fifo()?;
     Ok(fifo_handle)
 }
 
@@ -90,6 +128,8 @@ pub struct FifoWriterHandle {
     kek: RandomizedNonceKey,
     fifo_path: PathBuf,
     handle: OnceCell<File>,
+    //Binary reading the FIFO
+    child: Option<Child>,
 }
 
 impl FifoWriterHandle {
@@ -114,59 +154,245 @@ impl FifoWriterHandle {
         let usable_key = RandomizedNonceKey::new(&AES_256_GCM, &end_derived_key)
             .expect("Couldn't generate AES key from derived material");
 
-        let derived_hex = hex::encode(&end_derived_key);
+        let derived_hex = hex::encode(end_derived_key);
         (
             Self {
                 kek: usable_key,
                 fifo_path: path.as_ref().to_path_buf(),
                 handle: OnceCell::new(),
+                child: None,
             },
             derived_hex,
         )
     }
 
-    fn enable_fifo(&mut self) {
+    fn enable_fifo(&mut self) -> Result<(), FifoError> {
         let fifo_file = File::options()
-            .write(true)
             .append(true)
             .open(&self.fifo_path)
-            .expect("Couldn't open FIFO file");
+            .map_err(FifoError::IoError)?;
 
         self.handle
             .set(fifo_file)
             .expect("Couldn't set fifo handler");
+        Ok(())
     }
 
-    pub fn write_session_key(
-        &mut self,
-        key_material: &[u8],
-        client_id: &ClientId,
-    ) -> Result<(), ()> {
-        //Encrypt session key
-        let mut cipher = key_material.to_vec();
+    pub fn write_message(&mut self, message: &SessionMessage) -> Result<(), FifoError> {
+        //Encrypt the whole message, so that only the sidecar can open or revoke sessions
+        let mut cipher = serde_json::to_vec(message).map_err(FifoError::EncodingError)?;
         let nonce = self
             .kek
             .seal_in_place_append_tag(Aad::empty(), &mut cipher)
-            .map_err(|_| ())?;
+            .map_err(|_| FifoError::CryptoError)?;
         //Put the cipher in hex form so easier to decode on the other end
         let cipher_hex = hex::encode(&cipher);
         //Same for nonce
         let nonce_hex = hex::encode(nonce.as_ref());
-        //Also pass the client id
-        write!(
+        writeln!(
             self.handle.get_mut().expect("FIFO was not enabled yet"),
-            "{},{},{}\n",
+            "{},{}",
             nonce_hex,
             cipher_hex,
-            client_id
         )
-        .expect("Couldn't write to FIFO");
-        Ok(())
+        .map_err(FifoError::IoError)
+    }
+
+    ///Hands the keys of a new or rekeyed session to the server, with the identity of the caller
+    ///if it presented one.
+    pub fn open_session(
+        &mut self,
+        client_id: &ClientId,
+        keys: SessionKeys,
+        expires_at: u64,
+        limits: &SessionLimits,
+        caller: Option<CallerIdentity>,
+    ) -> Result<(), FifoError> {
+        self.write_message(&SessionMessage::Open {
+            client_id: client_id.clone(),
+            keys,
+            expires_at,
+            budget: limits.budget,
+            caller: caller.map(Box::new),
+        })
+    }
+
+    ///Hands the server the credential it presents when calling other services.
+    pub fn send_credential(&mut self, credential: &CallerCredential) -> Result<(), FifoError> {
+        self.write_message(&SessionMessage::Credential {
+            credential: credential.clone(),
+        })
+    }
+
+    ///Makes the server evict the sessions of these clients.
+    pub fn revoke_sessions(
+        &mut self,
+        client_ids: Vec<ClientId>,
+        reason: RevocationReason,
+    ) -> Result<(), FifoError> {
+        self.write_message(&SessionMessage::Revoke { client_ids, reason })
+    }
+}
+
+///Session the sidecar handed out, kept to rekey and revoke it.
+pub struct TrackedSession {
+    pub bin_name: BinaryName,
+    pub service_name: ServiceName,
+    //Certificate the session was attested under
+    pub certificate: TahiniCertificate,
+    pub keys: SessionKeys,
+    //Seconds since UNIX epoch
+    pub expires_at: u64,
+    //Binary on the calling end, if it presented its credential
+    pub caller: Option<CallerIdentity>,
+    //Local user on the calling end, if it connected over the Unix socket
+    pub peer_uid: Option<u32>,
+}
+
+///Sessions handed out by the sidecar that may still be live on their server.
+#[derive(Default)]
+pub struct SessionTracker {
+    sessions: HashMap<ClientId, TrackedSession>,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Records a new session, or the new keys of a rekeyed one.
+    pub fn track(&mut self, client_id: ClientId, session: TrackedSession) {
+        self.sessions.insert(client_id, session);
+    }
+
+    ///Session of the client, if it did not expire.
+    pub fn get(&self, client_id: &ClientId, now: u64) -> Option<&TrackedSession> {
+        self.sessions
+            .get(client_id)
+            .filter(|session| now < session.expires_at)
+    }
+
+
+    ///Forgets expired sessions, which servers already refuse on their own.
+    pub fn remove_expired(&mut self, now: u64) {
+        self.sessions.retain(|_, session| now < session.expires_at);
+    }
+
+    ///Stops tracking the sessions attested under a certificate matching `revoked`, or whose
+    ///caller's certificate matches it, and returns them grouped by the binary to notify.
+    pub fn revoke_where(
+        &mut self,
+        revoked: impl Fn(&TahiniCertificate) -> bool,
+    ) -> HashMap<BinaryName, Vec<ClientId>> {
+        self.remove_where(|session| {
+            revoked(&session.certificate)
+                || session
+                    .caller
+                    .as_ref()
+                    .is_some_and(|caller| revoked(&caller.certificate))
+        })
+    }
+
+    ///Stops tracking the sessions matching `remove`, e.g. those of banned clients, and returns
+    ///them grouped by the binary to notify.
+    pub fn remove_where(
+        &mut self,
+        remove: impl Fn(&TrackedSession) -> bool,
+    ) -> HashMap<BinaryName, Vec<ClientId>> {
+        let mut by_binary: HashMap<BinaryName, Vec<ClientId>> = HashMap::new();
+        self.sessions.retain(|client_id, session| {
+            if remove(session) {
+                by_binary
+                    .entry(session.bin_name.clone())
+                    .or_default()
+                    .push(client_id.clone());
+                return false;
+            }
+            true
+        });
+        by_binary
     }
 }
 
 impl Drop for FifoWriterHandle {
     fn drop(&mut self) {
         let _ = std::fs::remove_file(&self.fifo_path);
+        //Stops the binary and reaps it, rather than leaving a zombie behind
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hoodini_core::types::{CertificateVersion, PolicyHash, Signature};
+
+    fn certificate(bin_name: &str) -> TahiniCertificate {
+        TahiniCertificate {
+            version: CertificateVersion::V1,
+            service_name: ServiceName(bin_name.to_string()),
+            policy_hash: PolicyHash("01".repeat(32)),
+            binary_hash: BinHash("02".repeat(32)),
+            issuance: None,
+            policy_manifest: None,
+            services: Vec::new(),
+            signature: Signature(String::new()),
+            transparency: None,
+        }
+    }
+
+    fn session(bin_name: &str, expires_at: u64) -> TrackedSession {
+        TrackedSession {
+            bin_name: BinaryName::from(bin_name.to_string()),
+            service_name: ServiceName(bin_name.to_string()),
+            certificate: certificate(bin_name),
+            keys: SessionKeys {
+                client_to_server: [1; 32],
+                server_to_client: [2; 32],
+            },
+            expires_at,
+            caller: None,
+            peer_uid: None,
+        }
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut tracker = SessionTracker::new();
+        tracker.track(ClientId::from(1), session("server", 100));
+        tracker.track(ClientId::from(2), session("server", 200));
+        assert!(tracker.get(&ClientId::from(1), 99).is_some());
+        assert!(tracker.get(&ClientId::from(1), 100).is_none());
+
+        tracker.remove_expired(150);
+        assert!(tracker.get(&ClientId::from(1), 0).is_none());
+        assert!(tracker.get(&ClientId::from(2), 150).is_some());
+    }
+
+    #[test]
+    fn revoked_sessions_are_grouped_by_binary() {
+        let mut tracker = SessionTracker::new();
+        tracker.track(ClientId::from(1), session("server", 100));
+        tracker.track(ClientId::from(2), session("other", 100));
+        //Session with other, called by server
+        let mut called = session("other", 100);
+        called.caller = Some(CallerIdentity::new(
+            BinaryName::from("server".to_string()),
+            certificate("server"),
+        ));
+        tracker.track(ClientId::from(3), called);
+
+        let revoked = tracker.revoke_where(|certificate| certificate.service_name.0 == "server");
+        assert_eq!(revoked.len(), 2);
+        assert_eq!(revoked[&BinaryName::from("server".to_string())], vec![ClientId::from(1)]);
+        assert_eq!(revoked[&BinaryName::from("other".to_string())], vec![ClientId::from(3)]);
+        assert!(tracker.get(&ClientId::from(1), 0).is_none());
+        assert!(tracker.get(&ClientId::from(2), 0).is_some());
+        assert!(tracker.get(&ClientId::from(3), 0).is_none());
+
+        assert!(tracker.revoke_where(|_| false).is_empty());
     }
 }